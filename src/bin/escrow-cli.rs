@@ -0,0 +1,208 @@
+//! Lists, buys, cancels and inspects escrows through a cluster's JSON-RPC API.
+//!
+//!     escrow-cli [--url <url>] [--keypair <path>] --program-id <pubkey> <command>
+//!
+//! Commands:
+//!
+//!     list <token account> <price>      list the token held by the account
+//!     buy <escrow>                      buy a listing into a new token account
+//!     cancel <escrow>                   cancel a listing of yours
+//!     update-price <escrow> <price>     change the price of a listing of yours
+//!     show <escrow>                     describe a listing and the fees of a sale
+//!     list-by-seller [<seller>]         the listings of a seller, yourself by default
+//!     treasury                          describe the marketplace treasury
+//!     withdraw-fees <amount> [<to>]     withdraw fees from the treasury, to yourself by default
+//!
+//! Prices are in lamports.
+
+use {
+    serde_json::{json, Value},
+    solana_escrow::cli::{self, Chain, CliResult, Filter},
+    solana_program::{hash::Hash, instruction::Instruction, pubkey::Pubkey},
+    solana_sdk::{
+        account::Account,
+        signature::{read_keypair_file, Keypair, Signature, Signer},
+        transaction::Transaction,
+    },
+    std::{env, process, str::FromStr, thread, time::Duration},
+};
+
+const DEFAULT_URL: &str = "http://localhost:8899";
+const USAGE: &str = "usage: escrow-cli [--url <url>] [--keypair <path>] --program-id <pubkey> \
+                     (list <token account> <price> | buy <escrow> | cancel <escrow> | \
+                     update-price <escrow> <price> | show <escrow> | list-by-seller [<seller>] | \
+                     treasury | withdraw-fees <amount> [<to>])";
+
+struct RpcChain {
+    url: String,
+    http: reqwest::blocking::Client,
+}
+
+impl RpcChain {
+    fn call(&self, method: &str, params: Value) -> CliResult<Value> {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let mut response: Value = self.http.post(&self.url).json(&request).send()?.json()?;
+        if let Some(error) = response.get("error") {
+            return Err(format!("{} failed: {}", method, error).into());
+        }
+        Ok(response["result"].take())
+    }
+}
+
+fn parse_account(value: &Value) -> CliResult<Account> {
+    let data = value["data"][0].as_str().ok_or("missing account data")?;
+    Ok(Account {
+        lamports: value["lamports"].as_u64().ok_or("missing lamports")?,
+        data: base64::decode(data)?,
+        owner: Pubkey::from_str(value["owner"].as_str().ok_or("missing owner")?)?,
+        executable: value["executable"].as_bool().unwrap_or(false),
+        rent_epoch: value["rentEpoch"].as_u64().unwrap_or(0),
+    })
+}
+
+impl Chain for RpcChain {
+    fn get_account(&mut self, pubkey: &Pubkey) -> CliResult<Option<Account>> {
+        let result = self.call("getAccountInfo", json!([pubkey.to_string(), {"encoding": "base64"}]))?;
+        match &result["value"] {
+            Value::Null => Ok(None),
+            value => Ok(Some(parse_account(value)?)),
+        }
+    }
+
+    fn get_program_accounts(&mut self, program_id: &Pubkey, filters: &[Filter]) -> CliResult<Vec<(Pubkey, Account)>> {
+        let filters: Vec<Value> = filters
+            .iter()
+            .map(|filter| match filter {
+                Filter::DataSize(size) => json!({ "dataSize": size }),
+                Filter::Memcmp { offset, bytes } => {
+                    json!({"memcmp": {"offset": offset, "bytes": bs58::encode(bytes).into_string()}})
+                }
+            })
+            .collect();
+        let result = self.call(
+            "getProgramAccounts",
+            json!([program_id.to_string(), {"encoding": "base64", "filters": filters}]),
+        )?;
+        let mut accounts = vec![];
+        for item in result.as_array().ok_or("unexpected getProgramAccounts result")? {
+            let pubkey = Pubkey::from_str(item["pubkey"].as_str().ok_or("missing pubkey")?)?;
+            accounts.push((pubkey, parse_account(&item["account"])?));
+        }
+        Ok(accounts)
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&mut self, len: usize) -> CliResult<u64> {
+        let result = self.call("getMinimumBalanceForRentExemption", json!([len]))?;
+        Ok(result.as_u64().ok_or("unexpected getMinimumBalanceForRentExemption result")?)
+    }
+
+    fn send(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> CliResult<Signature> {
+        let result = self.call("getRecentBlockhash", json!([]))?;
+        let blockhash = Hash::from_str(result["value"]["blockhash"].as_str().ok_or("missing blockhash")?)?;
+        let transaction = Transaction::new_signed_with_payer(instructions, Some(&signers[0].pubkey()), &signers.to_vec(), blockhash);
+        let encoded = base64::encode(bincode::serialize(&transaction)?);
+        self.call("sendTransaction", json!([encoded, {"encoding": "base64"}]))?;
+
+        let signature = transaction.signatures[0];
+        for _ in 0..60 {
+            let result = self.call("getSignatureStatuses", json!([[signature.to_string()]]))?;
+            let status = &result["value"][0];
+            if !status.is_null() {
+                if !status["err"].is_null() {
+                    return Err(format!("transaction {} failed: {}", signature, status["err"]).into());
+                }
+                if let Some("confirmed") | Some("finalized") = status["confirmationStatus"].as_str() {
+                    return Ok(signature);
+                }
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        Err(format!("transaction {} was not confirmed", signature).into())
+    }
+}
+
+fn parse_pubkey(arg: Option<&String>) -> CliResult<Pubkey> {
+    let arg = arg.ok_or(USAGE)?;
+    Pubkey::from_str(arg).map_err(|_| format!("invalid pubkey {}", arg).into())
+}
+
+fn parse_price(arg: Option<&String>) -> CliResult<u64> {
+    let arg = arg.ok_or(USAGE)?;
+    arg.parse().map_err(|_| format!("invalid price {}", arg).into())
+}
+
+fn run(args: Vec<String>) -> CliResult<()> {
+    let mut url = DEFAULT_URL.to_string();
+    let mut keypair_path = env::var("HOME").map(|home| format!("{}/.config/solana/id.json", home)).unwrap_or_default();
+    let mut program_id = None;
+    let mut args = args.into_iter();
+    let mut rest = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => url = args.next().ok_or(USAGE)?,
+            "--keypair" => keypair_path = args.next().ok_or(USAGE)?,
+            "--program-id" => program_id = Some(parse_pubkey(args.next().as_ref())?),
+            _ => rest.push(arg),
+        }
+    }
+    let program_id = program_id.ok_or(USAGE)?;
+    let keypair = || read_keypair_file(&keypair_path).map_err(|e| format!("reading {}: {}", keypair_path, e));
+
+    let mut chain = RpcChain {
+        url,
+        http: reqwest::blocking::Client::new(),
+    };
+    let command = rest.first().ok_or(USAGE)?;
+    match command.as_str() {
+        "list" => {
+            let token_account = parse_pubkey(rest.get(1))?;
+            let price = parse_price(rest.get(2))?;
+            let escrow = cli::list(&mut chain, &program_id, &keypair()?, &token_account, price)?;
+            println!("listed as escrow {}", escrow);
+        }
+        "buy" => {
+            let signature = cli::buy(&mut chain, &program_id, &keypair()?, &parse_pubkey(rest.get(1))?)?;
+            println!("bought: {}", signature);
+        }
+        "cancel" => {
+            let signature = cli::cancel(&mut chain, &program_id, &keypair()?, &parse_pubkey(rest.get(1))?)?;
+            println!("cancelled: {}", signature);
+        }
+        "update-price" => {
+            let escrow = parse_pubkey(rest.get(1))?;
+            let price = parse_price(rest.get(2))?;
+            let signature = cli::update_price(&mut chain, &program_id, &keypair()?, &escrow, price)?;
+            println!("updated: {}", signature);
+        }
+        "show" => print!("{}", cli::show(&mut chain, &parse_pubkey(rest.get(1))?)?),
+        "list-by-seller" => {
+            let seller = match rest.get(1) {
+                Some(_) => parse_pubkey(rest.get(1))?,
+                None => keypair()?.pubkey(),
+            };
+            for listing in cli::list_by_seller(&mut chain, &program_id, &seller)? {
+                println!("{} {} {} lamports", listing.escrow, listing.mint, listing.price);
+            }
+        }
+        "treasury" => print!("{}", cli::show_treasury(&mut chain, &program_id)?),
+        "withdraw-fees" => {
+            let amount = parse_price(rest.get(1))?;
+            let authority = keypair()?;
+            let destination = match rest.get(2) {
+                Some(_) => parse_pubkey(rest.get(2))?,
+                None => authority.pubkey(),
+            };
+            let signature = cli::withdraw_fees(&mut chain, &program_id, &authority, &destination, amount)?;
+            println!("withdrawn: {}", signature);
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
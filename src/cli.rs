@@ -0,0 +1,401 @@
+//! The commands of `escrow-cli`, enabled with the `cli` feature.
+//!
+//! Commands talk to the cluster through the `Chain` trait, implemented over
+//! JSON-RPC by the binary and over `solana-program-test` in tests.
+
+use {
+    solana_program::{
+        account_info::AccountInfo,
+        clock::Clock,
+        instruction::Instruction,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        system_instruction, sysvar,
+    },
+    solana_sdk::{
+        account::{from_account, Account},
+        signature::{Keypair, Signature, Signer},
+    },
+    std::{error::Error, fmt::Write},
+};
+
+use crate::{
+    client,
+    fees::{self, SaleTerms},
+    indexer::{self, Listing},
+    metadata::{get_metadata_account, metaplex_program_id, Metadata},
+    state::{get_marketplace_account, get_policy_account, get_treasury_account, CreatorPolicy, Escrow, FeeMode, Marketplace, Treasury},
+    token,
+};
+
+pub use crate::indexer::Filter;
+
+pub type CliResult<T> = Result<T, Box<dyn Error>>;
+
+pub trait Chain {
+    fn get_account(&mut self, pubkey: &Pubkey) -> CliResult<Option<Account>>;
+
+    fn get_program_accounts(&mut self, program_id: &Pubkey, filters: &[Filter]) -> CliResult<Vec<(Pubkey, Account)>>;
+
+    /// The lamports an account of `len` bytes needs to be rent exempt on this cluster.
+    fn get_minimum_balance_for_rent_exemption(&mut self, len: usize) -> CliResult<u64>;
+
+    /// Signs and sends a transaction paid by the first signer, waiting for its confirmation.
+    fn send(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> CliResult<Signature>;
+}
+
+fn get_existing_account<C: Chain>(chain: &mut C, pubkey: &Pubkey) -> CliResult<Account> {
+    chain
+        .get_account(pubkey)?
+        .ok_or_else(|| format!("account {} not found", pubkey).into())
+}
+
+pub fn get_escrow<C: Chain>(chain: &mut C, escrow_pubkey: &Pubkey) -> CliResult<Escrow> {
+    let account = get_existing_account(chain, escrow_pubkey)?;
+    Ok(Escrow::unpack_versioned(&account.data)?)
+}
+
+/// The metadata of `mint`, from its token metadata extension or its Metaplex metadata account.
+pub fn get_metadata<C: Chain>(chain: &mut C, mint: &Pubkey) -> CliResult<Option<Metadata>> {
+    let mut mint_account = get_existing_account(chain, mint)?;
+    let mint_info = AccountInfo::new(
+        mint,
+        false,
+        false,
+        &mut mint_account.lamports,
+        &mut mint_account.data,
+        &mint_account.owner,
+        false,
+        0,
+    );
+    if let Some(md) = token::get_token_metadata(&mint_info)? {
+        return Ok(Some(md));
+    }
+    match chain.get_account(&get_metadata_account(mint))? {
+        Some(account) if account.owner == metaplex_program_id() => Ok(Some(Metadata::from_u8(&account.data)?)),
+        _ => Ok(None),
+    }
+}
+
+/// The marketplace configuration, the defaults if it was never created.
+pub fn get_marketplace<C: Chain>(chain: &mut C, program_id: &Pubkey) -> CliResult<Marketplace> {
+    match chain.get_account(&get_marketplace_account(program_id).0)? {
+        Some(account) if !account.data.is_empty() => Ok(Marketplace::unpack_versioned(&account.data)?),
+        _ => Ok(Marketplace::default()),
+    }
+}
+
+/// The creator policies of the token with `metadata`: those of its verified collection and first verified creator.
+pub fn get_creator_policies<C: Chain>(
+    chain: &mut C,
+    program_id: &Pubkey,
+    metadata: Option<&Metadata>,
+) -> CliResult<Vec<CreatorPolicy>> {
+    let targets = match metadata {
+        Some(md) => vec![md.verified_collection(), md.first_verified_creator()],
+        None => vec![],
+    };
+    let mut policies = vec![];
+    for target in targets.into_iter().flatten() {
+        match chain.get_account(&get_policy_account(program_id, target).0)? {
+            Some(account) if account.owner == *program_id && !account.data.is_empty() => {
+                policies.push(CreatorPolicy::unpack(&account.data)?)
+            }
+            _ => (),
+        }
+    }
+    Ok(policies)
+}
+
+// The account the marketplace wants its fees paid to.
+fn get_fee_recipient<C: Chain>(chain: &mut C, program_id: &Pubkey) -> CliResult<Pubkey> {
+    Ok(client::fee_recipient(program_id, &get_marketplace(chain, program_id)?))
+}
+
+/// What the buyer of the listing in `escrow` pays, line by line.
+pub fn fee_breakdown(escrow: &Escrow, metadata: Option<&Metadata>, terms: &SaleTerms) -> String {
+    let quote = match fees::quote_escrow(escrow, metadata, terms) {
+        Ok(quote) => quote,
+        Err(e) => return format!("  cannot be bought: {}\n", e),
+    };
+    let mut out = String::new();
+    writeln!(out, "  price:          {} lamports", quote.price).unwrap();
+    writeln!(out, "  sales tax:      {} lamports", quote.sales_tax).unwrap();
+    for royalty in &quote.royalties {
+        writeln!(out, "  royalty:        {} lamports to {}", royalty.amount, royalty.creator).unwrap();
+    }
+    writeln!(out, "  seller nets:    {} lamports", quote.seller_proceeds).unwrap();
+    let paid_by = match quote.fee_mode {
+        FeeMode::SellerPaid => "seller",
+        FeeMode::BuyerPaid => "buyer",
+    };
+    writeln!(out, "  fees paid by:   {}", paid_by).unwrap();
+    writeln!(out, "  buyer pays:     {} lamports", quote.total).unwrap();
+    out
+}
+
+/// Describes the listing in `escrow_pubkey`, with the fees a sale would pay.
+pub fn show<C: Chain>(chain: &mut C, escrow_pubkey: &Pubkey) -> CliResult<String> {
+    let account = get_existing_account(chain, escrow_pubkey)?;
+    let escrow = Escrow::unpack_versioned(&account.data)?;
+    let marketplace = get_marketplace(chain, &account.owner)?;
+    let metadata = get_metadata(chain, &escrow.mint_pubkey)?;
+    let mut out = String::new();
+    writeln!(out, "escrow {}", escrow_pubkey)?;
+    writeln!(out, "  seller:         {}", escrow.initializer_pubkey)?;
+    writeln!(out, "  mint:           {}", escrow.mint_pubkey)?;
+    if let Some(md) = &metadata {
+        writeln!(out, "  name:           {}", md.data.name.trim_end_matches('\0'))?;
+    }
+    writeln!(out, "  token account:  {}", escrow.temp_token_account_pubkey)?;
+    writeln!(out, "  listed at:      {} (slot {})", escrow.created_at, escrow.created_slot)?;
+    writeln!(out, "  updated at:     {} (sequence {})", escrow.updated_at, escrow.sequence)?;
+    if escrow.listing_deposit > 0 {
+        writeln!(out, "  deposit:        {} lamports", escrow.listing_deposit)?;
+    }
+    let policies = get_creator_policies(chain, &account.owner, metadata.as_ref())?;
+    let mut terms = SaleTerms::new(&marketplace, false, None);
+    match fees::apply_creator_policies(&policies, escrow.expected_amount) {
+        Ok(royalty_floor_basis_points) => {
+            terms.royalty_floor_basis_points = royalty_floor_basis_points;
+            out.push_str(&fee_breakdown(&escrow, metadata.as_ref(), &terms));
+        }
+        Err(e) => writeln!(out, "  cannot be bought: {}", e)?,
+    }
+    Ok(out)
+}
+
+/// The listings of `seller`, in every escrow layout.
+pub fn list_by_seller<C: Chain>(chain: &mut C, program_id: &Pubkey, seller: &Pubkey) -> CliResult<Vec<Listing>> {
+    let mut listings = vec![];
+    for filters in indexer::seller_filters(seller) {
+        for (pubkey, account) in chain.get_program_accounts(program_id, &filters)? {
+            listings.extend(indexer::decode_listing(&pubkey, &account.data));
+        }
+    }
+    Ok(listings)
+}
+
+// Creates a token account for `mint` owned by `owner`, returning the instructions and its keypair.
+// It's sized for the extensions of `mint_account`, whose owner is the token program.
+fn create_token_account<C: Chain>(
+    chain: &mut C,
+    payer: &Pubkey,
+    mint: &Pubkey,
+    mint_account: &Account,
+    owner: &Pubkey,
+) -> CliResult<(Vec<Instruction>, Keypair)> {
+    let account = Keypair::new();
+    let token_program = &mint_account.owner;
+    let len = token::get_account_len(&mint_account.data);
+    let mut initialize = spl_token::instruction::initialize_account(&spl_token::id(), &account.pubkey(), mint, owner)?;
+    initialize.program_id = *token_program;
+    let instructions = vec![
+        system_instruction::create_account(
+            payer,
+            &account.pubkey(),
+            chain.get_minimum_balance_for_rent_exemption(len)?,
+            len as u64,
+            token_program,
+        ),
+        initialize,
+    ];
+    Ok((instructions, account))
+}
+
+/// Lists the token held by `source_token_account` for `price` lamports, returning the escrow account.
+pub fn list<C: Chain>(
+    chain: &mut C,
+    program_id: &Pubkey,
+    seller: &Keypair,
+    source_token_account: &Pubkey,
+    price: u64,
+) -> CliResult<Pubkey> {
+    let source = get_existing_account(chain, source_token_account)?;
+    let token_program = source.owner;
+    let source_info = spl_token::state::Account::unpack_from_slice(&source.data[..spl_token::state::Account::LEN])?;
+    let mint = source_info.mint;
+    let mint_account = get_existing_account(chain, &mint)?;
+    let decimals = spl_token::state::Mint::unpack_from_slice(&mint_account.data[..spl_token::state::Mint::LEN])?.decimals;
+
+    let escrow = Keypair::new();
+    let mut instructions = vec![system_instruction::create_account(
+        &seller.pubkey(),
+        &escrow.pubkey(),
+        chain.get_minimum_balance_for_rent_exemption(Escrow::LEN)?,
+        Escrow::LEN as u64,
+        program_id,
+    )];
+
+    let metadata = get_metadata(chain, &mint)?;
+    let fee_recipient = get_fee_recipient(chain, program_id)?;
+    if let Some(md) = metadata.as_ref().filter(|md| md.is_programmable()) {
+        instructions.push(client::init_programmable_escrow(
+            program_id,
+            &seller.pubkey(),
+            source_token_account,
+            &escrow.pubkey(),
+            &fee_recipient,
+            md,
+            None,
+            price,
+        ));
+        chain.send(&instructions, &[seller, &escrow])?;
+        return Ok(escrow.pubkey());
+    }
+
+    let (create_temp, temp) = create_token_account(chain, &seller.pubkey(), &mint, &mint_account, &seller.pubkey())?;
+    instructions.extend(create_temp);
+    instructions.push(token::transfer_checked(
+        &token_program,
+        source_token_account,
+        &mint,
+        &temp.pubkey(),
+        &seller.pubkey(),
+        source_info.amount,
+        decimals,
+    )?);
+    instructions.push(client::init_escrow(
+        program_id,
+        &seller.pubkey(),
+        &temp.pubkey(),
+        &mint,
+        &escrow.pubkey(),
+        &fee_recipient,
+        &token_program,
+        metadata.as_ref(),
+        Some(source_token_account),
+        None,
+        price,
+    ));
+    chain.send(&instructions, &[seller, &escrow, &temp])?;
+    Ok(escrow.pubkey())
+}
+
+// Takes the listing: buys it, or cancels it if `taker` is the seller.
+fn take<C: Chain>(
+    chain: &mut C,
+    program_id: &Pubkey,
+    taker: &Keypair,
+    escrow_pubkey: &Pubkey,
+    destination: Option<Pubkey>,
+) -> CliResult<Signature> {
+    let escrow = get_escrow(chain, escrow_pubkey)?;
+    let mint = escrow.mint_pubkey;
+    let mut mint_account = get_existing_account(chain, &mint)?;
+    let token_program = mint_account.owner;
+    let metadata = get_metadata(chain, &mint)?;
+    if taker.pubkey() != escrow.initializer_pubkey && matches!(&metadata, Some(md) if md.is_programmable()) {
+        return Err("programmable NFTs have to be bought into an associated token account, use a wallet".into());
+    }
+
+    let temp = get_existing_account(chain, &escrow.temp_token_account_pubkey)?;
+    let mut amount = spl_token::state::Account::unpack_from_slice(&temp.data[..spl_token::state::Account::LEN])?.amount;
+    if token_program == token::token_2022_program_id() {
+        let clock: Clock = from_account(&get_existing_account(chain, &sysvar::clock::id())?)
+            .ok_or("invalid clock sysvar")?;
+        let mint_info = AccountInfo::new(
+            &mint,
+            false,
+            false,
+            &mut mint_account.lamports,
+            &mut mint_account.data,
+            &mint_account.owner,
+            false,
+            0,
+        );
+        if let Some(fee) = token::get_transfer_fee(&mint_info, clock.epoch) {
+            amount -= fee.calculate_fee(amount);
+        }
+    }
+
+    let mut instructions = vec![];
+    let mut signers = vec![taker];
+    let new_account;
+    let destination = match destination {
+        Some(destination) => destination,
+        None => {
+            let (create, account) = create_token_account(chain, &taker.pubkey(), &mint, &mint_account, &taker.pubkey())?;
+            instructions.extend(create);
+            new_account = account;
+            signers.push(&new_account);
+            new_account.pubkey()
+        }
+    };
+    instructions.push(client::exchange(
+        program_id,
+        &taker.pubkey(),
+        &destination,
+        escrow_pubkey,
+        &escrow,
+        &get_fee_recipient(chain, program_id)?,
+        &token_program,
+        metadata.as_ref(),
+        None,
+        None,
+        amount,
+    ));
+    chain.send(&instructions, &signers)
+}
+
+/// Buys the listing in `escrow_pubkey` into a new token account of the buyer.
+pub fn buy<C: Chain>(chain: &mut C, program_id: &Pubkey, buyer: &Keypair, escrow_pubkey: &Pubkey) -> CliResult<Signature> {
+    take(chain, program_id, buyer, escrow_pubkey, None)
+}
+
+/// Cancels the listing in `escrow_pubkey`, returning the token to the account it was listed from.
+pub fn cancel<C: Chain>(chain: &mut C, program_id: &Pubkey, seller: &Keypair, escrow_pubkey: &Pubkey) -> CliResult<Signature> {
+    let escrow = get_escrow(chain, escrow_pubkey)?;
+    let destination = if escrow.seller_token_account_pubkey != Pubkey::default() {
+        Some(escrow.seller_token_account_pubkey)
+    } else {
+        None
+    };
+    take(chain, program_id, seller, escrow_pubkey, destination)
+}
+
+pub fn update_price<C: Chain>(
+    chain: &mut C,
+    program_id: &Pubkey,
+    seller: &Keypair,
+    escrow_pubkey: &Pubkey,
+    price: u64,
+) -> CliResult<Signature> {
+    let escrow = get_escrow(chain, escrow_pubkey)?;
+    let metadata = get_metadata(chain, &escrow.mint_pubkey)?;
+    let ix = client::update_price(program_id, &seller.pubkey(), escrow_pubkey, &escrow.mint_pubkey, metadata.as_ref(), price);
+    chain.send(&[ix], &[seller])
+}
+
+/// Describes the marketplace treasury and its accounting periods.
+pub fn show_treasury<C: Chain>(chain: &mut C, program_id: &Pubkey) -> CliResult<String> {
+    let treasury_pubkey = get_treasury_account(program_id).0;
+    let account = get_existing_account(chain, &treasury_pubkey)?;
+    let treasury = Treasury::unpack(&account.data)?;
+    let available = account.lamports.saturating_sub(chain.get_minimum_balance_for_rent_exemption(account.data.len())?);
+    let mut out = String::new();
+    writeln!(out, "treasury {}", treasury_pubkey)?;
+    writeln!(out, "  authority:      {}", treasury.authority_pubkey)?;
+    writeln!(out, "  available:      {} lamports", available)?;
+    writeln!(out, "  collected:      {} lamports", treasury.total_collected)?;
+    writeln!(out, "  withdrawn:      {} lamports", treasury.total_withdrawn)?;
+    for (name, period) in [("current period", &treasury.current_period), ("previous period", &treasury.previous_period)] {
+        writeln!(out, "  {} from {}:", name, period.start)?;
+        writeln!(out, "    sales tax:    {} lamports", period.sales_tax)?;
+        writeln!(out, "    listing fees: {} lamports", period.listing_fees)?;
+        writeln!(out, "    withdrawn:    {} lamports", period.withdrawn)?;
+    }
+    Ok(out)
+}
+
+/// Withdraws `amount` lamports of fees from the treasury to `destination`.
+pub fn withdraw_fees<C: Chain>(
+    chain: &mut C,
+    program_id: &Pubkey,
+    treasury_authority: &Keypair,
+    destination: &Pubkey,
+    amount: u64,
+) -> CliResult<Signature> {
+    let ix = client::withdraw_fees(program_id, &treasury_authority.pubkey(), destination, amount);
+    chain.send(&[ix], &[treasury_authority])
+}
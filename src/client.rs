@@ -0,0 +1,643 @@
+//! Instruction builders for off-chain callers, enabled with the `client` feature.
+//!
+//! The builders derive the PDAs and Metaplex accounts the program expects and
+//! pass the creator accounts owed royalties, so callers only provide the
+//! accounts they own.
+
+use {
+    solana_program::{
+        instruction::{AccountMeta, Instruction},
+        pubkey::Pubkey,
+        system_program, sysvar,
+    },
+    std::str::FromStr,
+};
+
+use crate::{
+    compression,
+    instruction::EscrowInstruction,
+    metadata::{get_metadata_account, metaplex_program_id, Creator, Metadata, ProgrammableConfig, PREFIX},
+    processor::{ESCROW_PDA_SEED, SALES_TAX_RECIPIENT},
+    state::{
+        get_access_account, get_marketplace_account, get_policy_account, get_treasury_account, AccessMode, CompressedEscrow, Escrow, FeeMode,
+        FeeTier, Marketplace,
+    },
+};
+
+pub const ASSOCIATED_TOKEN_PROGRAM: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+pub const AUTHORIZATION_RULES_PROGRAM: &str = "auth9SigNpDKz4sJJ1DfCTuZrZNSAgh9sFD3rboVmgg";
+
+/// The PDA owning temp token accounts and delegated tokens of all listings.
+pub fn get_escrow_pda(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id).0
+}
+
+pub fn sales_tax_recipient() -> Pubkey {
+    SALES_TAX_RECIPIENT
+}
+
+/// The account fees are paid to on `marketplace`: its treasury once it has one.
+pub fn fee_recipient(program_id: &Pubkey, marketplace: &Marketplace) -> Pubkey {
+    if marketplace.has_treasury {
+        get_treasury_account(program_id).0
+    } else {
+        sales_tax_recipient()
+    }
+}
+
+pub fn get_master_edition_account(mint: &Pubkey) -> Pubkey {
+    let program_key = metaplex_program_id();
+    let seeds = &[PREFIX.as_bytes(), program_key.as_ref(), mint.as_ref(), b"edition"];
+    Pubkey::find_program_address(seeds, &program_key).0
+}
+
+pub fn get_token_record_account(mint: &Pubkey, token: &Pubkey) -> Pubkey {
+    let program_key = metaplex_program_id();
+    let seeds = &[
+        PREFIX.as_bytes(),
+        program_key.as_ref(),
+        mint.as_ref(),
+        b"token_record",
+        token.as_ref(),
+    ];
+    Pubkey::find_program_address(seeds, &program_key).0
+}
+
+// The authorization rules program and rule set of a programmable NFT,
+// or the token metadata program in their place if it has none.
+fn authorization_rules(metadata: &Metadata) -> (Pubkey, Pubkey) {
+    match &metadata.programmable_config {
+        Some(ProgrammableConfig::V1 { rule_set: Some(rule_set) }) => {
+            (Pubkey::from_str(AUTHORIZATION_RULES_PROGRAM).unwrap(), *rule_set)
+        }
+        _ => (metaplex_program_id(), metaplex_program_id()),
+    }
+}
+
+/// A token account proving its owner holds the token of a fee tier.
+pub struct DiscountProof {
+    pub token_account: Pubkey,
+    /// The metadata account of the token's mint, for tiers by collection
+    pub metadata: Option<Pubkey>,
+}
+
+// The proof accounts, with this program's id standing in for those not passed.
+fn discount_proof_metas(program_id: &Pubkey, discount_proof: Option<&DiscountProof>) -> Vec<AccountMeta> {
+    let token_account = discount_proof.map_or(*program_id, |proof| proof.token_account);
+    let metadata = discount_proof.and_then(|proof| proof.metadata).unwrap_or(*program_id);
+    vec![
+        AccountMeta::new_readonly(token_account, false),
+        AccountMeta::new_readonly(metadata, false),
+    ]
+}
+
+// The access list entries of `mint` and of its verified collection, with this program's id
+// standing in for the latter if it has none.
+fn access_metas(program_id: &Pubkey, mint: &Pubkey, metadata: Option<&Metadata>) -> Vec<AccountMeta> {
+    let collection = metadata.and_then(|md| md.verified_collection());
+    vec![
+        AccountMeta::new_readonly(get_access_account(program_id, mint).0, false),
+        AccountMeta::new_readonly(collection.map_or(*program_id, |collection| get_access_account(program_id, collection).0), false),
+    ]
+}
+
+// The creator policies of the token's verified collection and of its first verified creator,
+// with this program's id standing in for those it has none of.
+fn policy_metas(program_id: &Pubkey, metadata: Option<&Metadata>) -> Vec<AccountMeta> {
+    let collection = metadata.and_then(|md| md.verified_collection());
+    let creator = metadata.and_then(|md| md.first_verified_creator());
+    [collection, creator]
+        .iter()
+        .map(|target| AccountMeta::new_readonly(target.map_or(*program_id, |target| get_policy_account(program_id, target).0), false))
+        .collect()
+}
+
+// The creator policy of a compressed NFT's first verified creator, or this program's id if it has none.
+fn compressed_policy_meta(program_id: &Pubkey, creators: &[Creator]) -> AccountMeta {
+    let creator = creators.iter().find(|c| c.verified).map(|c| &c.address);
+    AccountMeta::new_readonly(creator.map_or(*program_id, |creator| get_policy_account(program_id, creator).0), false)
+}
+
+// The accounts of the creators with a share of the royalties, the others are owed nothing.
+fn creator_metas(creators: &[Creator]) -> impl Iterator<Item = AccountMeta> + '_ {
+    creators.iter().filter(|c| c.share > 0).map(|c| AccountMeta::new(c.address, false))
+}
+
+/// Lists the token held in `temp_token_account`, which the initializer owns.
+///
+/// `fee_recipient` gets the listing fee, see [fee_recipient]. `metadata` is the mint's metadata
+/// if it has any, for the allow and deny lists of its collection. `seller_token_account` is the account the token was moved from, where a
+/// cancellation will have to return it. `discount_proof` lowers the listing fee.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    mint: &Pubkey,
+    escrow: &Pubkey,
+    fee_recipient: &Pubkey,
+    token_program: &Pubkey,
+    metadata: Option<&Metadata>,
+    seller_token_account: Option<&Pubkey>,
+    discount_proof: Option<&DiscountProof>,
+    amount: u64,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new(get_metadata_account(mint), false),
+    ];
+    accounts.push(AccountMeta::new_readonly(*seller_token_account.unwrap_or(program_id), false));
+    accounts.push(AccountMeta::new_readonly(get_marketplace_account(program_id).0, false));
+    accounts.extend(discount_proof_metas(program_id, discount_proof));
+    accounts.extend(access_metas(program_id, mint, metadata));
+    accounts.extend(policy_metas(program_id, metadata));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitEscrow { amount }.pack(),
+    }
+}
+
+/// Lists a programmable NFT, which stays in the seller's `token_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_programmable_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    token_account: &Pubkey,
+    escrow: &Pubkey,
+    fee_recipient: &Pubkey,
+    metadata: &Metadata,
+    discount_proof: Option<&DiscountProof>,
+    amount: u64,
+) -> Instruction {
+    let mint = &metadata.mint;
+    let (authorization_rules_program, authorization_rules) = authorization_rules(metadata);
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*token_account, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(get_metadata_account(mint), false),
+        AccountMeta::new_readonly(get_master_edition_account(mint), false),
+        AccountMeta::new(get_token_record_account(mint, token_account), false),
+        AccountMeta::new_readonly(metaplex_program_id(), false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(authorization_rules_program, false),
+        AccountMeta::new_readonly(authorization_rules, false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+    ];
+    accounts.push(AccountMeta::new_readonly(get_marketplace_account(program_id).0, false));
+    accounts.extend(discount_proof_metas(program_id, discount_proof));
+    accounts.extend(access_metas(program_id, mint, Some(metadata)));
+    accounts.extend(policy_metas(program_id, Some(metadata)));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitEscrow { amount }.pack(),
+    }
+}
+
+/// Buys the listing in `escrow_pubkey`, or cancels it if the taker is the seller.
+///
+/// `fee_recipient` gets the sales fee, see [fee_recipient].
+/// `metadata` is the mint's metadata if it has any: its creators are appended,
+/// and the accounts of programmable NFTs are resolved. `referrer` gets part of
+/// the sales tax and `discount_proof` lowers it. `amount` is the token amount
+/// the taker expects to receive. The sale is refused if the listing's price
+/// no longer is `escrow`'s.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    taker_token_account: &Pubkey,
+    escrow_pubkey: &Pubkey,
+    escrow: &Escrow,
+    fee_recipient: &Pubkey,
+    token_program: &Pubkey,
+    metadata: Option<&Metadata>,
+    referrer: Option<&Pubkey>,
+    discount_proof: Option<&DiscountProof>,
+    amount: u64,
+) -> Instruction {
+    let mint = &escrow.mint_pubkey;
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(*taker_token_account, false),
+        AccountMeta::new(escrow.temp_token_account_pubkey, false),
+        AccountMeta::new(escrow.initializer_pubkey, false),
+        AccountMeta::new(*escrow_pubkey, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new(*mint, false),
+        // Moving a programmable NFT writes its metadata.
+        if matches!(metadata, Some(md) if md.is_programmable()) {
+            AccountMeta::new(get_metadata_account(mint), false)
+        } else {
+            AccountMeta::new_readonly(get_metadata_account(mint), false)
+        },
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        match referrer {
+            Some(referrer) => AccountMeta::new(*referrer, false),
+            None => AccountMeta::new_readonly(*program_id, false),
+        },
+    ];
+    accounts.extend(discount_proof_metas(program_id, discount_proof));
+    accounts.extend(access_metas(program_id, mint, metadata));
+    accounts.extend(policy_metas(program_id, metadata));
+    if let Some(metadata) = metadata {
+        if metadata.is_programmable() {
+            let (authorization_rules_program, authorization_rules) = authorization_rules(metadata);
+            accounts.extend(vec![
+                AccountMeta::new_readonly(get_master_edition_account(mint), false),
+                AccountMeta::new(get_token_record_account(mint, &escrow.temp_token_account_pubkey), false),
+                AccountMeta::new(get_token_record_account(mint, taker_token_account), false),
+                AccountMeta::new_readonly(metaplex_program_id(), false),
+                AccountMeta::new_readonly(sysvar::instructions::id(), false),
+                AccountMeta::new_readonly(Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM).unwrap(), false),
+                AccountMeta::new_readonly(authorization_rules_program, false),
+                AccountMeta::new_readonly(authorization_rules, false),
+            ]);
+        }
+        // A cancellation doesn't pay royalties, but the accounts don't hurt.
+        if let Some(creators) = &metadata.data.creators {
+            accounts.extend(creator_metas(creators));
+        }
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeV2 {
+            amount,
+            expected_price: escrow.expected_amount,
+        }
+        .pack(),
+    }
+}
+
+pub fn init_marketplace(program_id: &Pubkey, authority: &Pubkey, require_metadata: bool) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(get_marketplace_account(program_id).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: EscrowInstruction::InitMarketplace { require_metadata }.pack(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_marketplace(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    require_metadata: bool,
+    referral_basis_points: u16,
+    fee_mode: FeeMode,
+    fee_tiers: Vec<FeeTier>,
+    refundable_listing_fee: bool,
+    deposit_forfeit_seconds: u32,
+    allow_list_only: bool,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(get_marketplace_account(program_id).0, false),
+        ],
+        data: EscrowInstruction::UpdateMarketplaceV2 {
+            require_metadata,
+            referral_basis_points,
+            fee_mode,
+            fee_tiers,
+            refundable_listing_fee,
+            deposit_forfeit_seconds,
+            allow_list_only,
+        }
+        .pack(),
+    }
+}
+
+pub fn set_paused(program_id: &Pubkey, authority: &Pubkey, paused: bool) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(get_marketplace_account(program_id).0, false),
+        ],
+        data: EscrowInstruction::SetPaused { paused }.pack(),
+    }
+}
+
+pub fn set_access(program_id: &Pubkey, authority: &Pubkey, target: &Pubkey, mode: AccessMode) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+            AccountMeta::new(get_access_account(program_id, target).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: EscrowInstruction::SetAccess { target: *target, mode }.pack(),
+    }
+}
+
+/// Takes down the listing in `escrow_pubkey`, returning the token to the seller's `seller_token_account`.
+///
+/// `metadata` is the mint's metadata if it has any, for the accounts of programmable NFTs.
+pub fn force_delist(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    escrow_pubkey: &Pubkey,
+    escrow: &Escrow,
+    seller_token_account: &Pubkey,
+    token_program: &Pubkey,
+    metadata: Option<&Metadata>,
+) -> Instruction {
+    let mint = &escrow.mint_pubkey;
+    let mut accounts = vec![
+        AccountMeta::new(*authority, true),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        AccountMeta::new(*escrow_pubkey, false),
+        AccountMeta::new(escrow.initializer_pubkey, false),
+        AccountMeta::new(*seller_token_account, false),
+        AccountMeta::new(escrow.temp_token_account_pubkey, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+    ];
+    if let Some(metadata) = metadata.filter(|md| md.is_programmable()) {
+        let (authorization_rules_program, authorization_rules) = authorization_rules(metadata);
+        accounts.extend(vec![
+            AccountMeta::new(get_metadata_account(mint), false),
+            AccountMeta::new_readonly(get_master_edition_account(mint), false),
+            AccountMeta::new(get_token_record_account(mint, &escrow.temp_token_account_pubkey), false),
+            AccountMeta::new_readonly(metaplex_program_id(), false),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(authorization_rules_program, false),
+            AccountMeta::new_readonly(authorization_rules, false),
+        ]);
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ForceDelist.pack(),
+    }
+}
+
+/// Sets the policy of `target`: the creator themselves, or a verified collection they are a verified creator of.
+pub fn set_creator_policy(
+    program_id: &Pubkey,
+    creator: &Pubkey,
+    target: &Pubkey,
+    min_price: u64,
+    royalty_floor_basis_points: u16,
+    payment_mints: Vec<Pubkey>,
+) -> Instruction {
+    let collection_metadata = if target == creator {
+        *program_id
+    } else {
+        get_metadata_account(target)
+    };
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*creator, true),
+            AccountMeta::new(get_policy_account(program_id, target).0, false),
+            AccountMeta::new_readonly(collection_metadata, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: EscrowInstruction::SetCreatorPolicy {
+            target: *target,
+            min_price,
+            royalty_floor_basis_points,
+            payment_mints,
+        }
+        .pack(),
+    }
+}
+
+pub fn init_treasury(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    treasury_authority: &Pubkey,
+    period_seconds: u32,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(get_marketplace_account(program_id).0, false),
+            AccountMeta::new(get_treasury_account(program_id).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: EscrowInstruction::InitTreasury {
+            authority: *treasury_authority,
+            period_seconds,
+        }
+        .pack(),
+    }
+}
+
+pub fn withdraw_fees(program_id: &Pubkey, treasury_authority: &Pubkey, destination: &Pubkey, amount: u64) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*treasury_authority, true),
+            AccountMeta::new(get_treasury_account(program_id).0, false),
+            AccountMeta::new(*destination, false),
+        ],
+        data: EscrowInstruction::WithdrawFees { amount }.pack(),
+    }
+}
+
+/// The leaf of a compressed NFT to list, as returned by a DAS API provider.
+pub struct CompressedLeaf {
+    pub merkle_tree: Pubkey,
+    /// The current leaf delegate, the owner if the leaf isn't delegated
+    pub leaf_delegate: Pubkey,
+    pub root: [u8; 32],
+    pub metadata_args_hash: [u8; 32],
+    pub seller_fee_basis_points: u16,
+    pub nonce: u64,
+    pub index: u32,
+    pub creators: Vec<Creator>,
+    pub proof: Vec<Pubkey>,
+}
+
+pub fn init_compressed_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    fee_recipient: &Pubkey,
+    leaf: &CompressedLeaf,
+    discount_proof: Option<&DiscountProof>,
+    amount: u64,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new_readonly(leaf.leaf_delegate, false),
+        AccountMeta::new_readonly(compression::get_tree_authority(&leaf.merkle_tree), false),
+        AccountMeta::new(leaf.merkle_tree, false),
+        AccountMeta::new_readonly(compression::noop_program_id(), false),
+        AccountMeta::new_readonly(compression::account_compression_program_id(), false),
+        AccountMeta::new_readonly(compression::bubblegum_program_id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        AccountMeta::new_readonly(get_access_account(program_id, &compression::get_asset_id(&leaf.merkle_tree, leaf.nonce)).0, false),
+        compressed_policy_meta(program_id, &leaf.creators),
+    ];
+    accounts.extend(discount_proof_metas(program_id, discount_proof));
+    accounts.extend(leaf.proof.iter().map(|node| AccountMeta::new_readonly(*node, false)));
+
+    let data = EscrowInstruction::InitCompressedEscrow {
+        amount,
+        root: leaf.root,
+        metadata_args_hash: leaf.metadata_args_hash,
+        seller_fee_basis_points: leaf.seller_fee_basis_points,
+        nonce: leaf.nonce,
+        index: leaf.index,
+        creators: leaf.creators.clone(),
+    }
+    .pack();
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// Buys the compressed NFT listed in `escrow_pubkey`, or cancels the listing
+/// if the taker is the seller. `root` and `proof` are the leaf's current ones.
+pub fn exchange_compressed(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    escrow_pubkey: &Pubkey,
+    escrow: &CompressedEscrow,
+    fee_recipient: &Pubkey,
+    root: [u8; 32],
+    proof: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(escrow.initializer_pubkey, false),
+        AccountMeta::new(*escrow_pubkey, false),
+        AccountMeta::new(*fee_recipient, false),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+        AccountMeta::new_readonly(compression::get_tree_authority(&escrow.merkle_tree_pubkey), false),
+        AccountMeta::new(escrow.merkle_tree_pubkey, false),
+        AccountMeta::new_readonly(compression::noop_program_id(), false),
+        AccountMeta::new_readonly(compression::account_compression_program_id(), false),
+        AccountMeta::new_readonly(compression::bubblegum_program_id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(get_access_account(program_id, &compression::get_asset_id(&escrow.merkle_tree_pubkey, escrow.nonce)).0, false),
+        compressed_policy_meta(program_id, &escrow.creators),
+    ];
+    // Creators are only passed on sales, so the proof follows right away on cancellations.
+    if *taker != escrow.initializer_pubkey {
+        accounts.extend(creator_metas(&escrow.creators));
+    }
+    accounts.extend(proof.iter().map(|node| AccountMeta::new_readonly(*node, false)));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeCompressed { root }.pack(),
+    }
+}
+
+/// Takes down the compressed NFT listing in `escrow_pubkey`, giving the leaf back to the seller.
+/// `root` and `proof` are the leaf's current ones.
+pub fn force_delist_compressed(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    escrow_pubkey: &Pubkey,
+    escrow: &CompressedEscrow,
+    root: [u8; 32],
+    proof: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        AccountMeta::new(*escrow_pubkey, false),
+        AccountMeta::new(escrow.initializer_pubkey, false),
+        AccountMeta::new_readonly(get_escrow_pda(program_id), false),
+        AccountMeta::new_readonly(compression::get_tree_authority(&escrow.merkle_tree_pubkey), false),
+        AccountMeta::new(escrow.merkle_tree_pubkey, false),
+        AccountMeta::new_readonly(compression::noop_program_id(), false),
+        AccountMeta::new_readonly(compression::account_compression_program_id(), false),
+        AccountMeta::new_readonly(compression::bubblegum_program_id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(proof.iter().map(|node| AccountMeta::new_readonly(*node, false)));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ForceDelistCompressed { root }.pack(),
+    }
+}
+
+pub fn migrate_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    legacy_escrow: &Pubkey,
+    escrow: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(*legacy_escrow, false),
+            AccountMeta::new(*escrow, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: EscrowInstruction::MigrateEscrow.pack(),
+    }
+}
+
+/// Changes the price of the listing in `escrow`. `metadata` is the metadata of `mint`, the escrowed
+/// mint, if it has any, for the creators' policies.
+pub fn update_price(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    mint: &Pubkey,
+    metadata: Option<&Metadata>,
+    amount: u64,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*initializer, true),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(get_metadata_account(mint), false),
+    ];
+    accounts.extend(policy_metas(program_id, metadata));
+    accounts.push(AccountMeta::new_readonly(get_marketplace_account(program_id).0, false));
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::UpdatePrice { amount }.pack(),
+    }
+}
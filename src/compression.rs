@@ -0,0 +1,158 @@
+//! Instructions of the Bubblegum program needed to list and sell compressed NFTs.
+//!
+//! Compressed NFTs are leaves in a concurrent merkle tree rather than token accounts.
+//! A listing makes the escrow PDA the leaf delegate, which lets it transfer the leaf
+//! to the buyer later. Every instruction touching a leaf takes the current root,
+//! the leaf's hashes and a proof, passed as trailing accounts.
+
+use {
+    solana_program::{
+        instruction::{AccountMeta, Instruction},
+        keccak,
+        pubkey::Pubkey,
+        system_program,
+    },
+};
+
+use crate::metadata::Creator;
+
+pub const BUBBLEGUM: &str = "BGUMAp9Gq7iTEuizy4pqaxsTyUCBK68MDfK752saRPUY";
+pub const ACCOUNT_COMPRESSION: &str = "cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK";
+pub const NOOP: &str = "noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV";
+
+// The ids above, decoded: base58 decoding costs thousands of compute units on-chain.
+const BUBBLEGUM_ID: Pubkey = Pubkey::new_from_array([
+    152, 139, 128, 235, 121, 53, 40, 105, 178, 36, 116, 95, 89, 221, 191, 138, 38, 88, 202, 19, 220, 104, 129, 33, 38, 53, 28, 174, 7, 193, 165, 165,
+]);
+const ACCOUNT_COMPRESSION_ID: Pubkey = Pubkey::new_from_array([
+    9, 42, 19, 238, 149, 196, 28, 186, 8, 166, 127, 90, 198, 126, 141, 247, 225, 218, 17, 98, 94, 29, 100, 19, 127, 143, 79, 35, 131, 3, 127, 20,
+]);
+const NOOP_ID: Pubkey = Pubkey::new_from_array([
+    11, 188, 15, 192, 187, 71, 202, 47, 116, 196, 17, 46, 148, 171, 19, 207, 163, 198, 52, 229, 220, 23, 234, 203, 3, 205, 26, 35, 205, 126, 120, 124,
+]);
+
+// Anchor discriminators, the first 8 bytes of sha256("global:<instruction name>").
+const DELEGATE: [u8; 8] = [90, 147, 75, 178, 85, 88, 4, 137];
+const TRANSFER: [u8; 8] = [163, 52, 200, 231, 140, 3, 69, 186];
+
+pub fn bubblegum_program_id() -> Pubkey {
+    BUBBLEGUM_ID
+}
+
+pub fn account_compression_program_id() -> Pubkey {
+    ACCOUNT_COMPRESSION_ID
+}
+
+pub fn noop_program_id() -> Pubkey {
+    NOOP_ID
+}
+
+/// The tree authority, the PDA of the Bubblegum program with the merkle tree as seed.
+/// Bubblegum checks it, so it's only needed off-chain.
+pub fn get_tree_authority(merkle_tree: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[merkle_tree.as_ref()], &bubblegum_program_id()).0
+}
+
+/// The asset id of a leaf, the PDA standing in for the mint of a compressed NFT.
+pub fn get_asset_id(merkle_tree: &Pubkey, nonce: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"asset", merkle_tree.as_ref(), &nonce.to_le_bytes()],
+        &bubblegum_program_id(),
+    )
+    .0
+}
+
+/// The data hash of a leaf, as computed by Bubblegum from the hash of the
+/// metadata args and the royalty basis points.
+pub fn hash_metadata_data(metadata_args_hash: &[u8; 32], seller_fee_basis_points: u16) -> [u8; 32] {
+    keccak::hashv(&[metadata_args_hash, &seller_fee_basis_points.to_le_bytes()]).to_bytes()
+}
+
+/// The creator hash of a leaf, as computed by Bubblegum.
+pub fn hash_creators(creators: &[Creator]) -> [u8; 32] {
+    let creator_data: Vec<Vec<u8>> = creators
+        .iter()
+        .map(|c| [c.address.as_ref(), &[c.verified as u8], &[c.share]].concat())
+        .collect();
+    let slices: Vec<&[u8]> = creator_data.iter().map(|c| c.as_slice()).collect();
+    keccak::hashv(&slices).to_bytes()
+}
+
+/// The leaf being delegated or transferred, and the tree root it's proven against.
+pub struct Leaf {
+    pub root: [u8; 32],
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+    pub nonce: u64,
+    pub index: u32,
+}
+
+fn instruction_data(discriminator: [u8; 8], leaf: &Leaf) -> Vec<u8> {
+    let mut data = discriminator.to_vec();
+    data.extend_from_slice(&leaf.root);
+    data.extend_from_slice(&leaf.data_hash);
+    data.extend_from_slice(&leaf.creator_hash);
+    data.extend_from_slice(&leaf.nonce.to_le_bytes());
+    data.extend_from_slice(&leaf.index.to_le_bytes());
+    data
+}
+
+fn proof_metas(proof: &[Pubkey]) -> impl Iterator<Item = AccountMeta> + '_ {
+    proof.iter().map(|node| AccountMeta::new_readonly(*node, false))
+}
+
+/// Sets the delegate of a leaf, signed by the leaf owner.
+pub fn delegate(
+    tree_authority: &Pubkey,
+    merkle_tree: &Pubkey,
+    leaf_owner: &Pubkey,
+    previous_leaf_delegate: &Pubkey,
+    new_leaf_delegate: &Pubkey,
+    leaf: &Leaf,
+    proof: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*tree_authority, false),
+        AccountMeta::new_readonly(*leaf_owner, true),
+        AccountMeta::new_readonly(*previous_leaf_delegate, false),
+        AccountMeta::new_readonly(*new_leaf_delegate, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(noop_program_id(), false),
+        AccountMeta::new_readonly(account_compression_program_id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(proof_metas(proof));
+    Instruction {
+        program_id: bubblegum_program_id(),
+        accounts,
+        data: instruction_data(DELEGATE, leaf),
+    }
+}
+
+/// Transfers a leaf to a new owner, signed by the leaf delegate.
+pub fn transfer(
+    tree_authority: &Pubkey,
+    merkle_tree: &Pubkey,
+    leaf_owner: &Pubkey,
+    leaf_delegate: &Pubkey,
+    new_leaf_owner: &Pubkey,
+    leaf: &Leaf,
+    proof: &[Pubkey],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*tree_authority, false),
+        AccountMeta::new_readonly(*leaf_owner, false),
+        AccountMeta::new_readonly(*leaf_delegate, true),
+        AccountMeta::new_readonly(*new_leaf_owner, false),
+        AccountMeta::new(*merkle_tree, false),
+        AccountMeta::new_readonly(noop_program_id(), false),
+        AccountMeta::new_readonly(account_compression_program_id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    accounts.extend(proof_metas(proof));
+    Instruction {
+        program_id: bubblegum_program_id(),
+        accounts,
+        data: instruction_data(TRANSFER, leaf),
+    }
+}
@@ -42,6 +42,75 @@ pub enum EscrowError {
 
     #[error("Creator mismatch")]
     CreatorMismatch,
+
+    #[error("Invalid marketplace account")]
+    InvalidMarketplaceAccount,
+
+    #[error("Invalid marketplace authority")]
+    InvalidMarketplaceAuthority,
+
+    #[error("Escrow account not owned by this program")]
+    InvalidEscrowOwner,
+
+    #[error("Token account not owned by the token program")]
+    InvalidTokenAccountOwner,
+
+    #[error("Mint not owned by the token program")]
+    InvalidMintOwner,
+
+    #[error("Metadata account not owned by the token metadata program")]
+    InvalidMetadataOwner,
+
+    #[error("Account data type mismatch")]
+    DataTypeMismatch,
+
+    #[error("Referral fee higher than the sales tax")]
+    InvalidReferralFee,
+
+    #[error("Invalid referrer")]
+    InvalidReferrer,
+
+    #[error("Invalid fee tier")]
+    InvalidFeeTier,
+
+    #[error("Token account doesn't prove a fee tier")]
+    InvalidDiscountProof,
+
+    #[error("Invalid treasury account")]
+    InvalidTreasuryAccount,
+
+    #[error("Invalid treasury authority")]
+    InvalidTreasuryAuthority,
+
+    #[error("Marketplace paused")]
+    MarketplacePaused,
+
+    #[error("Mint or collection denied")]
+    AccessDenied,
+
+    #[error("Mint or collection not on the allow list")]
+    NotAllowListed,
+
+    #[error("Invalid access list account")]
+    InvalidAccessAccount,
+
+    #[error("Invalid creator policy")]
+    InvalidCreatorPolicy,
+
+    #[error("Not a verified creator")]
+    NotVerifiedCreator,
+
+    #[error("Price below the creator's minimum")]
+    PriceBelowPolicyMinimum,
+
+    #[error("Payment mint not allowed by the creator")]
+    PaymentMintNotAllowed,
+
+    #[error("Listing price changed")]
+    PriceChanged,
+
+    #[error("Mint extension not supported")]
+    UnsupportedMintExtension,
 }
 
 impl From<EscrowError> for ProgramError {
@@ -0,0 +1,114 @@
+//! Machine-parsable events, logged for every change to a listing and every fee withdrawal.
+//!
+//! Each event is Borsh-encoded and logged in base64 as `Program data: <base64>`,
+//! the format of `sol_log_data`. That syscall isn't available in the Solana
+//! version we build against, so the line goes through `sol_log` for now and shows
+//! up as `Program log: Program data: <base64>`. `decode_events` accepts both.
+
+use {
+    borsh::{BorshDeserialize, BorshSerialize},
+    solana_program::{msg, pubkey::Pubkey},
+    std::str::FromStr,
+};
+
+use crate::state::FeeMode;
+
+const PROGRAM_DATA: &str = "Program data: ";
+const PROGRAM_LOG: &str = "Program log: ";
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct RoyaltyPayment {
+    pub creator: Pubkey,
+    pub amount: u64,
+}
+
+/// `mint` is the asset id for compressed NFTs, which have no mint.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub enum EscrowEvent {
+    ListingCreated {
+        escrow: Pubkey,
+        seller: Pubkey,
+        mint: Pubkey,
+        price: u64,
+    },
+    ListingCancelled {
+        escrow: Pubkey,
+        seller: Pubkey,
+        mint: Pubkey,
+    },
+    Sale {
+        escrow: Pubkey,
+        seller: Pubkey,
+        buyer: Pubkey,
+        mint: Pubkey,
+        price: u64,
+        sales_tax: u64,
+        royalties: Vec<RoyaltyPayment>,
+        seller_proceeds: u64,
+        fee_mode: FeeMode,
+    },
+    PriceUpdated {
+        escrow: Pubkey,
+        old_price: u64,
+        new_price: u64,
+        sequence: u64,
+    },
+    ListingMigrated {
+        legacy_escrow: Pubkey,
+        escrow: Pubkey,
+    },
+    /// Logged after the `Sale` it's part of, `amount` is taken from the sales tax.
+    ReferralPaid {
+        escrow: Pubkey,
+        referrer: Pubkey,
+        amount: u64,
+    },
+    FeesWithdrawn {
+        treasury: Pubkey,
+        destination: Pubkey,
+        amount: u64,
+    },
+}
+
+impl EscrowEvent {
+    pub fn emit(&self) {
+        msg!("{}{}", PROGRAM_DATA, base64::encode(self.try_to_vec().unwrap()));
+    }
+}
+
+/// Decodes the events logged by `program_id` in a transaction's log messages.
+///
+/// Log lines are attributed to the innermost program being invoked, so data
+/// logged by other programs, including ones we CPI into, is skipped.
+pub fn decode_events(program_id: &Pubkey, logs: &[String]) -> Vec<EscrowEvent> {
+    let mut stack: Vec<Pubkey> = vec![];
+    let mut events = vec![];
+    for line in logs {
+        let mut words = line.split_whitespace();
+        if let (Some("Program"), Some(id), Some(status)) = (words.next(), words.next(), words.next()) {
+            if let Ok(id) = Pubkey::from_str(id) {
+                match status {
+                    "invoke" => stack.push(id),
+                    "success" | "failed:" => {
+                        stack.pop();
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+        }
+
+        if stack.last() != Some(program_id) {
+            continue;
+        }
+        let data = line.strip_prefix(PROGRAM_LOG).unwrap_or(line);
+        let event = data
+            .strip_prefix(PROGRAM_DATA)
+            .and_then(|data| base64::decode(data).ok())
+            .and_then(|data| EscrowEvent::try_from_slice(&data).ok());
+        if let Some(event) = event {
+            events.push(event);
+        }
+    }
+    events
+}
@@ -0,0 +1,247 @@
+//! What a sale costs the buyer and who gets what.
+//!
+//! The processor pays out exactly the quote computed here, so clients can call
+//! the same functions to show buyers the breakdown before they sign.
+
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{
+    error::EscrowError,
+    events::RoyaltyPayment,
+    metadata::{Creator, Metadata},
+    state::{CompressedEscrow, CreatorPolicy, Escrow, FeeMode, FeeTier, Marketplace, MAX_FEE_TIERS, MAX_PAYMENT_MINTS},
+};
+
+/// The sales tax, in basis points of the price.
+pub const SALES_TAX: u64 = 250;
+/// The listing fee, in lamports.
+pub const LISTING_FEE: u64 = 10000000; // 0.01 SOL
+
+/// The marketplace settings and circumstances of a sale that its fees depend on.
+///
+/// The default terms are those of a marketplace that hasn't been configured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaleTerms {
+    pub fee_mode: FeeMode,
+    pub sales_tax_basis_points: u16,
+    // The referrer's cut of the sales tax, zero for sales without a referrer.
+    pub referral_basis_points: u16,
+    // The royalties paid at least, set by the creators' policies.
+    pub royalty_floor_basis_points: u16,
+}
+
+impl Default for SaleTerms {
+    fn default() -> Self {
+        SaleTerms {
+            fee_mode: FeeMode::default(),
+            sales_tax_basis_points: SALES_TAX as u16,
+            referral_basis_points: 0,
+            royalty_floor_basis_points: 0,
+        }
+    }
+}
+
+impl SaleTerms {
+    /// The terms of a sale on `marketplace`, through a referrer or not,
+    /// to a buyer holding the token of `fee_tier` if any.
+    ///
+    /// The referrer's cut is capped at the discounted sales tax.
+    pub fn new(marketplace: &Marketplace, referred: bool, fee_tier: Option<&FeeTier>) -> Self {
+        let sales_tax_basis_points = fee_tier.map_or(SALES_TAX as u16, |tier| tier.sales_tax_basis_points);
+        let referral_basis_points = if referred { marketplace.referral_basis_points } else { 0 };
+        SaleTerms {
+            fee_mode: marketplace.fee_mode,
+            sales_tax_basis_points,
+            referral_basis_points: referral_basis_points.min(sales_tax_basis_points),
+            royalty_floor_basis_points: 0,
+        }
+    }
+}
+
+/// The listing fee for a seller holding the token of `fee_tier` if any.
+pub fn listing_fee(fee_tier: Option<&FeeTier>) -> u64 {
+    fee_tier.map_or(LISTING_FEE, |tier| tier.listing_fee)
+}
+
+/// The cheapest tier of `marketplace` for the holder of a token of `mint`,
+/// in the verified `collection` if any.
+pub fn find_fee_tier<'a>(marketplace: &'a Marketplace, mint: &Pubkey, collection: Option<&Pubkey>) -> Option<&'a FeeTier> {
+    marketplace
+        .fee_tiers
+        .iter()
+        .filter(|tier| tier.token == *mint || Some(&tier.token) == collection)
+        .min_by_key(|tier| tier.sales_tax_basis_points)
+}
+
+/// The breakdown of a sale, all amounts in lamports paid by the buyer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaleQuote {
+    pub price: u64,
+    pub fee_mode: FeeMode,
+    pub sales_tax: u64,
+    // The part of the sales tax going to the referrer, the rest goes to the house.
+    pub referral_fee: u64,
+    // One payment per creator, in the order of the metadata's creators.
+    pub royalties: Vec<RoyaltyPayment>,
+    // The price minus the sales tax and the royalties if the seller pays the fees,
+    // the price otherwise. Rounding leftovers of the creators' shares stay with the buyer.
+    pub seller_proceeds: u64,
+    // Everything the buyer pays: the sales tax, the royalties and the seller proceeds.
+    pub total: u64,
+}
+
+impl SaleQuote {
+    /// The part of the sales tax the house keeps.
+    pub fn house_fee(&self) -> u64 {
+        self.sales_tax - self.referral_fee
+    }
+}
+
+/// Rejects royalties that would leave nothing for the seller once the sales tax is paid.
+pub fn check_seller_fee_basis_points(seller_fee_basis_points: u16) -> Result<(), ProgramError> {
+    if seller_fee_basis_points as u64 + SALES_TAX > 10000 {
+        return Err(EscrowError::InvalidRoyaltyFee.into());
+    }
+    Ok(())
+}
+
+/// Rejects referral fees that the sales tax can't cover.
+pub fn check_referral_basis_points(referral_basis_points: u16) -> Result<(), ProgramError> {
+    if referral_basis_points as u64 > SALES_TAX {
+        return Err(EscrowError::InvalidReferralFee.into());
+    }
+    Ok(())
+}
+
+/// Rejects fee tiers that don't fit in the marketplace account or raise the fees.
+pub fn check_fee_tiers(fee_tiers: &[FeeTier]) -> Result<(), ProgramError> {
+    if fee_tiers.len() > MAX_FEE_TIERS {
+        msg!("At most {:?} fee tiers, got {:?}", MAX_FEE_TIERS, fee_tiers.len());
+        return Err(EscrowError::InvalidFeeTier.into());
+    }
+    for tier in fee_tiers {
+        if tier.token == Pubkey::default()
+            || tier.sales_tax_basis_points as u64 > SALES_TAX
+            || tier.listing_fee > LISTING_FEE
+        {
+            msg!("Invalid fee tier for token {:?}", tier.token);
+            return Err(EscrowError::InvalidFeeTier.into());
+        }
+    }
+    Ok(())
+}
+
+/// Rejects creator policies that don't fit in their account or leave nothing for the seller.
+pub fn check_creator_policy(policy: &CreatorPolicy) -> Result<(), ProgramError> {
+    if policy.payment_mints.len() > MAX_PAYMENT_MINTS {
+        msg!("At most {:?} payment mints, got {:?}", MAX_PAYMENT_MINTS, policy.payment_mints.len());
+        return Err(EscrowError::InvalidCreatorPolicy.into());
+    }
+    check_seller_fee_basis_points(policy.royalty_floor_basis_points)
+}
+
+/// Checks a listing at `price` against the creator policies of its token,
+/// returning the royalty floor they set.
+pub fn apply_creator_policies(policies: &[CreatorPolicy], price: u64) -> Result<u16, ProgramError> {
+    let mut royalty_floor_basis_points = 0;
+    for policy in policies {
+        if price < policy.min_price {
+            msg!("Price {:?} below the minimum {:?} set for {:?}", price, policy.min_price, policy.target);
+            return Err(EscrowError::PriceBelowPolicyMinimum.into());
+        }
+        if !policy.payment_mints.is_empty() && !policy.payment_mints.contains(&spl_token::native_mint::id()) {
+            msg!("Sales are paid in SOL, which {:?} doesn't allow", policy.target);
+            return Err(EscrowError::PaymentMintNotAllowed.into());
+        }
+        royalty_floor_basis_points = royalty_floor_basis_points.max(policy.royalty_floor_basis_points);
+    }
+    Ok(royalty_floor_basis_points)
+}
+
+/// Quotes a sale at `price` with the royalty settings of the token.
+///
+/// Fails with `InvalidRoyaltyFee` if the royalties and the sales tax exceed the
+/// price, and with `InvalidFinalAmount` if the seller would get nothing.
+pub fn quote_sale(
+    price: u64,
+    seller_fee_basis_points: u16,
+    creators: Option<&[Creator]>,
+    terms: &SaleTerms,
+) -> Result<SaleQuote, ProgramError> {
+    let sales_tax = price
+        .checked_mul(terms.sales_tax_basis_points as u64)
+        .ok_or(EscrowError::AmountOverflow)?
+        / 10000;
+    if terms.referral_basis_points > terms.sales_tax_basis_points {
+        return Err(EscrowError::InvalidReferralFee.into());
+    }
+    let referral_fee = price * terms.referral_basis_points as u64 / 10000;
+    // The creators' royalty floor, if there are creators to pay it to.
+    let seller_fee_basis_points = match creators {
+        Some(creators) if !creators.is_empty() => seller_fee_basis_points.max(terms.royalty_floor_basis_points),
+        _ => seller_fee_basis_points,
+    };
+    check_seller_fee_basis_points(seller_fee_basis_points)?;
+    let royalty_total = price
+        .checked_mul(seller_fee_basis_points as u64)
+        .ok_or(EscrowError::AmountOverflow)?
+        / 10000;
+
+    // Note: we are disregarding the primary_sale_happened flag,
+    // because a lot of collections/minters are not using it properly.
+    let royalties: Vec<RoyaltyPayment> = creators
+        .unwrap_or_default()
+        .iter()
+        .map(|creator| RoyaltyPayment {
+            creator: creator.address,
+            amount: creator.share as u64 * royalty_total / 100,
+        })
+        .collect();
+
+    let seller_proceeds = match terms.fee_mode {
+        FeeMode::SellerPaid => price - sales_tax - royalty_total,
+        FeeMode::BuyerPaid => price,
+    };
+    if seller_proceeds == 0 {
+        msg!("Final amount {:?} is non-positive / tax={:?} / royalties={:?}", seller_proceeds, sales_tax, royalty_total);
+        return Err(EscrowError::InvalidFinalAmount.into());
+    }
+    let total = royalties
+        .iter()
+        .map(|royalty| royalty.amount)
+        .try_fold(sales_tax, u64::checked_add)
+        .and_then(|fees| fees.checked_add(seller_proceeds))
+        .ok_or(EscrowError::AmountOverflow)?;
+
+    Ok(SaleQuote {
+        price,
+        fee_mode: terms.fee_mode,
+        sales_tax,
+        referral_fee,
+        royalties,
+        seller_proceeds,
+        total,
+    })
+}
+
+/// Quotes buying the listing in `escrow`, given the metadata of its mint if it has any.
+pub fn quote_escrow(
+    escrow: &Escrow,
+    metadata: Option<&Metadata>,
+    terms: &SaleTerms,
+) -> Result<SaleQuote, ProgramError> {
+    match metadata {
+        Some(md) => quote_sale(
+            escrow.expected_amount,
+            md.data.seller_fee_basis_points,
+            md.data.creators.as_deref(),
+            terms,
+        ),
+        None => quote_sale(escrow.expected_amount, 0, None, terms),
+    }
+}
+
+/// Quotes buying the compressed NFT listed in `escrow`.
+pub fn quote_compressed_escrow(escrow: &CompressedEscrow, terms: &SaleTerms) -> Result<SaleQuote, ProgramError> {
+    quote_sale(escrow.expected_amount, escrow.seller_fee_basis_points, Some(&escrow.creators), terms)
+}
@@ -0,0 +1,296 @@
+//! An in-memory order book of the program's listings, for off-chain indexers.
+//!
+//! The book is loaded from the program accounts, as returned by `getProgramAccounts`
+//! with the filters below, and kept current by replaying the events the program logs.
+
+use {
+    solana_program::{program_pack::Pack, pubkey::Pubkey},
+    std::collections::{BTreeSet, HashMap},
+};
+
+use crate::{
+    compression,
+    events::{decode_events, EscrowEvent},
+    metadata::Metadata,
+    state::{account_key, AccountKey, CompressedEscrow, Escrow, LEGACY_COMPRESSED_ESCROW_LEN, LEGACY_ESCROW_LEN},
+};
+
+// Offsets of the fields worth filtering on, see the layouts in `state`.
+pub const ESCROW_SELLER_OFFSET: usize = 2;
+pub const ESCROW_MINT_OFFSET: usize = 34;
+// Legacy escrows have no version byte.
+pub const LEGACY_ESCROW_SELLER_OFFSET: usize = 1;
+pub const LEGACY_ESCROW_MINT_OFFSET: usize = 33;
+pub const COMPRESSED_ESCROW_SELLER_OFFSET: usize = 1;
+
+/// A `getProgramAccounts` filter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    DataSize(usize),
+    Memcmp { offset: usize, bytes: Vec<u8> },
+}
+
+impl Filter {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            Filter::DataSize(size) => data.len() == *size,
+            Filter::Memcmp { offset, bytes } => data.get(*offset..*offset + bytes.len()) == Some(&bytes[..]),
+        }
+    }
+}
+
+fn memcmp(offset: usize, pubkey: &Pubkey) -> Filter {
+    Filter::Memcmp {
+        offset,
+        bytes: pubkey.to_bytes().to_vec(),
+    }
+}
+
+/// One set of filters per account layout, together matching every listing.
+pub fn listing_filters() -> Vec<Vec<Filter>> {
+    vec![
+        vec![Filter::DataSize(Escrow::LEN)],
+        vec![Filter::DataSize(LEGACY_ESCROW_LEN)],
+        vec![Filter::DataSize(CompressedEscrow::LEN)],
+        vec![Filter::DataSize(LEGACY_COMPRESSED_ESCROW_LEN)],
+    ]
+}
+
+/// One set of filters per account layout, together matching the listings of `seller`.
+pub fn seller_filters(seller: &Pubkey) -> Vec<Vec<Filter>> {
+    vec![
+        vec![Filter::DataSize(Escrow::LEN), memcmp(ESCROW_SELLER_OFFSET, seller)],
+        vec![Filter::DataSize(LEGACY_ESCROW_LEN), memcmp(LEGACY_ESCROW_SELLER_OFFSET, seller)],
+        vec![
+            Filter::DataSize(CompressedEscrow::LEN),
+            memcmp(COMPRESSED_ESCROW_SELLER_OFFSET, seller),
+        ],
+        vec![
+            Filter::DataSize(LEGACY_COMPRESSED_ESCROW_LEN),
+            memcmp(COMPRESSED_ESCROW_SELLER_OFFSET, seller),
+        ],
+    ]
+}
+
+/// One set of filters per account layout, together matching the listings of `mint`.
+/// Compressed escrows don't store their asset id, so they can't be filtered by it.
+pub fn mint_filters(mint: &Pubkey) -> Vec<Vec<Filter>> {
+    vec![
+        vec![Filter::DataSize(Escrow::LEN), memcmp(ESCROW_MINT_OFFSET, mint)],
+        vec![Filter::DataSize(LEGACY_ESCROW_LEN), memcmp(LEGACY_ESCROW_MINT_OFFSET, mint)],
+    ]
+}
+
+/// A listing, of either a token or a compressed NFT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listing {
+    pub escrow: Pubkey,
+    pub seller: Pubkey,
+    // The asset id for compressed NFTs.
+    pub mint: Pubkey,
+    pub price: u64,
+    // Zero for listings that don't record it, i.e. legacy and compressed escrows.
+    pub sequence: u64,
+}
+
+/// Decodes the program account `pubkey` into a listing, if it holds one.
+pub fn decode_listing(pubkey: &Pubkey, data: &[u8]) -> Option<Listing> {
+    match account_key(data).ok()? {
+        AccountKey::EscrowV0 | AccountKey::EscrowV1 => {
+            let escrow = Escrow::unpack_versioned(data).ok()?;
+            Some(Listing {
+                escrow: *pubkey,
+                seller: escrow.initializer_pubkey,
+                mint: escrow.mint_pubkey,
+                price: escrow.expected_amount,
+                sequence: escrow.sequence,
+            })
+        }
+        AccountKey::CompressedEscrow => {
+            let escrow = CompressedEscrow::unpack_versioned(data).ok()?;
+            Some(Listing {
+                escrow: *pubkey,
+                seller: escrow.initializer_pubkey,
+                mint: compression::get_asset_id(&escrow.merkle_tree_pubkey, escrow.nonce),
+                price: escrow.expected_amount,
+                sequence: 0,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn collection(metadata: &Metadata) -> Option<Pubkey> {
+    metadata.verified_collection().copied()
+}
+
+/// Listings indexed by escrow, mint, seller and collection.
+///
+/// Queries return listings cheapest first.
+#[derive(Default)]
+pub struct OrderBook {
+    listings: HashMap<Pubkey, Listing>,
+    metadata: HashMap<Pubkey, Metadata>,
+    by_mint: HashMap<Pubkey, BTreeSet<Pubkey>>,
+    by_seller: HashMap<Pubkey, BTreeSet<Pubkey>>,
+    by_collection: HashMap<Pubkey, BTreeSet<Pubkey>>,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.listings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_empty()
+    }
+
+    pub fn get(&self, escrow: &Pubkey) -> Option<&Listing> {
+        self.listings.get(escrow)
+    }
+
+    /// The metadata of `mint`, if it was added.
+    pub fn metadata(&self, mint: &Pubkey) -> Option<&Metadata> {
+        self.metadata.get(mint)
+    }
+
+    pub fn by_mint(&self, mint: &Pubkey) -> Vec<&Listing> {
+        self.query(self.by_mint.get(mint))
+    }
+
+    pub fn by_seller(&self, seller: &Pubkey) -> Vec<&Listing> {
+        self.query(self.by_seller.get(seller))
+    }
+
+    pub fn by_collection(&self, collection: &Pubkey) -> Vec<&Listing> {
+        self.query(self.by_collection.get(collection))
+    }
+
+    fn query(&self, escrows: Option<&BTreeSet<Pubkey>>) -> Vec<&Listing> {
+        let mut listings: Vec<&Listing> = escrows
+            .into_iter()
+            .flatten()
+            .filter_map(|escrow| self.listings.get(escrow))
+            .collect();
+        listings.sort_by_key(|listing| listing.price);
+        listings
+    }
+
+    /// Loads a program account. Returns whether it held a listing.
+    ///
+    /// A listing already known at a later sequence, from the events, is kept.
+    pub fn load_account(&mut self, pubkey: &Pubkey, data: &[u8]) -> bool {
+        match decode_listing(pubkey, data) {
+            Some(listing) => {
+                if !matches!(self.get(pubkey), Some(known) if known.sequence > listing.sequence) {
+                    self.insert(listing);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds the metadata of a mint, indexing its listings by collection.
+    pub fn add_metadata(&mut self, metadata: Metadata) {
+        let mint = metadata.mint;
+        let escrows: Vec<Pubkey> = self.by_mint.get(&mint).into_iter().flatten().copied().collect();
+        if let Some(old) = self.metadata.get(&mint).and_then(collection) {
+            for escrow in &escrows {
+                Self::unindex(&mut self.by_collection, &old, escrow);
+            }
+        }
+        if let Some(new) = collection(&metadata) {
+            for escrow in escrows {
+                self.by_collection.entry(new).or_default().insert(escrow);
+            }
+        }
+        self.metadata.insert(mint, metadata);
+    }
+
+    /// Inserts or replaces a listing.
+    pub fn insert(&mut self, listing: Listing) {
+        self.remove(&listing.escrow);
+        let escrow = listing.escrow;
+        self.by_mint.entry(listing.mint).or_default().insert(escrow);
+        self.by_seller.entry(listing.seller).or_default().insert(escrow);
+        if let Some(collection) = self.metadata.get(&listing.mint).and_then(collection) {
+            self.by_collection.entry(collection).or_default().insert(escrow);
+        }
+        self.listings.insert(escrow, listing);
+    }
+
+    pub fn remove(&mut self, escrow: &Pubkey) -> Option<Listing> {
+        let listing = self.listings.remove(escrow)?;
+        Self::unindex(&mut self.by_mint, &listing.mint, escrow);
+        Self::unindex(&mut self.by_seller, &listing.seller, escrow);
+        if let Some(collection) = self.metadata.get(&listing.mint).and_then(collection) {
+            Self::unindex(&mut self.by_collection, &collection, escrow);
+        }
+        Some(listing)
+    }
+
+    fn unindex(index: &mut HashMap<Pubkey, BTreeSet<Pubkey>>, key: &Pubkey, escrow: &Pubkey) {
+        if let Some(escrows) = index.get_mut(key) {
+            escrows.remove(escrow);
+            if escrows.is_empty() {
+                index.remove(key);
+            }
+        }
+    }
+
+    /// Applies an event, in the order the program logged them.
+    pub fn apply_event(&mut self, event: &EscrowEvent) {
+        match event {
+            EscrowEvent::ListingCreated {
+                escrow,
+                seller,
+                mint,
+                price,
+            } => self.insert(Listing {
+                escrow: *escrow,
+                seller: *seller,
+                mint: *mint,
+                price: *price,
+                sequence: 0,
+            }),
+            EscrowEvent::ListingCancelled { escrow, .. } | EscrowEvent::Sale { escrow, .. } => {
+                self.remove(escrow);
+            }
+            EscrowEvent::PriceUpdated {
+                escrow,
+                new_price,
+                sequence,
+                ..
+            } => {
+                // Skips updates older than what the account showed when it was loaded.
+                if let Some(listing) = self.listings.get_mut(escrow) {
+                    if *sequence > listing.sequence {
+                        listing.price = *new_price;
+                        listing.sequence = *sequence;
+                    }
+                }
+            }
+            EscrowEvent::ListingMigrated { legacy_escrow, escrow } => {
+                if let Some(listing) = self.remove(legacy_escrow) {
+                    self.insert(Listing {
+                        escrow: *escrow,
+                        ..listing
+                    });
+                }
+            }
+            EscrowEvent::ReferralPaid { .. } | EscrowEvent::FeesWithdrawn { .. } => (),
+        }
+    }
+
+    /// Applies the events that `program_id` logged in a transaction.
+    pub fn apply_logs(&mut self, program_id: &Pubkey, logs: &[String]) {
+        for event in decode_events(program_id, logs) {
+            self.apply_event(&event);
+        }
+    }
+}
@@ -1,8 +1,19 @@
-use solana_program::program_error::ProgramError;
-use std::convert::TryInto;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 
 use crate::error::EscrowError::InvalidInstruction;
+use crate::metadata::{Creator, MAX_CREATOR_LIMIT};
+use crate::state::{AccessMode, FeeMode, FeeTier};
 
+/// Instructions are Borsh-encoded: a one byte tag, the variant's position in
+/// this enum, followed by its fields in order, integers in little endian.
+///
+/// The tag versions the payload. Variants are never reordered and their fields never
+/// change: a new payload for an instruction is added as a new variant at the end,
+/// like `UpdateMarketplaceV2` and `ExchangeV2`. The accounts an instruction expects
+/// are not versioned and may grow between program versions, so build instructions
+/// with the `client` module rather than by hand.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
 pub enum EscrowInstruction {
     /// Starts the trade by creating and populating an escrow account and transferring ownership of the given temp token account to the PDA
     /// ("maker")
@@ -13,11 +24,46 @@ pub enum EscrowInstruction {
     /// 0. `[signer]` The account of the person initializing the escrow
     /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
     /// 2. `[]` The token mint
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[writable]` The account receiving the listing fee.
+    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade. Must be `Escrow::LEN` bytes long.
+    /// 4. `[writable]` The account receiving the listing fee, the sales tax recipient or the marketplace treasury,
+    ///    unless the marketplace makes it refundable: it is then held as a deposit in the escrow account.
     /// 5. `[]` The system program
     /// 6. `[]` The rent sysvar
-    /// 7. `[]` The token program
+    /// 7. `[]` The token program owning the mint and token account, SPL token or Token-2022
+    /// 8. `[writable]` Metadata account for the mint, the PDA with seeds ["metadata", METAPLEX_PROGRAM_ID, mint].
+    ///    It may be empty. Programmable NFTs are not moved to the temp token account:
+    ///    account 1 is then the seller's token account, and the PDA becomes its sale delegate.
+    ///
+    /// Only if the token is not a programmable NFT:
+    ///
+    /// 9. `[]` The seller's token account the token was moved from into the temp token account,
+    ///    or this program's id if none. A cancellation then has to return the token to it.
+    ///
+    /// Only if the metadata says the token is a programmable NFT:
+    ///
+    ///  9. `[]` The master edition account
+    /// 10. `[writable]` The token record of the seller's token account
+    /// 11. `[]` The token metadata program
+    /// 12. `[]` The instructions sysvar
+    /// 13. `[]` The authorization rules program, or the token metadata program if none
+    /// 14. `[]` The authorization rules account, or the token metadata program if none
+    /// 15. `[]` The PDA account
+    ///
+    /// Followed by, for the marketplace's listing fee rules, fee tiers, pause switch,
+    /// allow and deny lists and the creators' policies:
+    ///
+    /// XX. `[]` The marketplace account, the PDA with seeds ["marketplace"]. It may be empty.
+    /// XX. `[]` A token account of the initializer holding the token of a fee tier, or this program's id if none
+    /// XX. `[]` The metadata account of that token's mint if the tier is by collection, or this program's id if none
+    /// XX. `[]` The access list entry of the mint, the PDA with seeds ["access", mint]. It may be empty.
+    /// XX. `[]` The access list entry of the mint's verified collection, the PDA with seeds ["access", collection],
+    ///     or this program's id if the mint has no verified collection.
+    /// XX. `[]` The creator policy of the mint's verified collection, the PDA with seeds ["policy", collection],
+    ///     or this program's id if the mint has no verified collection.
+    /// XX. `[]` The creator policy of the mint's first verified creator, the PDA with seeds ["policy", creator],
+    ///     or this program's id if the mint has no verified creator.
+    ///
+    /// Wire format: `[0, amount: u64]`
     InitEscrow {
         /// The SOL amount party A expects to receive, in Lamports
         amount: u64,
@@ -25,12 +71,20 @@ pub enum EscrowInstruction {
 
     /// Accepts a trade ("taker")
     ///
-    /// A sales fee is charged from the taker.
+    /// A sales fee is charged from the taker. Depending on the marketplace's fee mode,
+    /// the sales fee and royalties are taken out of the price or paid on top of it.
+    /// Takers holding the token of one of the marketplace's fee tiers pay its reduced sales fee.
     ///
     /// If the taker is the same account that set up the escrow,
     /// no payment happens and no sales fee is charged, effectively
     /// allowing for a "Cancel Escrow" flow.
     ///
+    /// A listing deposit is forfeited to the account receiving sales fees on sale. On cancellation
+    /// it is refunded, unless the listing was up longer than the marketplace's forfeit period.
+    ///
+    /// Listings whose price was changed with `UpdatePrice` can only be bought with `ExchangeV2`;
+    /// `Exchange` refuses them with `PriceChanged`, as it can't tell which price the taker saw.
+    ///
     /// Accounts expected:
     ///
     ///  0. `[signer]` The account of the person taking the trade
@@ -38,45 +92,442 @@ pub enum EscrowInstruction {
     ///  2. `[writable]` The PDA's temp token account to get tokens from and eventually close
     ///  3. `[writable]` The initializer's main account to send their rent fees to
     ///  4. `[writable]` The escrow account holding the escrow info
-    ///  5. `[writable]` The account receiving sales fees.
-    ///  6. `[writable]` Mint
+    ///  5. `[writable]` The account receiving sales fees, the sales tax recipient or the marketplace treasury.
+    ///  6. `[writable]` Mint. For Token-2022 mints with a transfer fee, the taker receives the
+    ///     token amount minus the fee; if the mint has a token metadata extension, royalties and
+    ///     creators are read from it instead of the metadata account.
     ///  7. `[writable]` Metadata account for the mint.
     ///     This MUST be the PDA with seeds ["metadata", METAPLEX_PROGRAM_ID, mint],
     ///     even if the mint does not have metadata.
     ///     If the account doesn't contain valid metadata, royalties will not be paid out.
-    ///  8. `[]` The token program
+    ///     If the account is empty, the sale goes through without royalties,
+    ///     unless the marketplace requires metadata.
+    ///     If it is not owned by the Metaplex program or can't be parsed, the trade is aborted.
+    ///  8. `[]` The token program owning the mint, SPL token or Token-2022
     ///  9. `[]` The system program
     /// 10. `[]` The PDA account
-    /// 11. `[]` Creator 0 account, if present in metadata, and in metadata order.
-    /// XX. `[]` ...more creator accounts as above...
+    /// 11. `[]` The marketplace account, the PDA with seeds ["marketplace"]. It may be empty.
+    /// 12. `[writable]` The referrer, who gets part of the sales tax as set in the marketplace,
+    ///     or this program's id if none.
+    /// 13. `[]` A token account of the taker holding the token of a fee tier, for its sales tax,
+    ///     or this program's id if none.
+    /// 14. `[]` The metadata account of that token's mint if the tier is by collection,
+    ///     or this program's id if none.
+    /// 15. `[]` The access list entry of the mint, the PDA with seeds ["access", mint]. It may be empty.
+    /// 16. `[]` The access list entry of the mint's verified collection, the PDA with seeds ["access", collection],
+    ///     or this program's id if the mint has no verified collection.
+    /// 17. `[]` The creator policy of the mint's verified collection, the PDA with seeds ["policy", collection],
+    ///     or this program's id if the mint has no verified collection.
+    /// 18. `[]` The creator policy of the mint's first verified creator, the PDA with seeds ["policy", creator],
+    ///     or this program's id if the mint has no verified creator.
+    ///
+    /// Only if the metadata says the token is a programmable NFT
+    /// (account 2 is then the seller's token account the PDA is delegate of):
+    ///
+    /// 19. `[]` The master edition account
+    /// 20. `[writable]` The token record of the seller's token account
+    /// 21. `[writable]` The token record of the taker's token account
+    /// 22. `[]` The token metadata program
+    /// 23. `[]` The instructions sysvar
+    /// 24. `[]` The associated token account program
+    /// 25. `[]` The authorization rules program, or the token metadata program if none
+    /// 26. `[]` The authorization rules account, or the token metadata program if none
+    ///
+    /// Followed by:
+    ///
+    /// XX. `[writable]` The account of each creator in metadata owed royalties, in any order.
+    ///     Creators owed nothing may be left out; duplicates and other accounts are ignored.
+    /// XX. `[writable]` ...more creator accounts as above...
+    ///
+    /// Wire format: `[1, amount: u64]`
     Exchange {
-        /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
+        /// the amount the taker expects to be paid in the other token (after transfer fees), as a u64 because that's the max possible supply of a token
+        amount: u64,
+    },
+
+    /// Creates the marketplace configuration account
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account receiving sales fees, which becomes the marketplace authority and pays for the account
+    /// 1. `[writable]` The marketplace account, the PDA with seeds ["marketplace"]
+    /// 2. `[]` The system program
+    /// 3. `[]` The rent sysvar
+    ///
+    /// Wire format: `[2, require_metadata: bool]`
+    InitMarketplace {
+        /// Reject sales of mints that have no metadata account
+        require_metadata: bool,
+    },
+
+    /// Updates whether the marketplace requires metadata, leaving the rest of its configuration
+    ///
+    /// Superseded by `UpdateMarketplaceV2`, which sets the whole configuration.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The marketplace authority
+    /// 1. `[writable]` The marketplace account
+    ///
+    /// Wire format: `[3, require_metadata: bool]`
+    UpdateMarketplace {
+        /// Reject sales of mints that have no metadata account
+        require_metadata: bool,
+    },
+
+    /// Lists a compressed NFT by populating a compressed escrow account and
+    /// making the PDA the delegate of the leaf
+    ///
+    /// Accounts expected:
+    ///
+    ///  0. `[signer, writable]` The leaf owner, initializing the escrow
+    ///  1. `[writable]` The compressed escrow account, it will hold all necessary info about the trade.
+    ///  2. `[writable]` The account receiving the listing fee, the sales tax recipient or the marketplace treasury,
+    ///     unless the marketplace makes it refundable: it is then held as a deposit in the escrow account.
+    ///  3. `[]` The current leaf delegate, the leaf owner if the leaf isn't delegated
+    ///  4. `[]` The tree authority of the merkle tree
+    ///  5. `[writable]` The merkle tree
+    ///  6. `[]` The log wrapper (noop) program
+    ///  7. `[]` The account compression program
+    ///  8. `[]` The Bubblegum program
+    ///  9. `[]` The system program
+    /// 10. `[]` The rent sysvar
+    /// 11. `[]` The PDA account
+    /// 12. `[]` The marketplace account, the PDA with seeds ["marketplace"]. It may be empty.
+    /// 13. `[]` The access list entry of the asset, the PDA with seeds ["access", asset id]. It may be empty.
+    /// 14. `[]` The creator policy of the leaf's first verified creator, the PDA with seeds ["policy", creator],
+    ///     or this program's id if the leaf has no verified creator.
+    /// 15. `[]` A token account of the initializer holding the token of a fee tier, or this program's id if none
+    /// 16. `[]` The metadata account of that token's mint if the tier is by collection, or this program's id if none
+    ///
+    /// Followed by:
+    ///
+    /// XX. `[]` The proof nodes of the leaf
+    ///
+    /// Wire format: `[4, amount: u64, root: [u8; 32], metadata_args_hash: [u8; 32], seller_fee_basis_points: u16, nonce: u64, index: u32, creators: Vec<Creator>]`
+    InitCompressedEscrow {
+        /// The SOL amount the leaf owner expects to receive, in Lamports
+        amount: u64,
+        /// The current root of the merkle tree
+        root: [u8; 32],
+        /// The hash of the leaf's metadata args
+        metadata_args_hash: [u8; 32],
+        /// The royalty basis points of the leaf
+        seller_fee_basis_points: u16,
+        /// The nonce of the leaf
+        nonce: u64,
+        /// The index of the leaf in the tree
+        index: u32,
+        /// The creators of the leaf, to pay royalties to
+        creators: Vec<Creator>,
+    },
+
+    /// Buys a compressed NFT ("taker")
+    ///
+    /// Like `Exchange`, if the taker is the leaf owner who listed it,
+    /// the listing is cancelled: the leaf delegate is reset and no payment happens.
+    /// The listing deposit is forfeited or refunded as for `Exchange`.
+    ///
+    /// Accounts expected:
+    ///
+    ///  0. `[signer, writable]` The account of the person taking the trade, and the new leaf owner
+    ///  1. `[writable]` The initializer's main account, the current leaf owner
+    ///  2. `[writable]` The compressed escrow account
+    ///  3. `[writable]` The account receiving sales fees, the sales tax recipient or the marketplace treasury.
+    ///  4. `[]` The marketplace account, the PDA with seeds ["marketplace"]. It may be empty.
+    ///  5. `[]` The PDA account, the leaf delegate
+    ///  6. `[]` The tree authority of the merkle tree
+    ///  7. `[writable]` The merkle tree
+    ///  8. `[]` The log wrapper (noop) program
+    ///  9. `[]` The account compression program
+    /// 10. `[]` The Bubblegum program
+    /// 11. `[]` The system program
+    /// 12. `[]` The access list entry of the asset, the PDA with seeds ["access", asset id]. It may be empty.
+    /// 13. `[]` The creator policy of the listing's first verified creator, the PDA with seeds ["policy", creator],
+    ///     or this program's id if the listing has no verified creator.
+    ///
+    /// Followed by:
+    ///
+    /// XX. `[writable]` The account of each creator of the listing owed royalties, in any order. None when cancelling.
+    /// XX. `[]` The proof nodes of the leaf
+    ///
+    /// Wire format: `[5, root: [u8; 32]]`
+    ExchangeCompressed {
+        /// The current root of the merkle tree
+        root: [u8; 32],
+    },
+
+    /// Upgrades an escrow in the legacy 105-byte layout to the current versioned layout
+    ///
+    /// This is not an upgrade in place: programs can't resize their accounts on the
+    /// runtime this program targets (`AccountInfo::realloc` came with Solana 1.9), and
+    /// legacy escrows are keypair accounts, which the program can't recreate at the same
+    /// address. The escrow is copied into a new account and the legacy one is closed.
+    /// The temp token account stays with the PDA, but the listing is then known by the
+    /// new escrow account: indexers follow it with the `ListingMigrated` event.
+    ///
+    /// Legacy escrows don't need migrating to be sold or cancelled, only to have
+    /// their price updated.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The initializer of the escrow, receiving the legacy account's lamports
+    /// 1. `[writable]` The legacy escrow account
+    /// 2. `[writable]` The new escrow account, created prior to this instruction with `Escrow::LEN` bytes and owned by this program
+    /// 3. `[]` The rent sysvar
+    ///
+    /// Wire format: `[6]`
+    MigrateEscrow,
+
+    /// Changes the price of a listing
+    ///
+    /// The new price has to meet the creators' policies, like the listing price.
+    /// Prices can't change while the marketplace is paused.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The initializer of the escrow
+    /// 1. `[writable]` The escrow account
+    /// 2. `[]` Metadata account for the escrowed mint, the PDA with seeds ["metadata", METAPLEX_PROGRAM_ID, mint]. It may be empty.
+    /// 3. `[]` The creator policy of the mint's verified collection, the PDA with seeds ["policy", collection],
+    ///    or this program's id if the mint has no verified collection.
+    /// 4. `[]` The creator policy of the mint's first verified creator, the PDA with seeds ["policy", creator],
+    ///    or this program's id if the mint has no verified creator.
+    /// 5. `[]` The marketplace account, the PDA with seeds ["marketplace"]. It may be empty.
+    ///
+    /// Wire format: `[7, amount: u64]`
+    UpdatePrice {
+        /// The new SOL amount the initializer expects to receive, in Lamports
         amount: u64,
     },
+
+    /// Creates the marketplace treasury, which collects all fees from then on
+    ///
+    /// The account receiving fees in the other instructions then has to be the treasury,
+    /// whenever they are passed the marketplace account.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The marketplace authority, paying for the account
+    /// 1. `[writable]` The marketplace account
+    /// 2. `[writable]` The treasury account, the PDA with seeds ["treasury", marketplace]
+    /// 3. `[]` The system program
+    /// 4. `[]` The rent sysvar
+    ///
+    /// Wire format: `[8, authority: Pubkey, period_seconds: u32]`
+    InitTreasury {
+        /// The account allowed to withdraw fees, a wallet or the signing PDA of a multisig program
+        authority: Pubkey,
+        /// The length of accounting periods, in seconds. Zero for a single period.
+        period_seconds: u32,
+    },
+
+    /// Withdraws fees from the treasury
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The treasury authority
+    /// 1. `[writable]` The treasury account
+    /// 2. `[writable]` The account receiving the fees
+    ///
+    /// Wire format: `[9, amount: u64]`
+    WithdrawFees {
+        /// The lamports to withdraw, at most what the treasury holds above its rent exemption
+        amount: u64,
+    },
+
+    /// Pauses or resumes trading
+    ///
+    /// While paused, `InitEscrow`, `InitCompressedEscrow`, `Exchange`, `ExchangeV2` and `ExchangeCompressed`
+    /// refuse new listings and sales, and `UpdatePrice` refuses new prices. Sellers can still cancel their listings.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The marketplace authority
+    /// 1. `[writable]` The marketplace account
+    ///
+    /// Wire format: `[10, paused: bool]`
+    SetPaused {
+        /// Whether to pause trading
+        paused: bool,
+    },
+
+    /// Puts a mint, a verified collection or a compressed NFT's asset id on the allow or deny list,
+    /// or takes it off them
+    ///
+    /// `InitEscrow` and `Exchange` refuse tokens denied by mint or by collection, and, if the
+    /// marketplace only takes allowed tokens, those allowed by neither. The collection of a
+    /// compressed NFT isn't known on chain: `InitCompressedEscrow` and `ExchangeCompressed`
+    /// only go by its asset id.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The marketplace authority, paying for the entry account if it's new
+    /// 1. `[]` The marketplace account
+    /// 2. `[writable]` The access list entry, the PDA with seeds ["access", target]
+    /// 3. `[]` The system program
+    /// 4. `[]` The rent sysvar
+    ///
+    /// Wire format: `[11, target: Pubkey, mode: u8]`
+    SetAccess {
+        /// The mint or the verified collection
+        target: Pubkey,
+        /// The list to put it on, `Unlisted` for neither
+        mode: AccessMode,
+    },
+
+    /// Takes a listing down on behalf of the marketplace, e.g. of a stolen NFT
+    ///
+    /// The token goes back to the seller and the escrow account is closed, its rent and
+    /// any listing deposit refunded. No fee is charged. A programmable NFT stays in the seller's
+    /// token account: the PDA revokes its delegation, unlocking it. Listings of compressed NFTs
+    /// are taken down with `ForceDelistCompressed`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The marketplace authority, paying the token metadata program if it charges anything
+    /// 1. `[]` The marketplace account
+    /// 2. `[writable]` The escrow account
+    /// 3. `[writable]` The seller's main account, receiving the rent
+    /// 4. `[writable]` The seller's token account to return the token to,
+    ///    the one it was listed from if the escrow recorded it
+    /// 5. `[writable]` The PDA's temp token account, or the seller's token account for programmable NFTs
+    /// 6. `[]` The mint
+    /// 7. `[]` The token program owning the mint, SPL token or Token-2022
+    /// 8. `[]` The PDA account
+    ///
+    /// Only for programmable NFTs:
+    ///
+    ///  9. `[writable]` The metadata account of the mint
+    /// 10. `[]` The master edition account
+    /// 11. `[writable]` The token record of the seller's token account
+    /// 12. `[]` The token metadata program
+    /// 13. `[]` The instructions sysvar
+    /// 14. `[]` The system program
+    /// 15. `[]` The authorization rules program, or the token metadata program if none
+    /// 16. `[]` The authorization rules account, or the token metadata program if none
+    ///
+    /// Wire format: `[12]`
+    ForceDelist,
+
+    /// Sets the creator policy of a verified collection or of a creator
+    ///
+    /// `InitEscrow`, `UpdatePrice` and `Exchange` refuse prices below the minimum or paid in a mint the
+    /// policy doesn't allow, and sales pay at least the royalty floor, even where the metadata asks for less.
+    /// The policies of both the token's verified collection and its first verified creator apply.
+    /// Compressed NFTs only have the policy of their first verified creator, enforced by
+    /// `InitCompressedEscrow` and `ExchangeCompressed`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The creator, paying for the policy account if it's new
+    /// 1. `[writable]` The creator policy, the PDA with seeds ["policy", target]
+    /// 2. `[]` For a collection, the metadata account of the collection mint, listing the creator as verified.
+    ///    For the creator's own policy, this program's id.
+    /// 3. `[]` The system program
+    /// 4. `[]` The rent sysvar
+    ///
+    /// Wire format: `[13, target: Pubkey, min_price: u64, royalty_floor_basis_points: u16, payment_mints: Vec<Pubkey>]`
+    SetCreatorPolicy {
+        /// The verified collection, or the creator themselves
+        target: Pubkey,
+        /// The lowest price listings are allowed at, in lamports
+        min_price: u64,
+        /// The royalties paid at least, in basis points of the price
+        royalty_floor_basis_points: u16,
+        /// The mints sales may be paid in, any if empty, at most `MAX_PAYMENT_MINTS`
+        payment_mints: Vec<Pubkey>,
+    },
+
+    /// Takes a listing of a compressed NFT down on behalf of the marketplace
+    ///
+    /// The PDA transfers the leaf from the seller back to the seller, which resets its delegate,
+    /// and the escrow account is closed, its rent and any listing deposit refunded. No fee is charged.
+    ///
+    /// Accounts expected:
+    ///
+    ///  0. `[signer]` The marketplace authority
+    ///  1. `[]` The marketplace account
+    ///  2. `[writable]` The compressed escrow account
+    ///  3. `[writable]` The seller's main account, the leaf owner, receiving the rent
+    ///  4. `[]` The PDA account, the leaf delegate
+    ///  5. `[]` The tree authority of the merkle tree
+    ///  6. `[writable]` The merkle tree
+    ///  7. `[]` The log wrapper (noop) program
+    ///  8. `[]` The account compression program
+    ///  9. `[]` The Bubblegum program
+    /// 10. `[]` The system program
+    ///
+    /// Followed by:
+    ///
+    /// XX. `[]` The proof nodes of the leaf
+    ///
+    /// Wire format: `[14, root: [u8; 32]]`
+    ForceDelistCompressed {
+        /// The current root of the merkle tree
+        root: [u8; 32],
+    },
+
+    /// Buys a listing like `Exchange`, as long as it's still at the price the taker saw
+    ///
+    /// `UpdatePrice` may land first in the same slot: the sale is then refused
+    /// rather than paid at the new price. Cancellations ignore the price.
+    ///
+    /// Accounts expected: as for `Exchange`
+    ///
+    /// Wire format: `[15, amount: u64, expected_price: u64]`
+    ExchangeV2 {
+        /// the amount the taker expects to be paid in the other token (after transfer fees)
+        amount: u64,
+        /// The listing price the taker agrees to pay, in lamports
+        expected_price: u64,
+    },
+
+    /// Updates the marketplace configuration
+    ///
+    /// Marketplace accounts created with an older, shorter layout can't be resized
+    /// to take the new fields, and are refused with `AccountDataTooSmall`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The marketplace authority
+    /// 1. `[writable]` The marketplace account
+    ///
+    /// Wire format: `[16, require_metadata: bool, referral_basis_points: u16, fee_mode: u8, fee_tiers: Vec<FeeTier>, refundable_listing_fee: bool, deposit_forfeit_seconds: u32, allow_list_only: bool]`
+    UpdateMarketplaceV2 {
+        /// Reject sales of mints that have no metadata account
+        require_metadata: bool,
+        /// The referrer's cut of the sales tax, in basis points of the price. At most the sales tax.
+        referral_basis_points: u16,
+        /// Whether the sales tax and royalties are taken out of the price or added on top of it
+        fee_mode: FeeMode,
+        /// The discounts of token holders, at most `MAX_FEE_TIERS`
+        fee_tiers: Vec<FeeTier>,
+        /// Hold listing fees in the escrow account, forfeited on sale and refunded on cancellation
+        refundable_listing_fee: bool,
+        /// How long a listing can stay up before its deposit is forfeited on cancellation too, zero for never
+        deposit_forfeit_seconds: u32,
+        /// Only list and sell mints and collections on the allow list
+        allow_list_only: bool,
+    },
 }
 
 impl EscrowInstruction {
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
+    ///
+    /// The whole buffer has to be consumed, trailing bytes are rejected.
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
-
-        Ok(match tag {
-            0 => Self::InitEscrow {
-                amount: Self::unpack_amount(rest)?,
-            },
-            1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
-            },
-            _ => return Err(InvalidInstruction.into()),
-        })
+        let instruction = Self::try_from_slice(input).map_err(|_| InvalidInstruction)?;
+        if let Self::InitCompressedEscrow { creators, .. } = &instruction {
+            if creators.len() > MAX_CREATOR_LIMIT {
+                return Err(InvalidInstruction.into());
+            }
+        }
+        Ok(instruction)
     }
 
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        let amount = input
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-            .ok_or(InvalidInstruction)?;
-        Ok(amount)
+    /// Packs an [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
     }
 }
@@ -3,6 +3,18 @@ pub mod instruction;
 pub mod processor;
 pub mod state;
 pub mod metadata;
+pub mod programmable;
+pub mod token;
+pub mod compression;
+pub mod events;
+pub mod fees;
+
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod indexer;
+#[cfg(feature = "cli")]
+pub mod cli;
 
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
@@ -1,11 +1,8 @@
 use {
-    std::str::FromStr,
-
     solana_program::{
         account_info::AccountInfo,
         pubkey::Pubkey,
         program_error::ProgramError,
-        borsh::try_from_slice_unchecked
     },
 
     borsh::{BorshDeserialize, BorshSerialize},
@@ -13,26 +10,6 @@ use {
 
 use crate::error::EscrowError;
 
-pub fn try_from_slice_checked<T: BorshDeserialize>(
-    data: &[u8],
-    data_type: Key,
-    data_size: usize,
-) -> Result<T, ProgramError> {
-    if data.len() == 0 {
-        return Err(EscrowError::MissingMetadata.into());
-    }
-    if (data[0] != data_type as u8 && data[0] != Key::Uninitialized as u8)
-        || data.len() != data_size
-    {
-        return Err(EscrowError::InvalidMetadata.into());
-    }
-
-    let result: T = try_from_slice_unchecked(data)?;
-
-    Ok(result)
-}
-
-
 #[repr(C)]
 #[derive(Clone, BorshSerialize, BorshDeserialize, Debug)]
 pub struct Metadata {
@@ -44,6 +21,23 @@ pub struct Metadata {
     pub primary_sale_happened: bool,
     // Whether or not the data struct is mutable, default is not
     pub is_mutable: bool,
+
+    // The fields below were added in later versions of the token metadata program.
+    // Accounts created before that (or never resized) simply don't have them,
+    // in which case they are parsed as `None`.
+
+    /// nonce for easy calculation of editions, if present
+    pub edition_nonce: Option<u8>,
+    /// Since we cannot easily change Metadata, we add the new DataV2 fields here at the end.
+    pub token_standard: Option<TokenStandard>,
+    /// Collection
+    pub collection: Option<Collection>,
+    /// Uses
+    pub uses: Option<Uses>,
+    /// Collection Details
+    pub collection_details: Option<CollectionDetails>,
+    /// Programmable Config
+    pub programmable_config: Option<ProgrammableConfig>,
 }
 
 impl Metadata {
@@ -51,10 +45,108 @@ impl Metadata {
         Metadata::from_u8(&a.data.borrow_mut())
     }
 
+    /// Like `from_account_info`, but tells a mint without metadata apart from broken metadata:
+    /// an empty account yields `Ok(None)`, an account that is not owned by the
+    /// Metaplex program yields `InvalidMetadataOwner` and one that doesn't parse `InvalidMetadata`.
+    pub fn from_account_info_if_present(a: &AccountInfo) -> Result<Option<Metadata>, ProgramError> {
+        if a.data_is_empty() {
+            return Ok(None);
+        }
+        if *a.owner != metaplex_program_id() {
+            return Err(EscrowError::InvalidMetadataOwner.into());
+        }
+        let md = Metadata::from_account_info(a).map_err(|_| EscrowError::InvalidMetadata)?;
+        Ok(Some(md))
+    }
+
+    /// Whether the token can only be moved through the token metadata program.
+    pub fn is_programmable(&self) -> bool {
+        matches!(
+            self.token_standard,
+            Some(TokenStandard::ProgrammableNonFungible) | Some(TokenStandard::ProgrammableNonFungibleEdition)
+        )
+    }
+
+    /// The first of the token's creators to have verified it, whose creator policy applies.
+    pub fn first_verified_creator(&self) -> Option<&Pubkey> {
+        self.data.creators.as_ref()?.iter().find(|c| c.verified).map(|c| &c.address)
+    }
+
+    /// Whether `creator` is a verified creator of the token.
+    pub fn is_verified_creator(&self, creator: &Pubkey) -> bool {
+        matches!(&self.data.creators, Some(creators) if creators.iter().any(|c| c.verified && c.address == *creator))
+    }
+
+    /// The collection of the token, if verified: unverified ones can be claimed by anybody.
+    pub fn verified_collection(&self) -> Option<&Pubkey> {
+        self.collection.as_ref().filter(|c| c.verified).map(|c| &c.key)
+    }
+
+    /// Parses metadata of any layout version.
+    ///
+    /// The v1 prefix (up to `is_mutable`) is mandatory, the optional fields that follow
+    /// are read as long as there is data left. Like the token metadata program itself,
+    /// we stop at the first optional field that doesn't parse, since older accounts
+    /// are zero-padded (or contain leftovers) after the fields they know about.
     pub fn from_u8(u: &[u8]) -> Result<Metadata, ProgramError> {
-        let md: Metadata = try_from_slice_checked(&u, Key::MetadataV1, MAX_METADATA_LEN)?;
+        if u.is_empty() {
+            return Err(EscrowError::MissingMetadata.into());
+        }
+        if u[0] != Key::MetadataV1 as u8 && u[0] != Key::Uninitialized as u8 {
+            return Err(EscrowError::InvalidMetadata.into());
+        }
+
+        let buf = &mut &u[..];
+        let invalid = |_| EscrowError::InvalidMetadata;
+        let key = Key::deserialize(buf).map_err(invalid)?;
+        let update_authority = Pubkey::deserialize(buf).map_err(invalid)?;
+        let mint = Pubkey::deserialize(buf).map_err(invalid)?;
+        let data = Data::deserialize(buf).map_err(invalid)?;
+        let primary_sale_happened = bool::deserialize(buf).map_err(invalid)?;
+        let is_mutable = bool::deserialize(buf).map_err(invalid)?;
+
+        let mut md = Metadata {
+            key,
+            update_authority,
+            mint,
+            data,
+            primary_sale_happened,
+            is_mutable,
+            edition_nonce: None,
+            token_standard: None,
+            collection: None,
+            uses: None,
+            collection_details: None,
+            programmable_config: None,
+        };
+
+        // Stop at the first optional field that doesn't parse, keeping what we have so far.
+        let _ = md.deserialize_optional_fields(buf);
+
         Ok(md)
     }
+
+    fn deserialize_optional_fields(&mut self, buf: &mut &[u8]) -> Result<(), ()> {
+        self.edition_nonce = deserialize_optional(buf)?;
+        self.token_standard = deserialize_optional(buf)?;
+        self.collection = deserialize_optional(buf)?;
+        self.uses = deserialize_optional(buf)?;
+        self.collection_details = deserialize_optional(buf)?;
+        self.programmable_config = deserialize_optional(buf)?;
+        Ok(())
+    }
+}
+
+/// Reads an `Option<T>`, treating the end of the data as `None`.
+/// On a parse error, the cursor is left untouched.
+fn deserialize_optional<T: BorshDeserialize>(buf: &mut &[u8]) -> Result<Option<T>, ()> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let mut attempt = *buf;
+    let value = Option::<T>::deserialize(&mut attempt).map_err(|_| ())?;
+    *buf = attempt;
+    Ok(value)
 }
 
 pub const MAX_CREATOR_LIMIT: usize = 5;
@@ -90,6 +182,12 @@ pub enum Key {
     ReservationListV2,
     MasterEditionV2,
     EditionMarker,
+    UseAuthorityRecord,
+    CollectionAuthorityRecord,
+    TokenOwnedEscrow,
+    TokenRecord,
+    MetadataDelegate,
+    EditionMarkerV2,
 }
 
 #[repr(C)]
@@ -116,11 +214,69 @@ pub struct Data {
     pub creators: Option<Vec<Creator>>,
 }
 
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum TokenStandard {
+    NonFungible,
+    FungibleAsset,
+    Fungible,
+    NonFungibleEdition,
+    ProgrammableNonFungible,
+    ProgrammableNonFungibleEdition,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Collection {
+    pub verified: bool,
+    pub key: Pubkey,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub enum UseMethod {
+    Burn,
+    Multiple,
+    Single,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Uses {
+    pub use_method: UseMethod,
+    pub remaining: u64,
+    pub total: u64,
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub enum CollectionDetails {
+    V1 { size: u64 },
+    V2 { padding: [u8; 8] },
+}
+
+#[repr(C)]
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub enum ProgrammableConfig {
+    V1 {
+        /// Programmable authorization rules.
+        rule_set: Option<Pubkey>,
+    },
+}
+
 pub const PREFIX: &str = "metadata";
 pub const METAPLEX: &str = "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s";
+// Decoded, to spare the base58 decoding on every metadata account check.
+const METAPLEX_ID: Pubkey = Pubkey::new_from_array([
+    11, 112, 101, 177, 227, 209, 124, 69, 56, 157, 82, 127, 107, 4, 195, 205, 88, 184, 108, 115, 26, 160, 253, 181, 73, 182, 209, 188, 3, 248, 41, 70,
+]);
+
+pub fn metaplex_program_id() -> Pubkey {
+    METAPLEX_ID
+}
 
 pub fn get_metadata_account(mint: &Pubkey) -> Pubkey {
-    let program_key = Pubkey::from_str(METAPLEX).unwrap();
+    let program_key = metaplex_program_id();
     let metadata_seeds = &[
         PREFIX.as_bytes(),
         &program_key.as_ref(),
@@ -4,21 +4,21 @@ use solana_program::{
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
     system_instruction,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
 };
 
-use spl_token::state::Account as TokenAccount;
-use spl_token::state::Mint as TokenMint;
+use crate::{error::EscrowError, events::EscrowEvent, fees, fees::{SaleQuote, SaleTerms}, instruction::EscrowInstruction, state::AccessEntry, state::AccessMode, state::AccountKey, state::Escrow, state::CompressedEscrow, state::CreatorPolicy, state::FeeTier, state::Marketplace, state::ACCESS_PREFIX, state::MARKETPLACE_PREFIX, state::POLICY_PREFIX, state::Treasury, state::TREASURY_PREFIX, state::get_treasury_account, metadata::Creator, metadata::Key as MetadataKey, metadata::Metadata, metadata::get_metadata_account, metadata::metaplex_program_id, programmable, programmable::ProgrammableAccounts, token, compression};
 
-use crate::{error::EscrowError, instruction::EscrowInstruction, state::Escrow, metadata::Metadata, metadata::get_metadata_account};
-
-const SALES_TAX_RECIPIENT_INTERNAL: &str = "8Ba7LXjBTWScPKMV4Lmz5dsenz53NVAwJsKYXyf7TzFZ";
-const ESCROW_PDA_SEED: &[u8] = b"escrow";
-const SALES_TAX: u64 = 250;
-const LISTING_FEE: u64 = 10000000; // 0.01 SOL
+pub const SALES_TAX_RECIPIENT_INTERNAL: &str = "8Ba7LXjBTWScPKMV4Lmz5dsenz53NVAwJsKYXyf7TzFZ";
+/// `SALES_TAX_RECIPIENT_INTERNAL` decoded, as decoding base58 on-chain is expensive.
+pub const SALES_TAX_RECIPIENT: Pubkey = Pubkey::new_from_array([
+    106, 183, 13, 166, 228, 168, 27, 14, 198, 231, 181, 231, 200, 157, 229, 254, 83, 83, 247, 66, 191, 176, 191, 80, 104, 243, 17, 231, 143, 177, 248, 128,
+]);
+pub const ESCROW_PDA_SEED: &[u8] = b"escrow";
 
 pub struct Processor;
 impl Processor {
@@ -36,9 +36,323 @@ impl Processor {
             }
             EscrowInstruction::Exchange { amount } => {
                 msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, amount, program_id)
+                Self::process_exchange(accounts, amount, None, program_id)
+            }
+            EscrowInstruction::InitMarketplace { require_metadata } => {
+                msg!("Instruction: InitMarketplace");
+                Self::process_init_marketplace(accounts, require_metadata, program_id)
+            }
+            EscrowInstruction::UpdateMarketplace { require_metadata } => {
+                msg!("Instruction: UpdateMarketplace");
+                Self::process_update_marketplace(
+                    accounts,
+                    |marketplace| {
+                        marketplace.require_metadata = require_metadata;
+                        Ok(())
+                    },
+                    program_id,
+                )
+            }
+            EscrowInstruction::InitCompressedEscrow {
+                amount,
+                root,
+                metadata_args_hash,
+                seller_fee_basis_points,
+                nonce,
+                index,
+                creators,
+            } => {
+                msg!("Instruction: InitCompressedEscrow");
+                let leaf = compression::Leaf {
+                    root,
+                    data_hash: compression::hash_metadata_data(&metadata_args_hash, seller_fee_basis_points),
+                    creator_hash: compression::hash_creators(&creators),
+                    nonce,
+                    index,
+                };
+                Self::process_init_compressed_escrow(accounts, amount, leaf, seller_fee_basis_points, creators, program_id)
+            }
+            EscrowInstruction::ExchangeCompressed { root } => {
+                msg!("Instruction: ExchangeCompressed");
+                Self::process_exchange_compressed(accounts, root, program_id)
+            }
+            EscrowInstruction::MigrateEscrow => {
+                msg!("Instruction: MigrateEscrow");
+                Self::process_migrate_escrow(accounts, program_id)
+            }
+            EscrowInstruction::UpdatePrice { amount } => {
+                msg!("Instruction: UpdatePrice");
+                Self::process_update_price(accounts, amount, program_id)
+            }
+            EscrowInstruction::InitTreasury { authority, period_seconds } => {
+                msg!("Instruction: InitTreasury");
+                Self::process_init_treasury(accounts, authority, period_seconds, program_id)
+            }
+            EscrowInstruction::WithdrawFees { amount } => {
+                msg!("Instruction: WithdrawFees");
+                Self::process_withdraw_fees(accounts, amount, program_id)
+            }
+            EscrowInstruction::SetPaused { paused } => {
+                msg!("Instruction: SetPaused");
+                Self::process_set_paused(accounts, paused, program_id)
+            }
+            EscrowInstruction::SetAccess { target, mode } => {
+                msg!("Instruction: SetAccess");
+                Self::process_set_access(accounts, target, mode, program_id)
+            }
+            EscrowInstruction::ForceDelist => {
+                msg!("Instruction: ForceDelist");
+                Self::process_force_delist(accounts, program_id)
+            }
+            EscrowInstruction::SetCreatorPolicy {
+                target,
+                min_price,
+                royalty_floor_basis_points,
+                payment_mints,
+            } => {
+                msg!("Instruction: SetCreatorPolicy");
+                let policy = CreatorPolicy {
+                    key: AccountKey::CreatorPolicy,
+                    target,
+                    min_price,
+                    royalty_floor_basis_points,
+                    payment_mints,
+                    ..CreatorPolicy::default()
+                };
+                Self::process_set_creator_policy(accounts, policy, program_id)
+            }
+            EscrowInstruction::ForceDelistCompressed { root } => {
+                msg!("Instruction: ForceDelistCompressed");
+                Self::process_force_delist_compressed(accounts, root, program_id)
+            }
+            EscrowInstruction::ExchangeV2 { amount, expected_price } => {
+                msg!("Instruction: ExchangeV2");
+                Self::process_exchange(accounts, amount, Some(expected_price), program_id)
             }
+            EscrowInstruction::UpdateMarketplaceV2 {
+                require_metadata,
+                referral_basis_points,
+                fee_mode,
+                fee_tiers,
+                refundable_listing_fee,
+                deposit_forfeit_seconds,
+                allow_list_only,
+            } => {
+                msg!("Instruction: UpdateMarketplaceV2");
+                Self::process_update_marketplace(
+                    accounts,
+                    |marketplace| {
+                        fees::check_referral_basis_points(referral_basis_points)?;
+                        fees::check_fee_tiers(&fee_tiers)?;
+                        marketplace.require_metadata = require_metadata;
+                        marketplace.referral_basis_points = referral_basis_points;
+                        marketplace.fee_mode = fee_mode;
+                        marketplace.fee_tiers = fee_tiers;
+                        marketplace.refundable_listing_fee = refundable_listing_fee;
+                        marketplace.deposit_forfeit_seconds = deposit_forfeit_seconds;
+                        marketplace.allow_list_only = allow_list_only;
+                        Ok(())
+                    },
+                    program_id,
+                )
+            }
+        }
+    }
+
+    /// Loads the marketplace configuration, falling back to the defaults
+    /// if the marketplace account hasn't been created yet.
+    fn load_marketplace(
+        marketplace_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Marketplace, ProgramError> {
+        // The marketplace is only ever created at its PDA, so once it is there's no address to derive.
+        if marketplace_account.owner == program_id {
+            return Marketplace::unpack_versioned(&marketplace_account.data.borrow())
+                .ok()
+                .filter(|marketplace| marketplace.key == AccountKey::Marketplace)
+                .ok_or_else(|| {
+                    msg!("Invalid marketplace account: {:?}", marketplace_account.key);
+                    EscrowError::InvalidMarketplaceAccount.into()
+                });
+        }
+        let (marketplace_pda, _nonce) = Pubkey::find_program_address(&[MARKETPLACE_PREFIX.as_bytes()], program_id);
+        if *marketplace_account.key != marketplace_pda {
+            msg!("Invalid marketplace account: {:?}", marketplace_account.key);
+            return Err(EscrowError::InvalidMarketplaceAccount.into());
+        }
+        if !marketplace_account.data_is_empty() {
+            msg!("Marketplace account not owned by this program");
+            return Err(EscrowError::InvalidMarketplaceAccount.into());
+        }
+        Ok(Marketplace::default())
+    }
+
+    /// Writes the marketplace configuration back. The runtime can't resize accounts, so
+    /// marketplaces created with an older, shorter layout are read but can't be updated.
+    fn save_marketplace(marketplace: Marketplace, marketplace_account: &AccountInfo) -> ProgramResult {
+        if marketplace_account.data_len() < Marketplace::LEN {
+            msg!("Marketplace account {:?} has an older layout and can't be updated", marketplace_account.key);
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        Marketplace::pack(marketplace, &mut marketplace_account.data.borrow_mut())
+    }
+
+        /// Checks `metadata_account` is the metadata account of `mint`. Metaplex only keeps a mint's
+    /// metadata at the address derived from it, so metadata naming the mint is the mint's; the
+    /// derivation is only needed for an empty account, which must not hide the mint's royalties.
+    fn check_metadata_account(metadata_account: &AccountInfo, mint: &Pubkey) -> ProgramResult {
+        if *metadata_account.owner == metaplex_program_id() {
+            let data = metadata_account.data.borrow();
+            if data.len() >= 65 && data[0] == MetadataKey::MetadataV1 as u8 && data[33..65] == mint.as_ref()[..] {
+                return Ok(());
+            }
+        }
+        let mda_derived = get_metadata_account(mint);
+        if mda_derived != *metadata_account.key {
+            msg!("Mint-derived metadata account {:?} doesn't match passed metadata account {:?}", mda_derived, metadata_account.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    fn check_not_paused(marketplace: &Marketplace) -> ProgramResult {
+        if marketplace.paused {
+            msg!("The marketplace is paused, only cancellations go through");
+            return Err(EscrowError::MarketplacePaused.into());
+        }
+        Ok(())
+    }
+
+    // The list `target` is on, as recorded in its access list entry `access_account`.
+    fn load_access_mode(
+        access_account: &AccountInfo,
+        target: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<AccessMode, ProgramError> {
+        let (access_pda, _nonce) = Pubkey::find_program_address(&[ACCESS_PREFIX.as_bytes(), target.as_ref()], program_id);
+        if *access_account.key != access_pda {
+            msg!("Invalid access list account for {:?}: {:?}", target, access_account.key);
+            return Err(EscrowError::InvalidAccessAccount.into());
+        }
+        if access_account.data_is_empty() {
+            return Ok(AccessMode::Unlisted);
+        }
+        if access_account.owner != program_id {
+            msg!("Access list account not owned by this program");
+            return Err(EscrowError::InvalidAccessAccount.into());
+        }
+        Ok(AccessEntry::unpack_unchecked(&access_account.data.borrow())?.mode)
+    }
+
+    /// Checks `mint` and its verified `collection`, if any, against the allow and deny lists of `marketplace`.
+    fn check_access(
+        marketplace: &Marketplace,
+        mint: &Pubkey,
+        collection: Option<&Pubkey>,
+        mint_access_account: &AccountInfo,
+        collection_access_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let mut modes = vec![Self::load_access_mode(mint_access_account, mint, program_id)?];
+        if let Some(collection) = collection {
+            modes.push(Self::load_access_mode(collection_access_account, collection, program_id)?);
+        }
+        if modes.contains(&AccessMode::Denied) {
+            msg!("Mint {:?} or its collection {:?} is denied", mint, collection);
+            return Err(EscrowError::AccessDenied.into());
+        }
+        if marketplace.allow_list_only && !modes.contains(&AccessMode::Allowed) {
+            msg!("Neither mint {:?} nor its collection {:?} is allowed", mint, collection);
+            return Err(EscrowError::NotAllowListed.into());
+        }
+        Ok(())
+    }
+
+    // The creator policy set for `target` in `policy_account`, if any. Without a target, the account is ignored.
+    fn load_creator_policy(
+        policy_account: &AccountInfo,
+        target: Option<&Pubkey>,
+        program_id: &Pubkey,
+    ) -> Result<Option<CreatorPolicy>, ProgramError> {
+        let target = match target {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let (policy_pda, _nonce) = Pubkey::find_program_address(&[POLICY_PREFIX.as_bytes(), target.as_ref()], program_id);
+        if *policy_account.key != policy_pda {
+            msg!("Invalid creator policy account for {:?}: {:?}", target, policy_account.key);
+            return Err(EscrowError::InvalidCreatorPolicy.into());
+        }
+        if policy_account.data_is_empty() {
+            return Ok(None);
+        }
+        if policy_account.owner != program_id {
+            msg!("Creator policy account not owned by this program");
+            return Err(EscrowError::InvalidCreatorPolicy.into());
+        }
+        let policy = CreatorPolicy::unpack_unchecked(&policy_account.data.borrow())?;
+        Ok(Some(policy).filter(|policy| policy.is_initialized()))
+    }
+
+    /// Loads the creator policies of the token with `metadata`: those of its verified collection
+    /// and of its first verified creator.
+    fn load_creator_policies(
+        metadata: Option<&Metadata>,
+        collection_policy_account: &AccountInfo,
+        creator_policy_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Vec<CreatorPolicy>, ProgramError> {
+        let collection = metadata.and_then(|md| md.verified_collection());
+        let creator = metadata.and_then(|md| md.first_verified_creator());
+        Ok(Self::load_creator_policy(collection_policy_account, collection, program_id)?
+            .into_iter()
+            .chain(Self::load_creator_policy(creator_policy_account, creator, program_id)?)
+            .collect())
+    }
+
+    /// Loads the creator policy of the first verified creator of a compressed NFT. Its collection
+    /// isn't known on chain, so there's no collection policy to load.
+    fn load_compressed_creator_policies(
+        creators: &[Creator],
+        creator_policy_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Vec<CreatorPolicy>, ProgramError> {
+        let creator = creators.iter().find(|c| c.verified).map(|c| &c.address);
+        Ok(Self::load_creator_policy(creator_policy_account, creator, program_id)?.into_iter().collect())
+    }
+
+    /// Finds the fee tier of `marketplace` that `holder` qualifies for by holding
+    /// the token in `token_account`. Tiers by collection need `metadata_account`,
+    /// the metadata of the held token's mint.
+    fn prove_fee_tier<'m>(
+        marketplace: &'m Marketplace,
+        holder: &AccountInfo,
+        token_account: &AccountInfo,
+        metadata_account: Option<&AccountInfo>,
+    ) -> Result<&'m FeeTier, ProgramError> {
+        if !token::is_token_program(token_account.owner) {
+            msg!("Token account {:?} not owned by the token program", token_account.key);
+            return Err(EscrowError::InvalidTokenAccountOwner.into());
+        }
+        let token_account_info = token::unpack_account(token_account)?;
+        if token_account_info.owner != *holder.key || token_account_info.amount == 0 {
+            msg!("Token account {:?} holds no token of {:?}", token_account.key, holder.key);
+            return Err(EscrowError::InvalidDiscountProof.into());
         }
+        let collection = match metadata_account {
+            Some(metadata_account) => {
+                Self::check_metadata_account(metadata_account, &token_account_info.mint)?;
+                Metadata::from_account_info_if_present(metadata_account)?
+                    .and_then(|md| md.verified_collection().copied())
+            }
+            None => None,
+        };
+        let fee_tier = fees::find_fee_tier(marketplace, &token_account_info.mint, collection.as_ref()).ok_or_else(|| {
+            msg!("No fee tier for mint {:?} or collection {:?}", token_account_info.mint, collection);
+            EscrowError::InvalidDiscountProof
+        })?;
+        msg!("Fee tier of {:?}: sales tax {:?} bps", fee_tier.token, fee_tier.sales_tax_basis_points);
+        Ok(fee_tier)
     }
 
     fn process_init_escrow(
@@ -56,13 +370,24 @@ impl Processor {
         let system_program = next_account_info(account_info_iter)?;
         let rent_account = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let metadata_account = next_account_info(account_info_iter)?;
 
         if !initializer.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let mint = TokenMint::unpack(&mint_account.data.borrow())?;
-        let spl_token_account = TokenAccount::unpack(&temp_token_account.data.borrow())?;
+        let mint = token::unpack_mint(mint_account)?;
+        let spl_token_account = token::unpack_account(temp_token_account)?;
+        if !token::is_token_program(mint_account.owner) {
+            msg!("Mint {:?} not owned by the token program", mint_account.key);
+            return Err(EscrowError::InvalidMintOwner.into());
+        }
+        if temp_token_account.owner != mint_account.owner {
+            msg!("Token account {:?} not owned by the token program", temp_token_account.key);
+            return Err(EscrowError::InvalidTokenAccountOwner.into());
+        }
+        token::check_token_program(token_program, temp_token_account)?;
+        token::check_mint_extensions(mint_account)?;
         if *mint_account.key != spl_token_account.mint {
             msg!("mint account mismatch: {:?} / {:?}", *mint_account.key, spl_token_account.mint);
             return Err(EscrowError::InvalidMintAccount.into());
@@ -73,6 +398,16 @@ impl Processor {
             return Err(EscrowError::InvalidTokenAmount.into());
         }
 
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+
+        Self::check_metadata_account(metadata_account, mint_account.key)?;
+        let metadata = Metadata::from_account_info_if_present(metadata_account)?;
+        let is_programmable = matches!(&metadata, Some(md) if md.is_programmable());
+        let collection = metadata.as_ref().and_then(|md| md.verified_collection().copied());
+
         let rent = &Rent::from_account_info(rent_account)?;
 
         if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
@@ -84,34 +419,161 @@ impl Processor {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
-        let str_pk: &[u8] = &bs58::decode(SALES_TAX_RECIPIENT_INTERNAL).into_vec().expect("BUG decoding sales tax recipient!")[..];
-        msg!("sales tax recipients: passed:{:?} / expected:{:?}", sales_tax_recipient.key, SALES_TAX_RECIPIENT_INTERNAL);
-        if *sales_tax_recipient.key != Pubkey::new(str_pk) {
-            msg!("Invalid sales tax recipient: {:?}", sales_tax_recipient);
-            return Err(EscrowError::InvalidSalesTaxRecipient.into());
-        }
+        // Programmable NFTs are delegated to the PDA, which takes these accounts.
+        let delegate_accounts = if is_programmable {
+            Some([
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+            ])
+        } else {
+            None
+        };
+        let seller_token_account = if is_programmable {
+            None
+        } else {
+            Self::next_optional_account(account_info_iter, program_id)
+        };
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let discount_token_account = Self::next_optional_account(account_info_iter, program_id);
+        let discount_metadata_account = Self::next_optional_account(account_info_iter, program_id);
+        let mint_access_account = next_account_info(account_info_iter)?;
+        let collection_access_account = next_account_info(account_info_iter)?;
+        let collection_policy_account = next_account_info(account_info_iter)?;
+        let creator_policy_account = next_account_info(account_info_iter)?;
 
-        if LISTING_FEE > 0 {
-            let xfer_listing_fee = system_instruction::transfer(&initializer.key, &sales_tax_recipient.key, LISTING_FEE);
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        Self::check_not_paused(&marketplace)?;
+        Self::check_access(
+            &marketplace,
+            mint_account.key,
+            collection.as_ref(),
+            mint_access_account,
+            collection_access_account,
+            program_id,
+        )?;
+        let policies = Self::load_creator_policies(metadata.as_ref(), collection_policy_account, creator_policy_account, program_id)?;
+        fees::apply_creator_policies(&policies, amount)?;
+        Self::check_sales_tax_recipient(sales_tax_recipient, &marketplace, program_id)?;
+        let fee_tier = match discount_token_account {
+            Some(discount_token_account) => {
+                Some(Self::prove_fee_tier(&marketplace, initializer, discount_token_account, discount_metadata_account)?)
+            }
+            None => None,
+        };
+        let listing_fee = fees::listing_fee(fee_tier);
+        let listing_deposit = if marketplace.refundable_listing_fee { listing_fee } else { 0 };
+        if listing_deposit > 0 {
+            msg!("Holding the listing fee as a deposit: {:?}", listing_deposit);
+            let xfer_listing_deposit = system_instruction::transfer(initializer.key, escrow_account.key, listing_deposit);
+            invoke(&xfer_listing_deposit, &[initializer.clone(), escrow_account.clone(), system_program.clone()])?;
+        } else if listing_fee > 0 {
+            let xfer_listing_fee = system_instruction::transfer(initializer.key, sales_tax_recipient.key, listing_fee);
             invoke(&xfer_listing_fee, &[initializer.clone(), sales_tax_recipient.clone(), system_program.clone()])?;
+            Self::record_fees(sales_tax_recipient, program_id, 0, listing_fee)?;
         }
 
-        escrow_info.is_initialized = true;
+        // Programmable NFTs are listed from the seller's token account itself.
+        let seller_token_account_pubkey = if is_programmable {
+            *temp_token_account.key
+        } else {
+            match seller_token_account {
+                Some(seller_token_account) => {
+                    if seller_token_account.owner != temp_token_account.owner {
+                        msg!("Token account {:?} not owned by the token program", seller_token_account.key);
+                        return Err(EscrowError::InvalidTokenAccountOwner.into());
+                    }
+                    let seller_token_account_info = token::unpack_account(seller_token_account)?;
+                    if seller_token_account_info.mint != *mint_account.key || seller_token_account_info.owner != *initializer.key {
+                        msg!("Seller token account {:?} doesn't hold the mint for the initializer", seller_token_account.key);
+                        return Err(ProgramError::InvalidAccountData);
+                    }
+                    *seller_token_account.key
+                }
+                None => Pubkey::default(),
+            }
+        };
+
+        let clock = Clock::get()?;
+        escrow_info.key = AccountKey::EscrowV1;
         escrow_info.initializer_pubkey = *initializer.key;
         escrow_info.mint_pubkey = *mint_account.key;
         escrow_info.temp_token_account_pubkey = *temp_token_account.key;
         escrow_info.expected_amount = amount;
+        escrow_info.created_slot = clock.slot;
+        escrow_info.created_at = clock.unix_timestamp;
+        escrow_info.updated_at = clock.unix_timestamp;
+        escrow_info.seller_token_account_pubkey = seller_token_account_pubkey;
+        escrow_info.sequence = 0;
+        escrow_info.listing_deposit = listing_deposit;
 
         Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+        EscrowEvent::ListingCreated {
+            escrow: *escrow_account.key,
+            seller: *initializer.key,
+            mint: *mint_account.key,
+            price: amount,
+        }
+        .emit();
         let (pda, _nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
 
-        let owner_change_ix = spl_token::instruction::set_authority(
+        if let Some(delegate_accounts) = delegate_accounts {
+            // The token stays in the seller's account, locked, with the PDA as sale delegate.
+            let [master_edition, token_record, token_metadata_program, sysvar_instructions, authorization_rules_program, authorization_rules, pda_account] =
+                delegate_accounts;
+
+            if *token_metadata_program.key != metaplex_program_id() {
+                msg!("Invalid token metadata program: {:?}", token_metadata_program.key);
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            let delegate_ix = programmable::delegate_sale(
+                &pda,
+                metadata_account.key,
+                master_edition.key,
+                token_record.key,
+                mint_account.key,
+                temp_token_account.key,
+                initializer.key,
+                initializer.key,
+                authorization_rules_program.key,
+                authorization_rules.key,
+                spl_token_account.amount,
+            );
+
+            msg!("Calling the token metadata program to delegate the token to the PDA...");
+            invoke(
+                &delegate_ix,
+                &[
+                    token_metadata_program.clone(),
+                    pda_account.clone(),
+                    metadata_account.clone(),
+                    master_edition.clone(),
+                    token_record.clone(),
+                    mint_account.clone(),
+                    temp_token_account.clone(),
+                    initializer.clone(),
+                    system_program.clone(),
+                    sysvar_instructions.clone(),
+                    token_program.clone(),
+                    authorization_rules_program.clone(),
+                    authorization_rules.clone(),
+                ],
+            )?;
+
+            return Ok(());
+        }
+
+        let owner_change_ix = token::set_authority(
             token_program.key,
             temp_token_account.key,
             Some(&pda),
             spl_token::instruction::AuthorityType::AccountOwner,
             initializer.key,
-            &[&initializer.key],
         )?;
 
         msg!("Calling the token program to transfer token account ownership...");
@@ -130,6 +592,7 @@ impl Processor {
     fn process_exchange(
         accounts: &[AccountInfo],
         amount_expected_by_taker: u64,
+        price_expected_by_taker: Option<u64>,
         program_id: &Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -147,26 +610,39 @@ impl Processor {
         let token_program = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
         let pda_account = next_account_info(account_info_iter)?;
-
-        let creator_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect(); // rest 
-
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let referrer = Self::next_optional_account(account_info_iter, program_id);
+        let discount_token_account = Self::next_optional_account(account_info_iter, program_id);
+        let discount_metadata_account = Self::next_optional_account(account_info_iter, program_id);
+        let mint_access_account = next_account_info(account_info_iter)?;
+        let collection_access_account = next_account_info(account_info_iter)?;
+        let collection_policy_account = next_account_info(account_info_iter)?;
+        let creator_policy_account = next_account_info(account_info_iter)?;
 
         if !taker.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
+        if let Some(referrer) = referrer {
+            if referrer.key == taker.key {
+                msg!("The taker can't be their own referrer");
+                return Err(EscrowError::InvalidReferrer.into());
+            }
+        }
 
 
-        let pdas_temp_token_account_info =
-            TokenAccount::unpack(&pdas_temp_token_account.data.borrow())?;
-        let (pda, nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
-
-        if amount_expected_by_taker != pdas_temp_token_account_info.amount {
-            return Err(EscrowError::ExpectedAmountMismatch.into());
+        let pdas_temp_token_account_info = token::unpack_account(pdas_temp_token_account)?;
+        if !token::is_token_program(pdas_temp_token_account.owner) {
+            msg!("Token account {:?} not owned by the token program", pdas_temp_token_account.key);
+            return Err(EscrowError::InvalidTokenAccountOwner.into());
         }
-        msg!("amount: {:?}", amount_expected_by_taker);
+        let (pda, nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
 
 
-        let escrow_info = Escrow::unpack(&escrow_account.data.borrow())?;
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        let escrow_info = Escrow::unpack_versioned(&escrow_account.data.borrow())?;
 
         if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
             msg!("Deposit account not owned by this program's PDA");
@@ -178,107 +654,307 @@ impl Processor {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        let str_pk: &[u8] = &bs58::decode(SALES_TAX_RECIPIENT_INTERNAL).into_vec().expect("BUG decoding sales tax recipient!")[..];
-        if *sales_tax_recipient.key != Pubkey::new(str_pk) {
-            msg!("Invalid sales tax recipient: {:?}", sales_tax_recipient);
-            return Err(EscrowError::InvalidSalesTaxRecipient.into());
-        }
-
         if escrow_info.mint_pubkey != *mint.key {
             msg!("Mint in escrow {:?} doesn't match passed mint {:?}", escrow_info.mint_pubkey, mint.key);
             return Err(ProgramError::InvalidAccountData);
         }
+        if mint.owner != pdas_temp_token_account.owner {
+            msg!("Mint {:?} not owned by the token program", mint.key);
+            return Err(EscrowError::InvalidMintOwner.into());
+        }
+        token::check_token_program(token_program, mint)?;
+        let mint_info = token::unpack_mint(mint)?;
 
-        let mda_derived = get_metadata_account(&mint.key);
-        if mda_derived != *metadata_account.key {
-            msg!("Mint-derived metadata account {:?} doesn't match passed metadata account {:?}", mda_derived, metadata_account.key);
-            return Err(ProgramError::InvalidAccountData);
+        // Token-2022 mints may withhold part of every transfer as a fee.
+        let transfer_fee = if *mint.owner == token::token_2022_program_id() {
+            let clock = Clock::get()?;
+            token::get_transfer_fee(mint, clock.epoch)
+                .map_or(0, |fee| fee.calculate_fee(pdas_temp_token_account_info.amount))
+        } else {
+            0
+        };
+        if amount_expected_by_taker != pdas_temp_token_account_info.amount - transfer_fee {
+            msg!("taker expects {:?}, will receive {:?} (transfer fee {:?})", amount_expected_by_taker, pdas_temp_token_account_info.amount - transfer_fee, transfer_fee);
+            return Err(EscrowError::ExpectedAmountMismatch.into());
         }
+        msg!("amount: {:?}", amount_expected_by_taker);
+
+        Self::check_metadata_account(metadata_account, mint.key)?;
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        Self::check_sales_tax_recipient(sales_tax_recipient, &marketplace, program_id)?;
+
+        // Token-2022 mints can carry their metadata themselves, which takes precedence.
+        let metadata = match token::get_token_metadata(mint)? {
+            Some(md) => Some(md),
+            None => Metadata::from_account_info_if_present(metadata_account)?,
+        };
+        let programmable_accounts = match &metadata {
+            Some(md) if md.is_programmable() => Some(ProgrammableAccounts::next(account_info_iter)?),
+            _ => None,
+        };
+
+        let creator_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect(); // rest
 
         if *taker.key != escrow_info.initializer_pubkey {
             // Not a cancellation, so we need to process payment, sales tax and royalties.
-            msg!("Transfering sales tax");
-            let am = escrow_info.expected_amount;
-            let tax_amount: u64 = (am * SALES_TAX)/10000;
-            let xfer_sales_tax = system_instruction::transfer(&taker.key, &sales_tax_recipient.key, tax_amount);
-            invoke(&xfer_sales_tax, &[taker.clone(), sales_tax_recipient.clone(), system_program.clone()])?;
-
-            let mut royalty_total: u64 = 0;
-            let res = Metadata::from_u8(&metadata_account.data.borrow());
-            if res.is_ok() {
-                let md = res.unwrap();
-                royalty_total = (md.data.seller_fee_basis_points as u64 * am)/10000;
-                if md.data.seller_fee_basis_points as u64 + SALES_TAX > 10000 {
-                    return Err(EscrowError::InvalidRoyaltyFee.into());
+            // A price update landing first mustn't charge the taker more than they agreed to.
+            match price_expected_by_taker {
+                Some(price) if price != escrow_info.expected_amount => {
+                    msg!("taker expects a price of {:?}, listed at {:?}", price, escrow_info.expected_amount);
+                    return Err(EscrowError::PriceChanged.into());
                 }
-
-                // Note: we are disregarding the primary_sale_happened flag,
-                // because a lot of collections/minters are not using it properly.
-                msg!("Disbursing royalties...");
-
-                match md.data.creators {
-                    Some(creators) => {
-                        if creators.len() != creator_accounts.len() {
-                            msg!("number of creators in metadata {:?} doesn't match number of creators passed {:?}", creators.len(), creator_accounts.len());
-                                return Err(EscrowError::CreatorMismatch.into());
-                        }
-                        let mut i = 0;
-                        for creator in creators {
-                            if creator.address != *creator_accounts[i].key {
-                                msg!("creator {:?} in metadata {:?} doesn't match creator passed {:?}", i, creator.address, creator_accounts[i]);
-                                return Err(EscrowError::CreatorMismatch.into());
-                            }
-                            let amount = (creator.share as u64 * royalty_total)/100;
-                            let xfer = system_instruction::transfer(&taker.key, &creator.address, amount);
-                            invoke(&xfer, &[taker.clone(), creator_accounts[i].clone(), system_program.clone()])?;
-                            i += 1;
-                        }
-                    },
-                    None => msg!("no creators => no payouts"),
+                // Exchange doesn't say what the taker saw, so it only buys at the listing price.
+                None if escrow_info.sequence != 0 => {
+                    msg!("price changed {:?} times since listing, use ExchangeV2", escrow_info.sequence);
+                    return Err(EscrowError::PriceChanged.into());
                 }
-            } else {
-                if let Err(e) = res {
-                    // TODO discern between missing and invalid
-                    msg!("no metadata found or metadata invalid, skipping royalties: {:?}", e);
+                _ => {}
+            }
+            // Listings made before these extensions were refused can still be cancelled.
+            token::check_mint_extensions(mint)?;
+            Self::check_not_paused(&marketplace)?;
+            Self::check_access(
+                &marketplace,
+                mint.key,
+                metadata.as_ref().and_then(|md| md.verified_collection()),
+                mint_access_account,
+                collection_access_account,
+                program_id,
+            )?;
+            if metadata.is_none() && marketplace.require_metadata {
+                msg!("no metadata found for mint {:?}, but the marketplace requires it", mint.key);
+                return Err(EscrowError::MissingMetadata.into());
+            }
+            if metadata.is_none() {
+                msg!("no metadata found, skipping royalties");
+            }
+            let fee_tier = match discount_token_account {
+                Some(discount_token_account) => {
+                    Some(Self::prove_fee_tier(&marketplace, taker, discount_token_account, discount_metadata_account)?)
+                }
+                None => None,
+            };
+            let policies =
+                Self::load_creator_policies(metadata.as_ref(), collection_policy_account, creator_policy_account, program_id)?;
+            let mut terms = SaleTerms::new(&marketplace, referrer.is_some(), fee_tier);
+            terms.royalty_floor_basis_points = fees::apply_creator_policies(&policies, escrow_info.expected_amount)?;
+            let quote = fees::quote_escrow(&escrow_info, metadata.as_ref(), &terms)?;
+            Self::pay_for_sale(
+                taker,
+                initializers_main_account,
+                sales_tax_recipient,
+                referrer,
+                system_program,
+                &quote,
+                &creator_accounts,
+            )?;
+            Self::record_fees(sales_tax_recipient, program_id, quote.house_fee(), 0)?;
+            Self::forfeit_listing_deposit(escrow_account, sales_tax_recipient, escrow_info.listing_deposit, program_id)?;
+            EscrowEvent::Sale {
+                escrow: *escrow_account.key,
+                seller: escrow_info.initializer_pubkey,
+                buyer: *taker.key,
+                mint: *mint.key,
+                price: quote.price,
+                sales_tax: quote.sales_tax,
+                royalties: quote.royalties,
+                seller_proceeds: quote.seller_proceeds,
+                fee_mode: quote.fee_mode,
+            }
+            .emit();
+            if let Some(referrer) = referrer {
+                EscrowEvent::ReferralPaid {
+                    escrow: *escrow_account.key,
+                    referrer: *referrer.key,
+                    amount: quote.referral_fee,
                 }
+                .emit();
+            }
+        } else {
+            let listed_for = Clock::get()?.unix_timestamp.saturating_sub(escrow_info.created_at);
+            if marketplace.deposit_forfeit_seconds > 0 && listed_for >= marketplace.deposit_forfeit_seconds as i64 {
+                msg!("Listed for {:?} seconds, the listing deposit is forfeited", listed_for);
+                Self::forfeit_listing_deposit(escrow_account, sales_tax_recipient, escrow_info.listing_deposit, program_id)?;
             }
+            EscrowEvent::ListingCancelled {
+                escrow: *escrow_account.key,
+                seller: escrow_info.initializer_pubkey,
+                mint: *mint.key,
+            }
+            .emit();
+        }
 
-            msg!("Transfering payment to initializer.");
-            let final_amount_for_seller: u64 = am - tax_amount - royalty_total;
-            if final_amount_for_seller <= 0 {
-                msg!("Final amount {:?} is non-positive / tax={:?} / royalties={:?}", final_amount_for_seller, tax_amount, royalty_total);
-                return Err(EscrowError::InvalidFinalAmount.into());
+        if let Some(programmable_accounts) = programmable_accounts {
+            let ProgrammableAccounts {
+                master_edition,
+                owner_token_record,
+                destination_token_record,
+                token_metadata_program,
+                sysvar_instructions,
+                spl_ata_program,
+                authorization_rules_program,
+                authorization_rules,
+            } = programmable_accounts;
+
+            if *taker.key == escrow_info.initializer_pubkey {
+                let revoke_ix = programmable::revoke_sale(
+                    &pda,
+                    metadata_account.key,
+                    master_edition.key,
+                    owner_token_record.key,
+                    mint.key,
+                    pdas_temp_token_account.key,
+                    taker.key,
+                    taker.key,
+                    authorization_rules_program.key,
+                    authorization_rules.key,
+                );
+                msg!("Calling the token metadata program to revoke the PDA's delegation...");
+                invoke(
+                    &revoke_ix,
+                    &[
+                        token_metadata_program.clone(),
+                        pda_account.clone(),
+                        metadata_account.clone(),
+                        master_edition.clone(),
+                        owner_token_record.clone(),
+                        mint.clone(),
+                        pdas_temp_token_account.clone(),
+                        taker.clone(),
+                        system_program.clone(),
+                        sysvar_instructions.clone(),
+                        token_program.clone(),
+                        authorization_rules_program.clone(),
+                        authorization_rules.clone(),
+                    ],
+                )?;
+            } else {
+                let transfer_ix = programmable::transfer(
+                    pdas_temp_token_account.key,
+                    initializers_main_account.key,
+                    takers_token_to_receive_account.key,
+                    taker.key,
+                    mint.key,
+                    metadata_account.key,
+                    master_edition.key,
+                    owner_token_record.key,
+                    destination_token_record.key,
+                    &pda,
+                    taker.key,
+                    spl_ata_program.key,
+                    authorization_rules_program.key,
+                    authorization_rules.key,
+                    pdas_temp_token_account_info.amount,
+                );
+                msg!("Calling the token metadata program to transfer the token to the taker...");
+                invoke_signed(
+                    &transfer_ix,
+                    &[
+                        token_metadata_program.clone(),
+                        pdas_temp_token_account.clone(),
+                        initializers_main_account.clone(),
+                        takers_token_to_receive_account.clone(),
+                        taker.clone(),
+                        mint.clone(),
+                        metadata_account.clone(),
+                        master_edition.clone(),
+                        owner_token_record.clone(),
+                        destination_token_record.clone(),
+                        pda_account.clone(),
+                        system_program.clone(),
+                        sysvar_instructions.clone(),
+                        token_program.clone(),
+                        spl_ata_program.clone(),
+                        authorization_rules_program.clone(),
+                        authorization_rules.clone(),
+                    ],
+                    &[&[ESCROW_PDA_SEED, &[nonce]]],
+                )?;
             }
-            let xfer_lamports = system_instruction::transfer(&taker.key, &escrow_info.initializer_pubkey, final_amount_for_seller as u64);
-            invoke(&xfer_lamports, &[taker.clone(), initializers_main_account.clone(), system_program.clone()])?;
+
+            return Self::close_escrow_account(escrow_account, initializers_main_account);
+        }
+
+        if *taker.key == escrow_info.initializer_pubkey
+            && escrow_info.seller_token_account_pubkey != Pubkey::default()
+            && escrow_info.seller_token_account_pubkey != *takers_token_to_receive_account.key
+        {
+            msg!("Cancelled listings return the token to {:?}", escrow_info.seller_token_account_pubkey);
+            return Err(ProgramError::InvalidAccountData);
         }
 
         msg!("Calling the token program to transfer tokens to the taker...");
-        let transfer_to_taker_ix = spl_token::instruction::transfer(
+        Self::release_token(
+            token_program,
+            pdas_temp_token_account,
+            mint,
+            takers_token_to_receive_account,
+            initializers_main_account,
+            pda_account,
+            pdas_temp_token_account_info.amount,
+            mint_info.decimals,
+            nonce,
+        )?;
+
+        Self::close_escrow_account(escrow_account, initializers_main_account)
+    }
+
+    /// Transfers the token in the PDA's temp token account to `destination`
+    /// and closes the temp account, its rent going to the seller.
+    #[allow(clippy::too_many_arguments)]
+    fn release_token<'a>(
+        token_program: &AccountInfo<'a>,
+        pdas_temp_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        initializers_main_account: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        amount: u64,
+        decimals: u8,
+        nonce: u8,
+    ) -> ProgramResult {
+        let pda = pda_account.key;
+        let transfer_ix = token::transfer_checked(
             token_program.key,
             pdas_temp_token_account.key,
-            takers_token_to_receive_account.key,
-            &pda,
-            &[&pda],
-            pdas_temp_token_account_info.amount,
+            mint.key,
+            destination.key,
+            pda,
+            amount,
+            decimals,
         )?;
         invoke_signed(
-            &transfer_to_taker_ix,
+            &transfer_ix,
             &[
                 pdas_temp_token_account.clone(),
-                takers_token_to_receive_account.clone(),
+                mint.clone(),
+                destination.clone(),
                 pda_account.clone(),
                 token_program.clone(),
             ],
             &[&[&ESCROW_PDA_SEED[..], &[nonce]]],
         )?;
 
-        let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+        if token::get_withheld_amount(pdas_temp_token_account) > 0 {
+            // Token-2022 refuses to close accounts still holding withheld transfer fees.
+            let harvest_ix = token::harvest_withheld_tokens_to_mint(mint.key, pdas_temp_token_account.key);
+            msg!("Calling the token program to harvest withheld fees from pda's temp account...");
+            invoke(
+                &harvest_ix,
+                &[
+                    mint.clone(),
+                    pdas_temp_token_account.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+
+        let close_pdas_temp_acc_ix = token::close_account(
             token_program.key,
             pdas_temp_token_account.key,
             initializers_main_account.key,
-            &pda,
-            &[&pda],
+            pda,
         )?;
 
         msg!("Calling the token program to close pda's temp account...");
@@ -291,8 +967,98 @@ impl Processor {
                 token_program.clone(),
             ],
             &[&[&ESCROW_PDA_SEED[..], &[nonce]]],
-        )?;
+        )
+    }
+
+    /// Pays out `quote` from the taker's account: the sales tax, split with the referrer
+    /// if any, the royalties to the creators passed in `creator_accounts` and the rest to the seller.
+    ///
+    /// Creator accounts are matched by key, in any order. Creators owed nothing, like those
+    /// with a zero share, may be left out; accounts passed twice or of no creator are ignored.
+    #[allow(clippy::too_many_arguments)]
+    fn pay_for_sale<'a>(
+        taker: &AccountInfo<'a>,
+        initializers_main_account: &AccountInfo<'a>,
+        sales_tax_recipient: &AccountInfo<'a>,
+        referrer: Option<&AccountInfo<'a>>,
+        system_program: &AccountInfo<'a>,
+        quote: &SaleQuote,
+        creator_accounts: &[AccountInfo<'a>],
+    ) -> ProgramResult {
+        let creator_account = |creator: &Pubkey| creator_accounts.iter().find(|account| account.key == creator);
+        let missing_creators: Vec<&Pubkey> = quote
+            .royalties
+            .iter()
+            .filter(|royalty| royalty.amount > 0 && creator_account(&royalty.creator).is_none())
+            .map(|royalty| &royalty.creator)
+            .collect();
+        if !missing_creators.is_empty() {
+            msg!("creators in metadata missing from the accounts passed: {:?}", missing_creators);
+            return Err(EscrowError::CreatorMismatch.into());
+        }
+
+        msg!("Transfering sales tax");
+        let house_fee = quote.house_fee();
+        let xfer_sales_tax = system_instruction::transfer(&taker.key, &sales_tax_recipient.key, house_fee);
+        invoke(&xfer_sales_tax, &[taker.clone(), sales_tax_recipient.clone(), system_program.clone()])?;
+        if let Some(referrer) = referrer {
+            msg!("Sales tax split: {:?} to the house, {:?} to referrer {:?}", house_fee, quote.referral_fee, referrer.key);
+            let xfer_referral_fee = system_instruction::transfer(taker.key, referrer.key, quote.referral_fee);
+            invoke(&xfer_referral_fee, &[taker.clone(), referrer.clone(), system_program.clone()])?;
+        }
+
+        msg!("Disbursing royalties...");
+        if quote.royalties.is_empty() {
+            msg!("no creators => no payouts");
+        }
+        for royalty in quote.royalties.iter().filter(|royalty| royalty.amount > 0) {
+            let creator_account = creator_account(&royalty.creator).ok_or(EscrowError::CreatorMismatch)?;
+            let xfer = system_instruction::transfer(&taker.key, &royalty.creator, royalty.amount);
+            invoke(&xfer, &[taker.clone(), creator_account.clone(), system_program.clone()])?;
+        }
+
+        msg!("Transfering payment to initializer.");
+        let xfer_lamports = system_instruction::transfer(&taker.key, &initializers_main_account.key, quote.seller_proceeds);
+        invoke(&xfer_lamports, &[taker.clone(), initializers_main_account.clone(), system_program.clone()])?;
+        Ok(())
+    }
+
+    // The next account, unless it is the program id, which stands in for an optional account that isn't passed.
+    // Also `None` if there are no accounts left.
+    fn next_optional_account<'a, 'b, I: Iterator<Item = &'a AccountInfo<'b>>>(
+        iter: &mut I,
+        program_id: &Pubkey,
+    ) -> Option<&'a AccountInfo<'b>> {
+        iter.next().filter(|account| account.key != program_id)
+    }
+
+    // Moves the listing deposit held by the escrow account to the sales tax recipient.
+    // What is left goes back to the seller when the account is closed.
+    fn forfeit_listing_deposit(
+        escrow_account: &AccountInfo,
+        sales_tax_recipient: &AccountInfo,
+        listing_deposit: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if listing_deposit == 0 {
+            return Ok(());
+        }
+        msg!("Transfering the listing deposit: {:?}", listing_deposit);
+        **escrow_account.lamports.borrow_mut() = escrow_account
+            .lamports()
+            .checked_sub(listing_deposit)
+            .ok_or(EscrowError::AmountOverflow)?;
+        **sales_tax_recipient.lamports.borrow_mut() = sales_tax_recipient
+            .lamports()
+            .checked_add(listing_deposit)
+            .ok_or(EscrowError::AmountOverflow)?;
+        Self::record_fees(sales_tax_recipient, program_id, 0, listing_deposit)
+    }
 
+    fn close_escrow_account(
+        escrow_account: &AccountInfo,
+        initializers_main_account: &AccountInfo,
+    ) -> ProgramResult {
         msg!("Closing the escrow account...");
         **initializers_main_account.lamports.borrow_mut() = initializers_main_account
             .lamports()
@@ -302,4 +1068,1107 @@ impl Processor {
 
         Ok(())
     }
+
+    fn process_init_marketplace(
+        accounts: &[AccountInfo],
+        require_metadata: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *authority.key != SALES_TAX_RECIPIENT {
+            msg!("Only the sales tax recipient can create the marketplace: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        let (marketplace_pda, nonce) = Pubkey::find_program_address(&[MARKETPLACE_PREFIX.as_bytes()], program_id);
+        if *marketplace_account.key != marketplace_pda {
+            msg!("Invalid marketplace account: {:?}", marketplace_account.key);
+            return Err(EscrowError::InvalidMarketplaceAccount.into());
+        }
+        if !marketplace_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        let create_marketplace_ix = system_instruction::create_account(
+            authority.key,
+            marketplace_account.key,
+            rent.minimum_balance(Marketplace::LEN),
+            Marketplace::LEN as u64,
+            program_id,
+        );
+        msg!("Creating the marketplace account...");
+        invoke_signed(
+            &create_marketplace_ix,
+            &[
+                authority.clone(),
+                marketplace_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[MARKETPLACE_PREFIX.as_bytes(), &[nonce]]],
+        )?;
+
+        let marketplace = Marketplace {
+            key: AccountKey::Marketplace,
+            authority_pubkey: *authority.key,
+            require_metadata,
+            ..Marketplace::default()
+        };
+        Self::save_marketplace(marketplace, marketplace_account)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    // `update` changes the settings of the instruction's version, the others are kept.
+    fn process_update_marketplace(
+        accounts: &[AccountInfo],
+        update: impl FnOnce(&mut Marketplace) -> ProgramResult,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        update(&mut marketplace)?;
+        Self::save_marketplace(marketplace, marketplace_account)?;
+
+        Ok(())
+    }
+
+    /// Checks the account fees are paid to: the sales tax recipient, or the treasury,
+    /// which is required once `marketplace` has one.
+    fn check_sales_tax_recipient(
+        sales_tax_recipient: &AccountInfo,
+        marketplace: &Marketplace,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if *sales_tax_recipient.key == SALES_TAX_RECIPIENT {
+            if marketplace.has_treasury {
+                msg!("Fees go to the marketplace treasury {:?}", get_treasury_account(program_id).0);
+                return Err(EscrowError::InvalidSalesTaxRecipient.into());
+            }
+            return Ok(());
+        }
+
+        // The treasury is only ever created at its PDA, so a treasury of ours needs no
+        // address derivation, which costs a hash per bump tried.
+        let is_treasury = sales_tax_recipient.owner == program_id
+            && matches!(
+                Treasury::unpack_unchecked(&sales_tax_recipient.data.borrow()),
+                Ok(treasury) if treasury.key == AccountKey::Treasury
+            );
+        if is_treasury {
+            return Ok(());
+        }
+        if *sales_tax_recipient.key == get_treasury_account(program_id).0 {
+            msg!("Treasury account {:?} not initialized", sales_tax_recipient.key);
+            return Err(EscrowError::InvalidTreasuryAccount.into());
+        }
+        msg!("Invalid sales tax recipient: {:?}", sales_tax_recipient);
+        Err(EscrowError::InvalidSalesTaxRecipient.into())
+    }
+
+    /// Accounts for fees just paid to `sales_tax_recipient`, if it is the treasury.
+    fn record_fees(
+        sales_tax_recipient: &AccountInfo,
+        program_id: &Pubkey,
+        sales_tax: u64,
+        listing_fees: u64,
+    ) -> ProgramResult {
+        // Checked by `check_sales_tax_recipient`: the only account of ours fees go to is the treasury.
+        if sales_tax_recipient.owner != program_id {
+            return Ok(());
+        }
+        let mut treasury = Treasury::unpack(&sales_tax_recipient.data.borrow())?;
+        treasury.roll_period(Clock::get()?.unix_timestamp);
+        let period = &mut treasury.current_period;
+        period.sales_tax = period.sales_tax.checked_add(sales_tax).ok_or(EscrowError::AmountOverflow)?;
+        period.listing_fees = period.listing_fees.checked_add(listing_fees).ok_or(EscrowError::AmountOverflow)?;
+        treasury.total_collected = treasury
+            .total_collected
+            .checked_add(sales_tax + listing_fees)
+            .ok_or(EscrowError::AmountOverflow)?;
+        Treasury::pack(treasury, &mut sales_tax_recipient.data.borrow_mut())
+    }
+
+    fn check_bubblegum_program(bubblegum_program: &AccountInfo) -> ProgramResult {
+        if *bubblegum_program.key != compression::bubblegum_program_id() {
+            msg!("Invalid Bubblegum program: {:?}", bubblegum_program.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(())
+    }
+
+    fn process_init_compressed_escrow(
+        accounts: &[AccountInfo],
+        amount: u64,
+        leaf: compression::Leaf,
+        seller_fee_basis_points: u16,
+        creators: Vec<Creator>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let sales_tax_recipient = next_account_info(account_info_iter)?;
+        let leaf_delegate = next_account_info(account_info_iter)?;
+        let tree_authority = next_account_info(account_info_iter)?;
+        let merkle_tree = next_account_info(account_info_iter)?;
+        let log_wrapper = next_account_info(account_info_iter)?;
+        let compression_program = next_account_info(account_info_iter)?;
+        let bubblegum_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let asset_access_account = next_account_info(account_info_iter)?;
+        let creator_policy_account = next_account_info(account_info_iter)?;
+        let discount_token_account = Self::next_optional_account(account_info_iter, program_id);
+        let discount_metadata_account = Self::next_optional_account(account_info_iter, program_id);
+        let proof_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect(); // rest
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        let mut escrow_info = CompressedEscrow::unpack_unchecked(&escrow_account.data.borrow())?;
+        if escrow_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        Self::check_not_paused(&marketplace)?;
+        // The leaf's collection isn't known on chain, only the asset's own list entry applies.
+        let asset_id = compression::get_asset_id(merkle_tree.key, leaf.nonce);
+        Self::check_access(&marketplace, &asset_id, None, asset_access_account, asset_access_account, program_id)?;
+        let policies = Self::load_compressed_creator_policies(&creators, creator_policy_account, program_id)?;
+        fees::apply_creator_policies(&policies, amount)?;
+        Self::check_sales_tax_recipient(sales_tax_recipient, &marketplace, program_id)?;
+        Self::check_bubblegum_program(bubblegum_program)?;
+        fees::check_seller_fee_basis_points(seller_fee_basis_points)?;
+
+        let (pda, _nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
+        if *pda_account.key != pda {
+            msg!("Invalid PDA account: {:?}", pda_account.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let fee_tier = match discount_token_account {
+            Some(discount_token_account) => {
+                Some(Self::prove_fee_tier(&marketplace, initializer, discount_token_account, discount_metadata_account)?)
+            }
+            None => None,
+        };
+        let listing_fee = fees::listing_fee(fee_tier);
+        let listing_deposit = if marketplace.refundable_listing_fee { listing_fee } else { 0 };
+        if listing_deposit > 0 {
+            msg!("Holding the listing fee as a deposit: {:?}", listing_deposit);
+            let xfer_listing_deposit = system_instruction::transfer(initializer.key, escrow_account.key, listing_deposit);
+            invoke(&xfer_listing_deposit, &[initializer.clone(), escrow_account.clone(), system_program.clone()])?;
+        } else if listing_fee > 0 {
+            let xfer_listing_fee = system_instruction::transfer(initializer.key, sales_tax_recipient.key, listing_fee);
+            invoke(&xfer_listing_fee, &[initializer.clone(), sales_tax_recipient.clone(), system_program.clone()])?;
+            Self::record_fees(sales_tax_recipient, program_id, 0, listing_fee)?;
+        }
+
+        // Bubblegum checks the leaf against the tree, so the hashes we store are proven once this succeeds.
+        let proof: Vec<Pubkey> = proof_accounts.iter().map(|a| *a.key).collect();
+        let delegate_ix = compression::delegate(
+            tree_authority.key,
+            merkle_tree.key,
+            initializer.key,
+            leaf_delegate.key,
+            &pda,
+            &leaf,
+            &proof,
+        );
+        let mut delegate_accounts = vec![
+            bubblegum_program.clone(),
+            tree_authority.clone(),
+            initializer.clone(),
+            leaf_delegate.clone(),
+            pda_account.clone(),
+            merkle_tree.clone(),
+            log_wrapper.clone(),
+            compression_program.clone(),
+            system_program.clone(),
+        ];
+        delegate_accounts.extend(proof_accounts);
+        msg!("Calling the Bubblegum program to delegate the leaf to the PDA...");
+        invoke(&delegate_ix, &delegate_accounts)?;
+
+        escrow_info.key = AccountKey::CompressedEscrow;
+        escrow_info.initializer_pubkey = *initializer.key;
+        escrow_info.merkle_tree_pubkey = *merkle_tree.key;
+        escrow_info.expected_amount = amount;
+        escrow_info.nonce = leaf.nonce;
+        escrow_info.index = leaf.index;
+        escrow_info.data_hash = leaf.data_hash;
+        escrow_info.creator_hash = leaf.creator_hash;
+        escrow_info.seller_fee_basis_points = seller_fee_basis_points;
+        escrow_info.creators = creators;
+        escrow_info.created_at = Clock::get()?.unix_timestamp;
+        escrow_info.listing_deposit = listing_deposit;
+
+        CompressedEscrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+        EscrowEvent::ListingCreated {
+            escrow: *escrow_account.key,
+            seller: *initializer.key,
+            mint: asset_id,
+            price: amount,
+        }
+        .emit();
+
+        Ok(())
+    }
+
+    fn process_exchange_compressed(
+        accounts: &[AccountInfo],
+        root: [u8; 32],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let taker = next_account_info(account_info_iter)?;
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let sales_tax_recipient = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let tree_authority = next_account_info(account_info_iter)?;
+        let merkle_tree = next_account_info(account_info_iter)?;
+        let log_wrapper = next_account_info(account_info_iter)?;
+        let compression_program = next_account_info(account_info_iter)?;
+        let bubblegum_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let asset_access_account = next_account_info(account_info_iter)?;
+        let creator_policy_account = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        let escrow_info = CompressedEscrow::unpack_versioned(&escrow_account.data.borrow())?;
+
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            msg!("Escrow data account not owned by the initializer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.merkle_tree_pubkey != *merkle_tree.key {
+            msg!("Merkle tree in escrow {:?} doesn't match passed merkle tree {:?}", escrow_info.merkle_tree_pubkey, merkle_tree.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Self::check_bubblegum_program(bubblegum_program)?;
+        // There's no metadata account to require, only the fee settings apply.
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        Self::check_sales_tax_recipient(sales_tax_recipient, &marketplace, program_id)?;
+
+        let (pda, nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
+        if *pda_account.key != pda {
+            msg!("Invalid PDA account: {:?}", pda_account.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let is_cancel = *taker.key == escrow_info.initializer_pubkey;
+        let rest: Vec<AccountInfo> = account_info_iter.cloned().collect();
+        // The creator accounts passed come first, matched by key like for `Exchange`,
+        // and the proof follows. Creators owed nothing may be left out.
+        let creators_len = if is_cancel {
+            0
+        } else {
+            rest.iter()
+                .take_while(|account| escrow_info.creators.iter().any(|c| c.address == *account.key))
+                .count()
+        };
+        let (creator_accounts, proof_accounts) = rest.split_at(creators_len);
+        let proof: Vec<Pubkey> = proof_accounts.iter().map(|a| *a.key).collect();
+
+        let leaf = compression::Leaf {
+            root,
+            data_hash: escrow_info.data_hash,
+            creator_hash: escrow_info.creator_hash,
+            nonce: escrow_info.nonce,
+            index: escrow_info.index,
+        };
+
+        let asset_id = compression::get_asset_id(merkle_tree.key, escrow_info.nonce);
+        if is_cancel {
+            let delegate_ix = compression::delegate(
+                tree_authority.key,
+                merkle_tree.key,
+                taker.key,
+                &pda,
+                taker.key,
+                &leaf,
+                &proof,
+            );
+            let mut delegate_accounts = vec![
+                bubblegum_program.clone(),
+                tree_authority.clone(),
+                taker.clone(),
+                pda_account.clone(),
+                merkle_tree.clone(),
+                log_wrapper.clone(),
+                compression_program.clone(),
+                system_program.clone(),
+            ];
+            delegate_accounts.extend_from_slice(proof_accounts);
+            msg!("Calling the Bubblegum program to take back the leaf's delegation...");
+            invoke(&delegate_ix, &delegate_accounts)?;
+            let listed_for = Clock::get()?.unix_timestamp.saturating_sub(escrow_info.created_at);
+            if marketplace.deposit_forfeit_seconds > 0 && listed_for >= marketplace.deposit_forfeit_seconds as i64 {
+                msg!("Listed for {:?} seconds, the listing deposit is forfeited", listed_for);
+                Self::forfeit_listing_deposit(escrow_account, sales_tax_recipient, escrow_info.listing_deposit, program_id)?;
+            }
+            EscrowEvent::ListingCancelled {
+                escrow: *escrow_account.key,
+                seller: escrow_info.initializer_pubkey,
+                mint: asset_id,
+            }
+            .emit();
+        } else {
+            Self::check_not_paused(&marketplace)?;
+            Self::check_access(&marketplace, &asset_id, None, asset_access_account, asset_access_account, program_id)?;
+            let policies = Self::load_compressed_creator_policies(&escrow_info.creators, creator_policy_account, program_id)?;
+            let mut terms = SaleTerms::new(&marketplace, false, None);
+            terms.royalty_floor_basis_points = fees::apply_creator_policies(&policies, escrow_info.expected_amount)?;
+            let quote = fees::quote_compressed_escrow(&escrow_info, &terms)?;
+            Self::pay_for_sale(
+                taker,
+                initializers_main_account,
+                sales_tax_recipient,
+                None,
+                system_program,
+                &quote,
+                creator_accounts,
+            )?;
+            Self::record_fees(sales_tax_recipient, program_id, quote.house_fee(), 0)?;
+            Self::forfeit_listing_deposit(escrow_account, sales_tax_recipient, escrow_info.listing_deposit, program_id)?;
+            EscrowEvent::Sale {
+                escrow: *escrow_account.key,
+                seller: escrow_info.initializer_pubkey,
+                buyer: *taker.key,
+                mint: asset_id,
+                price: quote.price,
+                sales_tax: quote.sales_tax,
+                royalties: quote.royalties,
+                seller_proceeds: quote.seller_proceeds,
+                fee_mode: quote.fee_mode,
+            }
+            .emit();
+
+            let transfer_ix = compression::transfer(
+                tree_authority.key,
+                merkle_tree.key,
+                initializers_main_account.key,
+                &pda,
+                taker.key,
+                &leaf,
+                &proof,
+            );
+            let mut transfer_accounts = vec![
+                bubblegum_program.clone(),
+                tree_authority.clone(),
+                initializers_main_account.clone(),
+                pda_account.clone(),
+                taker.clone(),
+                merkle_tree.clone(),
+                log_wrapper.clone(),
+                compression_program.clone(),
+                system_program.clone(),
+            ];
+            transfer_accounts.extend_from_slice(proof_accounts);
+            msg!("Calling the Bubblegum program to transfer the leaf to the taker...");
+            invoke_signed(&transfer_ix, &transfer_accounts, &[&[ESCROW_PDA_SEED, &[nonce]]])?;
+        }
+
+        Self::close_escrow_account(escrow_account, initializers_main_account)
+    }
+
+    fn process_migrate_escrow(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_account_info(account_info_iter)?;
+        let legacy_escrow_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if legacy_escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", legacy_escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        if !Escrow::is_legacy(&legacy_escrow_account.data.borrow()) {
+            msg!("Escrow account {:?} is not in the legacy layout", legacy_escrow_account.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let escrow_info = Escrow::unpack_versioned(&legacy_escrow_account.data.borrow())?;
+        if escrow_info.initializer_pubkey != *initializer.key {
+            msg!("Escrow data account not owned by the initializer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        let rent = &Rent::from_account_info(rent_account)?;
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+        if Escrow::unpack_unchecked(&escrow_account.data.borrow())?.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+        EscrowEvent::ListingMigrated {
+            legacy_escrow: *legacy_escrow_account.key,
+            escrow: *escrow_account.key,
+        }
+        .emit();
+
+        // Zero the legacy data as well, so it can't be read as a listing again within this transaction.
+        legacy_escrow_account.data.borrow_mut().fill(0);
+        Self::close_escrow_account(legacy_escrow_account, initializer)
+    }
+
+    fn process_update_price(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let metadata_account = next_account_info(account_info_iter)?;
+        let collection_policy_account = next_account_info(account_info_iter)?;
+        let creator_policy_account = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        Self::check_not_paused(&marketplace)?;
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        if Escrow::is_legacy(&escrow_account.data.borrow()) {
+            msg!("Legacy escrows have to be migrated before updating them");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut escrow_info = Escrow::unpack(&escrow_account.data.borrow())?;
+        if escrow_info.initializer_pubkey != *initializer.key {
+            msg!("Escrow data account not owned by the initializer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // The new price has to meet the creators' policies, as the listing price did.
+        Self::check_metadata_account(metadata_account, &escrow_info.mint_pubkey)?;
+        let metadata = Metadata::from_account_info_if_present(metadata_account)?;
+        let policies = Self::load_creator_policies(metadata.as_ref(), collection_policy_account, creator_policy_account, program_id)?;
+        fees::apply_creator_policies(&policies, amount)?;
+
+        let old_price = escrow_info.expected_amount;
+        escrow_info.expected_amount = amount;
+        escrow_info.updated_at = Clock::get()?.unix_timestamp;
+        escrow_info.sequence = escrow_info.sequence.checked_add(1).ok_or(EscrowError::AmountOverflow)?;
+
+        EscrowEvent::PriceUpdated {
+            escrow: *escrow_account.key,
+            old_price,
+            new_price: amount,
+            sequence: escrow_info.sequence,
+        }
+        .emit();
+        Escrow::pack(escrow_info, &mut escrow_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_init_treasury(
+        accounts: &[AccountInfo],
+        treasury_authority: Pubkey,
+        period_seconds: u32,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        let (treasury_pda, nonce) = Pubkey::find_program_address(&[TREASURY_PREFIX.as_bytes(), marketplace_account.key.as_ref()], program_id);
+        if *treasury_account.key != treasury_pda {
+            msg!("Invalid treasury account: {:?}", treasury_account.key);
+            return Err(EscrowError::InvalidTreasuryAccount.into());
+        }
+        if !treasury_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        // Fees may have been sent to the address already, they stay in the treasury.
+        let rent = &Rent::from_account_info(rent_account)?;
+        let lamports = rent.minimum_balance(Treasury::LEN).saturating_sub(treasury_account.lamports());
+        if lamports > 0 {
+            let xfer_rent = system_instruction::transfer(authority.key, treasury_account.key, lamports);
+            invoke(&xfer_rent, &[authority.clone(), treasury_account.clone(), system_program.clone()])?;
+        }
+        let seeds: &[&[u8]] = &[TREASURY_PREFIX.as_bytes(), marketplace_account.key.as_ref(), &[nonce]];
+        msg!("Creating the treasury account...");
+        invoke_signed(
+            &system_instruction::allocate(treasury_account.key, Treasury::LEN as u64),
+            &[treasury_account.clone(), system_program.clone()],
+            &[seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(treasury_account.key, program_id),
+            &[treasury_account.clone(), system_program.clone()],
+            &[seeds],
+        )?;
+
+        let mut treasury = Treasury {
+            key: AccountKey::Treasury,
+            authority_pubkey: treasury_authority,
+            period_seconds,
+            ..Treasury::default()
+        };
+        treasury.current_period.start = Clock::get()?.unix_timestamp;
+        Treasury::pack(treasury, &mut treasury_account.data.borrow_mut())?;
+
+        marketplace.has_treasury = true;
+        Self::save_marketplace(marketplace, marketplace_account)?;
+
+        Ok(())
+    }
+
+    fn process_withdraw_fees(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let destination = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if treasury_account.owner != program_id {
+            msg!("Treasury account {:?} not owned by this program", treasury_account.key);
+            return Err(EscrowError::InvalidTreasuryAccount.into());
+        }
+        let mut treasury = Treasury::unpack(&treasury_account.data.borrow())?;
+        if treasury.authority_pubkey != *authority.key {
+            msg!("Invalid treasury authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidTreasuryAuthority.into());
+        }
+
+        let available = treasury_account
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(treasury_account.data_len()));
+        if amount > available {
+            msg!("Withdrawing {:?} lamports, the treasury holds {:?}", amount, available);
+            return Err(ProgramError::InsufficientFunds);
+        }
+
+        treasury.roll_period(Clock::get()?.unix_timestamp);
+        treasury.current_period.withdrawn = treasury.current_period.withdrawn.checked_add(amount).ok_or(EscrowError::AmountOverflow)?;
+        treasury.total_withdrawn = treasury.total_withdrawn.checked_add(amount).ok_or(EscrowError::AmountOverflow)?;
+        Treasury::pack(treasury, &mut treasury_account.data.borrow_mut())?;
+
+        **treasury_account.lamports.borrow_mut() -= amount;
+        **destination.lamports.borrow_mut() = destination
+            .lamports()
+            .checked_add(amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+        EscrowEvent::FeesWithdrawn {
+            treasury: *treasury_account.key,
+            destination: *destination.key,
+            amount,
+        }
+        .emit();
+
+        Ok(())
+    }
+
+    fn process_set_paused(
+        accounts: &[AccountInfo],
+        paused: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        msg!("Paused: {:?}", paused);
+        marketplace.paused = paused;
+        Self::save_marketplace(marketplace, marketplace_account)?;
+
+        Ok(())
+    }
+
+    fn process_set_access(
+        accounts: &[AccountInfo],
+        target: Pubkey,
+        mode: AccessMode,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let access_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        let (access_pda, nonce) = Pubkey::find_program_address(&[ACCESS_PREFIX.as_bytes(), target.as_ref()], program_id);
+        if *access_account.key != access_pda {
+            msg!("Invalid access list account for {:?}: {:?}", target, access_account.key);
+            return Err(EscrowError::InvalidAccessAccount.into());
+        }
+        if access_account.data_is_empty() {
+            let rent = &Rent::from_account_info(rent_account)?;
+            let lamports = rent.minimum_balance(AccessEntry::LEN).saturating_sub(access_account.lamports());
+            if lamports > 0 {
+                let xfer_rent = system_instruction::transfer(authority.key, access_account.key, lamports);
+                invoke(&xfer_rent, &[authority.clone(), access_account.clone(), system_program.clone()])?;
+            }
+            let seeds: &[&[u8]] = &[ACCESS_PREFIX.as_bytes(), target.as_ref(), &[nonce]];
+            msg!("Creating the access list account...");
+            invoke_signed(
+                &system_instruction::allocate(access_account.key, AccessEntry::LEN as u64),
+                &[access_account.clone(), system_program.clone()],
+                &[seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(access_account.key, program_id),
+                &[access_account.clone(), system_program.clone()],
+                &[seeds],
+            )?;
+        }
+
+        msg!("Access of {:?}: {:?}", target, mode);
+        let access_entry = AccessEntry {
+            key: AccountKey::AccessEntry,
+            target,
+            mode,
+        };
+        AccessEntry::pack(access_entry, &mut access_account.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    fn process_force_delist(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        let seller_token_account = next_account_info(account_info_iter)?;
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        if escrow_account.data.borrow().first() == Some(&(AccountKey::CompressedEscrow as u8)) {
+            msg!("Listings of compressed NFTs are taken down with ForceDelistCompressed");
+            return Err(EscrowError::DataTypeMismatch.into());
+        }
+        let escrow_info = Escrow::unpack_versioned(&escrow_account.data.borrow())?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            msg!("Deposit account not owned by this program's PDA");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            msg!("Escrow data account not owned by the initializer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.mint_pubkey != *mint.key {
+            msg!("Mint in escrow {:?} doesn't match passed mint {:?}", escrow_info.mint_pubkey, mint.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if !token::is_token_program(pdas_temp_token_account.owner) {
+            msg!("Token account {:?} not owned by the token program", pdas_temp_token_account.key);
+            return Err(EscrowError::InvalidTokenAccountOwner.into());
+        }
+        token::check_token_program(token_program, pdas_temp_token_account)?;
+        let pdas_temp_token_account_info = token::unpack_account(pdas_temp_token_account)?;
+
+        msg!("Delisting {:?} on behalf of the marketplace", escrow_account.key);
+        let (pda, nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
+        if pdas_temp_token_account_info.owner == pda {
+            if seller_token_account.owner != pdas_temp_token_account.owner {
+                msg!("Token account {:?} not owned by the token program", seller_token_account.key);
+                return Err(EscrowError::InvalidTokenAccountOwner.into());
+            }
+            let seller_token_account_info = token::unpack_account(seller_token_account)?;
+            if seller_token_account_info.mint != *mint.key || seller_token_account_info.owner != escrow_info.initializer_pubkey {
+                msg!("Seller token account {:?} doesn't hold the mint for the initializer", seller_token_account.key);
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if escrow_info.seller_token_account_pubkey != Pubkey::default()
+                && escrow_info.seller_token_account_pubkey != *seller_token_account.key
+            {
+                msg!("Delisted listings return the token to {:?}", escrow_info.seller_token_account_pubkey);
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let mint_info = token::unpack_mint(mint)?;
+            msg!("Calling the token program to return the token to the seller...");
+            Self::release_token(
+                token_program,
+                pdas_temp_token_account,
+                mint,
+                seller_token_account,
+                initializers_main_account,
+                pda_account,
+                pdas_temp_token_account_info.amount,
+                mint_info.decimals,
+                nonce,
+            )?;
+        } else {
+            // Programmable NFTs stay in the seller's account: the PDA revokes its own delegation,
+            // which unlocks the token, as a cancellation does.
+            let metadata_account = next_account_info(account_info_iter)?;
+            let master_edition = next_account_info(account_info_iter)?;
+            let token_record = next_account_info(account_info_iter)?;
+            let token_metadata_program = next_account_info(account_info_iter)?;
+            let sysvar_instructions = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            let authorization_rules_program = next_account_info(account_info_iter)?;
+            let authorization_rules = next_account_info(account_info_iter)?;
+
+            if *token_metadata_program.key != metaplex_program_id() {
+                msg!("Invalid token metadata program: {:?}", token_metadata_program.key);
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            Self::check_metadata_account(metadata_account, mint.key)?;
+            if pdas_temp_token_account_info.delegate != COption::Some(pda) {
+                msg!("Token account {:?} is neither held nor delegated by the PDA", pdas_temp_token_account.key);
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            let revoke_ix = programmable::revoke_sale(
+                &pda,
+                metadata_account.key,
+                master_edition.key,
+                token_record.key,
+                mint.key,
+                pdas_temp_token_account.key,
+                &pda,
+                authority.key,
+                authorization_rules_program.key,
+                authorization_rules.key,
+            );
+            msg!("Calling the token metadata program to revoke the PDA's delegation...");
+            invoke_signed(
+                &revoke_ix,
+                &[
+                    token_metadata_program.clone(),
+                    pda_account.clone(),
+                    metadata_account.clone(),
+                    master_edition.clone(),
+                    token_record.clone(),
+                    mint.clone(),
+                    pdas_temp_token_account.clone(),
+                    authority.clone(),
+                    system_program.clone(),
+                    sysvar_instructions.clone(),
+                    token_program.clone(),
+                    authorization_rules_program.clone(),
+                    authorization_rules.clone(),
+                ],
+                &[&[ESCROW_PDA_SEED, &[nonce]]],
+            )?;
+        }
+
+        // As far as indexers are concerned, the seller cancelled the listing.
+        EscrowEvent::ListingCancelled {
+            escrow: *escrow_account.key,
+            seller: escrow_info.initializer_pubkey,
+            mint: *mint.key,
+        }
+        .emit();
+        Self::close_escrow_account(escrow_account, initializers_main_account)
+    }
+
+    fn process_force_delist_compressed(
+        accounts: &[AccountInfo],
+        root: [u8; 32],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority = next_account_info(account_info_iter)?;
+        let marketplace_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let tree_authority = next_account_info(account_info_iter)?;
+        let merkle_tree = next_account_info(account_info_iter)?;
+        let log_wrapper = next_account_info(account_info_iter)?;
+        let compression_program = next_account_info(account_info_iter)?;
+        let bubblegum_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let proof_accounts: Vec<AccountInfo> = account_info_iter.cloned().collect(); // rest
+
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let marketplace = Self::load_marketplace(marketplace_account, program_id)?;
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        if marketplace.authority_pubkey != *authority.key {
+            msg!("Invalid marketplace authority: {:?}", authority.key);
+            return Err(EscrowError::InvalidMarketplaceAuthority.into());
+        }
+
+        if escrow_account.owner != program_id {
+            msg!("Escrow account {:?} not owned by this program", escrow_account.key);
+            return Err(EscrowError::InvalidEscrowOwner.into());
+        }
+        let escrow_info = CompressedEscrow::unpack_versioned(&escrow_account.data.borrow())?;
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            msg!("Escrow data account not owned by the initializer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow_info.merkle_tree_pubkey != *merkle_tree.key {
+            msg!("Merkle tree in escrow {:?} doesn't match passed merkle tree {:?}", escrow_info.merkle_tree_pubkey, merkle_tree.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Self::check_bubblegum_program(bubblegum_program)?;
+
+        let (pda, nonce) = Pubkey::find_program_address(&[ESCROW_PDA_SEED], program_id);
+        if *pda_account.key != pda {
+            msg!("Invalid PDA account: {:?}", pda_account.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        msg!("Delisting {:?} on behalf of the marketplace", escrow_account.key);
+        let leaf = compression::Leaf {
+            root,
+            data_hash: escrow_info.data_hash,
+            creator_hash: escrow_info.creator_hash,
+            nonce: escrow_info.nonce,
+            index: escrow_info.index,
+        };
+        // Only the owner can delegate the leaf, but a transfer resets its delegate to the new owner:
+        // the PDA transfers the leaf from the seller to the seller.
+        let proof: Vec<Pubkey> = proof_accounts.iter().map(|a| *a.key).collect();
+        let transfer_ix = compression::transfer(
+            tree_authority.key,
+            merkle_tree.key,
+            initializers_main_account.key,
+            &pda,
+            initializers_main_account.key,
+            &leaf,
+            &proof,
+        );
+        let mut transfer_accounts = vec![
+            bubblegum_program.clone(),
+            tree_authority.clone(),
+            initializers_main_account.clone(),
+            pda_account.clone(),
+            merkle_tree.clone(),
+            log_wrapper.clone(),
+            compression_program.clone(),
+            system_program.clone(),
+        ];
+        transfer_accounts.extend(proof_accounts);
+        msg!("Calling the Bubblegum program to take back the PDA's delegation...");
+        invoke_signed(&transfer_ix, &transfer_accounts, &[&[ESCROW_PDA_SEED, &[nonce]]])?;
+
+        EscrowEvent::ListingCancelled {
+            escrow: *escrow_account.key,
+            seller: escrow_info.initializer_pubkey,
+            mint: compression::get_asset_id(merkle_tree.key, escrow_info.nonce),
+        }
+        .emit();
+        Self::close_escrow_account(escrow_account, initializers_main_account)
+    }
+
+    fn process_set_creator_policy(
+        accounts: &[AccountInfo],
+        mut policy: CreatorPolicy,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let creator = next_account_info(account_info_iter)?;
+        let policy_account = next_account_info(account_info_iter)?;
+        let collection_metadata_account = Self::next_optional_account(account_info_iter, program_id);
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !creator.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Creators set their own policy, collection policies are set by a verified creator of the collection.
+        if policy.target != *creator.key {
+            let collection_metadata_account = collection_metadata_account.ok_or(ProgramError::NotEnoughAccountKeys)?;
+            let mda_derived = get_metadata_account(&policy.target);
+            if mda_derived != *collection_metadata_account.key {
+                msg!("Collection-derived metadata account {:?} doesn't match passed metadata account {:?}", mda_derived, collection_metadata_account.key);
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let collection_metadata = Metadata::from_account_info_if_present(collection_metadata_account)?;
+            if !matches!(&collection_metadata, Some(md) if md.is_verified_creator(creator.key)) {
+                msg!("{:?} is not a verified creator of collection {:?}", creator.key, policy.target);
+                return Err(EscrowError::NotVerifiedCreator.into());
+            }
+        }
+        fees::check_creator_policy(&policy)?;
+
+        let (policy_pda, nonce) = Pubkey::find_program_address(&[POLICY_PREFIX.as_bytes(), policy.target.as_ref()], program_id);
+        if *policy_account.key != policy_pda {
+            msg!("Invalid creator policy account for {:?}: {:?}", policy.target, policy_account.key);
+            return Err(EscrowError::InvalidCreatorPolicy.into());
+        }
+        if policy_account.data_is_empty() {
+            let rent = &Rent::from_account_info(rent_account)?;
+            let lamports = rent.minimum_balance(CreatorPolicy::LEN).saturating_sub(policy_account.lamports());
+            if lamports > 0 {
+                let xfer_rent = system_instruction::transfer(creator.key, policy_account.key, lamports);
+                invoke(&xfer_rent, &[creator.clone(), policy_account.clone(), system_program.clone()])?;
+            }
+            let seeds: &[&[u8]] = &[POLICY_PREFIX.as_bytes(), policy.target.as_ref(), &[nonce]];
+            msg!("Creating the creator policy account...");
+            invoke_signed(
+                &system_instruction::allocate(policy_account.key, CreatorPolicy::LEN as u64),
+                &[policy_account.clone(), system_program.clone()],
+                &[seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(policy_account.key, program_id),
+                &[policy_account.clone(), system_program.clone()],
+                &[seeds],
+            )?;
+        }
+
+        msg!("Policy of {:?}: minimum price {:?}, royalty floor {:?} bps", policy.target, policy.min_price, policy.royalty_floor_basis_points);
+        policy.creator_pubkey = *creator.key;
+        CreatorPolicy::pack(policy, &mut policy_account.data.borrow_mut())?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,193 @@
+//! Instructions of the token metadata program needed to list and sell programmable NFTs.
+//!
+//! Programmable NFTs are frozen in their owner's token account and can only be moved
+//! through the token metadata program. Instead of taking over a temp token account,
+//! a listing makes the escrow PDA the sale delegate of the seller's token account,
+//! which also locks the token until it's transferred or the delegation is revoked.
+//!
+//! Optional accounts that are not used are passed as the token metadata program id,
+//! the convention used by the token metadata program itself.
+
+use {
+    borsh::BorshSerialize,
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        instruction::{AccountMeta, Instruction},
+        msg,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+        system_program, sysvar,
+    },
+};
+
+use crate::metadata::metaplex_program_id;
+
+const DELEGATE: u8 = 44;
+const REVOKE: u8 = 45;
+const TRANSFER: u8 = 49;
+
+// Variant index of `SaleV1` in both `DelegateArgs` and `RevokeArgs`.
+const SALE_V1: u8 = 1;
+// Variant index of `V1` in `TransferArgs`.
+const TRANSFER_V1: u8 = 0;
+
+/// The extra accounts an Exchange of a programmable NFT takes, in order.
+pub struct ProgrammableAccounts<'a, 'b> {
+    pub master_edition: &'a AccountInfo<'b>,
+    pub owner_token_record: &'a AccountInfo<'b>,
+    pub destination_token_record: &'a AccountInfo<'b>,
+    pub token_metadata_program: &'a AccountInfo<'b>,
+    pub sysvar_instructions: &'a AccountInfo<'b>,
+    pub spl_ata_program: &'a AccountInfo<'b>,
+    pub authorization_rules_program: &'a AccountInfo<'b>,
+    pub authorization_rules: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> ProgrammableAccounts<'a, 'b> {
+    pub fn next<I: Iterator<Item = &'a AccountInfo<'b>>>(iter: &mut I) -> Result<Self, ProgramError> {
+        let accounts = ProgrammableAccounts {
+            master_edition: next_account_info(iter)?,
+            owner_token_record: next_account_info(iter)?,
+            destination_token_record: next_account_info(iter)?,
+            token_metadata_program: next_account_info(iter)?,
+            sysvar_instructions: next_account_info(iter)?,
+            spl_ata_program: next_account_info(iter)?,
+            authorization_rules_program: next_account_info(iter)?,
+            authorization_rules: next_account_info(iter)?,
+        };
+        if *accounts.token_metadata_program.key != metaplex_program_id() {
+            msg!("Invalid token metadata program: {:?}", accounts.token_metadata_program.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(accounts)
+    }
+}
+
+fn instruction_data(tag: u8, variant: u8, amount: Option<u64>) -> Vec<u8> {
+    let mut data = vec![tag, variant];
+    if let Some(amount) = amount {
+        data.extend_from_slice(&amount.try_to_vec().unwrap());
+    }
+    // authorization_data: None
+    data.push(0);
+    data
+}
+
+/// Makes `delegate` the sale delegate of `token`, locking it.
+#[allow(clippy::too_many_arguments)]
+pub fn delegate_sale(
+    delegate: &Pubkey,
+    metadata: &Pubkey,
+    master_edition: &Pubkey,
+    token_record: &Pubkey,
+    mint: &Pubkey,
+    token: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    authorization_rules_program: &Pubkey,
+    authorization_rules: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let program_id = metaplex_program_id();
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new_readonly(*delegate, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(*master_edition, false),
+            AccountMeta::new(*token_record, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*token, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(*authorization_rules_program, false),
+            AccountMeta::new_readonly(*authorization_rules, false),
+        ],
+        data: instruction_data(DELEGATE, SALE_V1, Some(amount)),
+    }
+}
+
+/// Revokes the sale delegation of `token`, unlocking it.
+#[allow(clippy::too_many_arguments)]
+pub fn revoke_sale(
+    delegate: &Pubkey,
+    metadata: &Pubkey,
+    master_edition: &Pubkey,
+    token_record: &Pubkey,
+    mint: &Pubkey,
+    token: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    authorization_rules_program: &Pubkey,
+    authorization_rules: &Pubkey,
+) -> Instruction {
+    let program_id = metaplex_program_id();
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new_readonly(*delegate, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(*master_edition, false),
+            AccountMeta::new(*token_record, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*token, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(*authorization_rules_program, false),
+            AccountMeta::new_readonly(*authorization_rules, false),
+        ],
+        data: instruction_data(REVOKE, SALE_V1, None),
+    }
+}
+
+/// Transfers `amount` tokens from `token` to `destination`, signed by the owner or a delegate.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer(
+    token: &Pubkey,
+    token_owner: &Pubkey,
+    destination: &Pubkey,
+    destination_owner: &Pubkey,
+    mint: &Pubkey,
+    metadata: &Pubkey,
+    edition: &Pubkey,
+    owner_token_record: &Pubkey,
+    destination_token_record: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    spl_ata_program: &Pubkey,
+    authorization_rules_program: &Pubkey,
+    authorization_rules: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    Instruction {
+        program_id: metaplex_program_id(),
+        accounts: vec![
+            AccountMeta::new(*token, false),
+            AccountMeta::new_readonly(*token_owner, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*destination_owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(*edition, false),
+            AccountMeta::new(*owner_token_record, false),
+            AccountMeta::new(*destination_token_record, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(*spl_ata_program, false),
+            AccountMeta::new_readonly(*authorization_rules_program, false),
+            AccountMeta::new_readonly(*authorization_rules, false),
+        ],
+        data: instruction_data(TRANSFER, TRANSFER_V1, Some(amount)),
+    }
+}
@@ -1,78 +1,617 @@
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
+    msg,
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 
-use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use crate::{
+    error::EscrowError,
+    metadata::{Creator, MAX_CREATOR_LEN, MAX_CREATOR_LIMIT},
+};
+
+/// The first byte of every account owned by this program, telling them apart.
+///
+/// Legacy escrows start with their `is_initialized` flag, hence `EscrowV0` = 1.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AccountKey {
+    Uninitialized,
+    EscrowV0,
+    EscrowV1,
+    Marketplace,
+    CompressedEscrow,
+    Treasury,
+    AccessEntry,
+    CreatorPolicy,
+}
+
+// Not derived, `#[default]` on enum variants is too recent for the BPF toolchain.
+#[allow(clippy::derivable_impls)]
+impl Default for AccountKey {
+    fn default() -> Self {
+        AccountKey::Uninitialized
+    }
+}
+
+/// Program state stored behind an `AccountKey`.
+pub trait Keyed {
+    const KEY: AccountKey;
+}
+
+/// Reads the account key of `data`, without looking at anything else.
+pub fn account_key(data: &[u8]) -> Result<AccountKey, ProgramError> {
+    data.get(..1)
+        .and_then(|key| AccountKey::try_from_slice(key).ok())
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Deserializes `data` as a `T`, rejecting accounts of another type before any field is read.
+///
+/// Uninitialized accounts are accepted, they deserialize to the zeroed state.
+pub fn try_from_slice_checked<T: Keyed + BorshDeserialize>(data: &[u8]) -> Result<T, ProgramError> {
+    let key = account_key(data)?;
+    if key != T::KEY && key != AccountKey::Uninitialized {
+        msg!("Account key mismatch: expected {:?}, found {:?}", T::KEY, key);
+        return Err(EscrowError::DataTypeMismatch.into());
+    }
+    T::deserialize(&mut &data[..]).map_err(|_| ProgramError::InvalidAccountData)
+}
+
+// Serializes `state` at the start of `dst`, zeroing the rest of it.
+fn serialize_padded<T: BorshSerialize>(state: &T, dst: &mut [u8]) {
+    for b in dst.iter_mut() {
+        *b = 0;
+    }
+    let mut dst = dst;
+    state.serialize(&mut dst).expect("BUG serializing account state!");
+}
+
+// The current layout version of escrows with the `EscrowV1` key.
+pub const ESCROW_VERSION: u8 = 1;
 
+// The original layout, without version or reserved space.
+pub const LEGACY_ESCROW_LEN: usize = 105;
+// Zeroed space at the end of the account, for fields added in later versions.
+pub const ESCROW_RESERVED_LEN: usize = 56;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
 pub struct Escrow {
     // First include fields that we might want to query for via RPC,
     // since we can query only the first 129 bytes.
-    pub is_initialized: bool,
+    pub key: AccountKey,
+    pub version: u8,
     pub initializer_pubkey: Pubkey,
     pub mint_pubkey: Pubkey,
 
     pub temp_token_account_pubkey: Pubkey,
     pub expected_amount: u64,
+
+    // When the listing was created and last updated. Zero for escrows
+    // migrated from the legacy layout, which didn't record them.
+    pub created_slot: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+    // The token account the token was listed from, where a cancellation returns it.
+    // The default pubkey if the seller didn't pass it.
+    pub seller_token_account_pubkey: Pubkey,
+    // Incremented by every update of the listing.
+    pub sequence: u64,
+    // The listing fee held in the account, refunded on cancellation unless forfeited.
+    // Zero if the listing fee was paid outright.
+    pub listing_deposit: u64,
+}
+
+impl Default for Escrow {
+    fn default() -> Self {
+        Escrow {
+            key: AccountKey::Uninitialized,
+            version: ESCROW_VERSION,
+            initializer_pubkey: Pubkey::default(),
+            mint_pubkey: Pubkey::default(),
+            temp_token_account_pubkey: Pubkey::default(),
+            expected_amount: 0,
+            created_slot: 0,
+            created_at: 0,
+            updated_at: 0,
+            seller_token_account_pubkey: Pubkey::default(),
+            sequence: 0,
+            listing_deposit: 0,
+        }
+    }
+}
+
+impl Keyed for Escrow {
+    const KEY: AccountKey = AccountKey::EscrowV1;
 }
 
 impl Sealed for Escrow {}
 
 impl IsInitialized for Escrow {
     fn is_initialized(&self) -> bool {
-        self.is_initialized
+        self.key != AccountKey::Uninitialized
     }
 }
 
-impl Pack for Escrow {
-    const LEN: usize = 105;
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, Escrow::LEN];
-        let (
-            is_initialized,
-            initializer_pubkey,
-            mint_pubkey,
-            temp_token_account_pubkey,
-            expected_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
-        let is_initialized = match is_initialized {
-            [0] => false,
-            [1] => true,
+// The legacy layout: the same fields, without the version.
+#[derive(BorshDeserialize)]
+struct LegacyEscrow {
+    key: AccountKey,
+    initializer_pubkey: Pubkey,
+    mint_pubkey: Pubkey,
+    temp_token_account_pubkey: Pubkey,
+    expected_amount: u64,
+}
+
+impl Escrow {
+    /// Unpacks an escrow in either the legacy layout or the current one,
+    /// telling them apart by the account length.
+    pub fn unpack_versioned(src: &[u8]) -> Result<Self, ProgramError> {
+        let escrow = match src.len() {
+            LEGACY_ESCROW_LEN => Self::unpack_legacy(src)?,
+            Escrow::LEN => Self::unpack_from_slice(src)?,
             _ => return Err(ProgramError::InvalidAccountData),
         };
+        if !escrow.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(escrow)
+    }
+
+    /// Whether `src` holds an escrow in the legacy layout, which `MigrateEscrow` upgrades.
+    pub fn is_legacy(src: &[u8]) -> bool {
+        src.len() == LEGACY_ESCROW_LEN
+    }
+
+    fn unpack_legacy(src: &[u8]) -> Result<Self, ProgramError> {
+        let legacy = LegacyEscrow::deserialize(&mut &src[..LEGACY_ESCROW_LEN])
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        let key = match legacy.key {
+            AccountKey::Uninitialized => AccountKey::Uninitialized,
+            AccountKey::EscrowV0 => AccountKey::EscrowV1,
+            _ => return Err(EscrowError::DataTypeMismatch.into()),
+        };
 
         Ok(Escrow {
-            is_initialized,
-            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
-            mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
-            temp_token_account_pubkey: Pubkey::new_from_array(*temp_token_account_pubkey),
-            expected_amount: u64::from_le_bytes(*expected_amount),
+            key,
+            version: ESCROW_VERSION,
+            initializer_pubkey: legacy.initializer_pubkey,
+            mint_pubkey: legacy.mint_pubkey,
+            temp_token_account_pubkey: legacy.temp_token_account_pubkey,
+            expected_amount: legacy.expected_amount,
+            ..Escrow::default()
         })
     }
+}
+
+impl Pack for Escrow {
+    const LEN: usize = 1 + 1 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 32 + 8 + 8 + ESCROW_RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let escrow: Escrow = try_from_slice_checked(src)?;
+        if escrow.is_initialized() && escrow.version != ESCROW_VERSION {
+            msg!("Unsupported escrow version {:?}", escrow.version);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(escrow)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        serialize_padded(self, dst)
+    }
+}
+
+pub const MARKETPLACE_PREFIX: &str = "marketplace";
+
+/// Who pays the sales tax and the royalties of a sale.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FeeMode {
+    /// The fees are taken out of the price, the seller gets the rest.
+    SellerPaid,
+    /// The fees are added on top of the price, the seller gets all of it.
+    BuyerPaid,
+}
+
+// Not derived, `#[default]` on enum variants is too recent for the BPF toolchain.
+#[allow(clippy::derivable_impls)]
+impl Default for FeeMode {
+    fn default() -> Self {
+        FeeMode::SellerPaid
+    }
+}
+
+/// A reduced sales tax and listing fee for the holders of a token.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct FeeTier {
+    // The mint of the token to hold, or the verified collection it belongs to.
+    pub token: Pubkey,
+    // The sales tax in basis points of the price, at most the default sales tax.
+    pub sales_tax_basis_points: u16,
+    // The listing fee in lamports, at most the default listing fee.
+    pub listing_fee: u64,
+}
+
+pub const FEE_TIER_LEN: usize = 32 + 2 + 8;
+pub const MAX_FEE_TIERS: usize = 4;
+
+// The current layout version of the marketplace.
+pub const MARKETPLACE_VERSION: u8 = 1;
+
+// The original layout, starting with an `is_initialized` flag instead of the account key.
+pub const LEGACY_MARKETPLACE_LEN: usize = 34;
+// Zeroed space at the end of the account, for fields added in later versions.
+pub const MARKETPLACE_RESERVED_LEN: usize = 64;
+
+/// Marketplace-wide configuration, stored in the PDA with seeds ["marketplace"].
+///
+/// The account does not need to exist: if it has not been created yet,
+/// the defaults from `Marketplace::default()` apply.
+///
+/// Fields are only ever appended, into the reserved space. Accounts created with an
+/// older, shorter layout read as if zero-padded, the fields they lack taking their defaults.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct Marketplace {
+    pub key: AccountKey,
+    pub authority_pubkey: Pubkey,
+    // If set, sales of mints without a metadata account are rejected.
+    pub require_metadata: bool,
+    // The part of the sales tax paid to the referrer of a sale, in basis points of the price.
+    pub referral_basis_points: u16,
+    pub fee_mode: FeeMode,
+    // The discounts of token holders, who prove it with a token account on sales and listings.
+    pub fee_tiers: Vec<FeeTier>,
+    // If set, listing fees are held in the escrow account as a deposit, forfeited
+    // on sale and refunded on cancellation.
+    pub refundable_listing_fee: bool,
+    // How long a listing can stay up before its deposit is forfeited on cancellation
+    // too, in seconds. Zero for never.
+    pub deposit_forfeit_seconds: u32,
+    // Set once the treasury is created: fees then have to be paid into it.
+    pub has_treasury: bool,
+    // If set, no listings are created and no sales happen, cancellations still go through.
+    pub paused: bool,
+    // If set, only mints and collections on the allow list are listed and sold.
+    pub allow_list_only: bool,
+    // The layout version. Zero in accounts created before it was recorded.
+    pub version: u8,
+}
+
+impl Default for Marketplace {
+    fn default() -> Self {
+        Marketplace {
+            key: AccountKey::Uninitialized,
+            authority_pubkey: Pubkey::default(),
+            require_metadata: false,
+            referral_basis_points: 0,
+            fee_mode: FeeMode::default(),
+            fee_tiers: vec![],
+            refundable_listing_fee: false,
+            deposit_forfeit_seconds: 0,
+            has_treasury: false,
+            paused: false,
+            allow_list_only: false,
+            version: MARKETPLACE_VERSION,
+        }
+    }
+}
+
+impl Keyed for Marketplace {
+    const KEY: AccountKey = AccountKey::Marketplace;
+}
+
+impl Sealed for Marketplace {}
+
+impl IsInitialized for Marketplace {
+    fn is_initialized(&self) -> bool {
+        self.key != AccountKey::Uninitialized
+    }
+}
+
+impl Marketplace {
+    /// Unpacks a marketplace in the current layout or an older, shorter one.
+    pub fn unpack_versioned(src: &[u8]) -> Result<Self, ProgramError> {
+        let marketplace = if src.len() < Marketplace::LEN {
+            let mut padded = vec![0; Marketplace::LEN];
+            padded[..src.len()].copy_from_slice(src);
+            if src.len() == LEGACY_MARKETPLACE_LEN && account_key(src)? == AccountKey::EscrowV0 {
+                padded[0] = AccountKey::Marketplace as u8;
+            }
+            let mut marketplace = Self::unpack_from_slice(&padded)?;
+            marketplace.version = MARKETPLACE_VERSION;
+            marketplace
+        } else if src.len() == Marketplace::LEN {
+            Self::unpack_from_slice(src)?
+        } else {
+            return Err(ProgramError::InvalidAccountData);
+        };
+        if !marketplace.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(marketplace)
+    }
+}
+
+impl Pack for Marketplace {
+    const LEN: usize =
+        1 + 32 + 1 + 2 + 1 + 4 + MAX_FEE_TIERS * FEE_TIER_LEN + 1 + 4 + 1 + 1 + 1 + 1 + MARKETPLACE_RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let marketplace: Marketplace = try_from_slice_checked(src)?;
+        if marketplace.is_initialized() && marketplace.version > MARKETPLACE_VERSION {
+            msg!("Unsupported marketplace version {:?}", marketplace.version);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(marketplace)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        serialize_padded(self, dst)
+    }
+}
+
+pub fn get_marketplace_account(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MARKETPLACE_PREFIX.as_bytes()], program_id)
+}
+
+pub const TREASURY_PREFIX: &str = "treasury";
+
+/// The fees collected by a treasury and withdrawn from it over one accounting period.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct FeePeriod {
+    // The unix timestamp the period started at.
+    pub start: i64,
+    // The house's part of the sales tax.
+    pub sales_tax: u64,
+    // Listing fees, including forfeited listing deposits.
+    pub listing_fees: u64,
+    pub withdrawn: u64,
+}
+
+pub const FEE_PERIOD_LEN: usize = 8 + 8 + 8 + 8;
+
+/// The account collecting the marketplace's fees, the PDA with seeds ["treasury", marketplace].
+///
+/// Its lamports above the rent exemption are the fees not withdrawn yet.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct Treasury {
+    pub key: AccountKey,
+    // The only account allowed to withdraw fees.
+    pub authority_pubkey: Pubkey,
+    // The length of accounting periods, in seconds. Zero for a single period that never ends.
+    pub period_seconds: u32,
+    pub current_period: FeePeriod,
+    // The period that was current until the treasury last moved on, which may have had no activity.
+    // Periods without any activity in between aren't recorded, so it may not be the one right before.
+    pub previous_period: FeePeriod,
+    pub total_collected: u64,
+    pub total_withdrawn: u64,
+}
+
+impl Treasury {
+    /// Moves on to the period `now` falls in, if the current one is over.
+    pub fn roll_period(&mut self, now: i64) {
+        let period_seconds = self.period_seconds as i64;
+        if period_seconds == 0 || now < self.current_period.start + period_seconds {
+            return;
+        }
+        let elapsed_periods = (now - self.current_period.start) / period_seconds;
+        self.previous_period = self.current_period;
+        self.current_period = FeePeriod {
+            start: self.current_period.start + elapsed_periods * period_seconds,
+            ..FeePeriod::default()
+        };
+    }
+}
+
+impl Keyed for Treasury {
+    const KEY: AccountKey = AccountKey::Treasury;
+}
+
+impl Sealed for Treasury {}
+
+impl IsInitialized for Treasury {
+    fn is_initialized(&self) -> bool {
+        self.key != AccountKey::Uninitialized
+    }
+}
+
+impl Pack for Treasury {
+    const LEN: usize = 1 + 32 + 4 + FEE_PERIOD_LEN + FEE_PERIOD_LEN + 8 + 8;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        try_from_slice_checked(src)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        serialize_padded(self, dst)
+    }
+}
+
+pub fn get_treasury_account(program_id: &Pubkey) -> (Pubkey, u8) {
+    let marketplace = get_marketplace_account(program_id).0;
+    Pubkey::find_program_address(&[TREASURY_PREFIX.as_bytes(), marketplace.as_ref()], program_id)
+}
+
+pub const ACCESS_PREFIX: &str = "access";
+
+/// Where a mint or a verified collection stands on the marketplace's allow and deny lists.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AccessMode {
+    /// On neither list, like mints and collections without an entry.
+    Unlisted,
+    /// On the allow list, required when the marketplace only takes allowed tokens.
+    Allowed,
+    /// On the deny list: never listed nor sold.
+    Denied,
+}
+
+// Not derived, `#[default]` on enum variants is too recent for the BPF toolchain.
+#[allow(clippy::derivable_impls)]
+impl Default for AccessMode {
+    fn default() -> Self {
+        AccessMode::Unlisted
+    }
+}
+
+/// The allow or deny list entry of a mint or a verified collection, the PDA with seeds ["access", target].
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct AccessEntry {
+    pub key: AccountKey,
+    // The mint or the verified collection.
+    pub target: Pubkey,
+    pub mode: AccessMode,
+}
+
+impl Keyed for AccessEntry {
+    const KEY: AccountKey = AccountKey::AccessEntry;
+}
+
+impl Sealed for AccessEntry {}
+
+impl IsInitialized for AccessEntry {
+    fn is_initialized(&self) -> bool {
+        self.key != AccountKey::Uninitialized
+    }
+}
+
+impl Pack for AccessEntry {
+    const LEN: usize = 1 + 32 + 1;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        try_from_slice_checked(src)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        serialize_padded(self, dst)
+    }
+}
+
+pub fn get_access_account(program_id: &Pubkey, target: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ACCESS_PREFIX.as_bytes(), target.as_ref()], program_id)
+}
+
+pub const POLICY_PREFIX: &str = "policy";
+pub const MAX_PAYMENT_MINTS: usize = 4;
+
+/// The terms a creator sets for the sales of their tokens, the PDA with seeds ["policy", target].
+///
+/// The target is a verified collection, or a creator for the tokens they are the first verified creator of.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct CreatorPolicy {
+    pub key: AccountKey,
+    pub target: Pubkey,
+    // The verified creator who last set the policy.
+    pub creator_pubkey: Pubkey,
+    // Listings below this price, in lamports, are refused.
+    pub min_price: u64,
+    // The royalties paid on sales at least, in basis points of the price,
+    // whatever the metadata says.
+    pub royalty_floor_basis_points: u16,
+    // The mints sales may be paid in, any if empty. Sales are paid in SOL, i.e. the native mint.
+    pub payment_mints: Vec<Pubkey>,
+}
+
+impl Keyed for CreatorPolicy {
+    const KEY: AccountKey = AccountKey::CreatorPolicy;
+}
+
+impl Sealed for CreatorPolicy {}
+
+impl IsInitialized for CreatorPolicy {
+    fn is_initialized(&self) -> bool {
+        self.key != AccountKey::Uninitialized
+    }
+}
+
+impl Pack for CreatorPolicy {
+    const LEN: usize = 1 + 32 + 32 + 8 + 2 + 4 + MAX_PAYMENT_MINTS * 32;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let policy: CreatorPolicy = try_from_slice_checked(src)?;
+        if policy.payment_mints.len() > MAX_PAYMENT_MINTS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(policy)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        serialize_padded(self, dst)
+    }
+}
+
+pub fn get_policy_account(program_id: &Pubkey, target: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POLICY_PREFIX.as_bytes(), target.as_ref()], program_id)
+}
+
+/// A listing of a compressed NFT, i.e. a leaf of a Bubblegum merkle tree
+/// whose delegate is the escrow PDA.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, PartialEq)]
+pub struct CompressedEscrow {
+    pub key: AccountKey,
+    pub initializer_pubkey: Pubkey,
+    pub merkle_tree_pubkey: Pubkey,
+    pub expected_amount: u64,
+
+    // The leaf, as proven when it was delegated to the PDA.
+    pub nonce: u64,
+    pub index: u32,
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+
+    // Royalty info from the leaf, matching its data and creator hashes.
+    pub seller_fee_basis_points: u16,
+    pub creators: Vec<Creator>,
+
+    // When the listing was created. Zero for listings made before it was recorded.
+    pub created_at: i64,
+    // The listing fee held in the account, refunded on cancellation unless forfeited.
+    // Zero if the listing fee was paid outright.
+    pub listing_deposit: u64,
+}
+
+impl Keyed for CompressedEscrow {
+    const KEY: AccountKey = AccountKey::CompressedEscrow;
+}
+
+impl Sealed for CompressedEscrow {}
+
+impl IsInitialized for CompressedEscrow {
+    fn is_initialized(&self) -> bool {
+        self.key != AccountKey::Uninitialized
+    }
+}
+
+// The layout before listing deposits were recorded, which ended with the creators.
+pub const LEGACY_COMPRESSED_ESCROW_LEN: usize = 1 + 32 + 32 + 8 + 8 + 4 + 32 + 32 + 2 + 4 + MAX_CREATOR_LIMIT * MAX_CREATOR_LEN;
+// Zeroed space at the end of the account, for fields added in later versions.
+pub const COMPRESSED_ESCROW_RESERVED_LEN: usize = 32;
+
+impl CompressedEscrow {
+    /// Unpacks a compressed escrow in either the legacy layout or the current one,
+    /// reading legacy listings as holding no deposit.
+    pub fn unpack_versioned(src: &[u8]) -> Result<Self, ProgramError> {
+        let escrow = match src.len() {
+            LEGACY_COMPRESSED_ESCROW_LEN => {
+                let mut padded = vec![0; CompressedEscrow::LEN];
+                padded[..src.len()].copy_from_slice(src);
+                Self::unpack_from_slice(&padded)?
+            }
+            CompressedEscrow::LEN => Self::unpack_from_slice(src)?,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        if !escrow.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(escrow)
+    }
+}
+
+impl Pack for CompressedEscrow {
+    const LEN: usize = LEGACY_COMPRESSED_ESCROW_LEN + 8 + 8 + COMPRESSED_ESCROW_RESERVED_LEN;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let escrow: CompressedEscrow = try_from_slice_checked(src)?;
+        if escrow.creators.len() > MAX_CREATOR_LIMIT {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(escrow)
+    }
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, Escrow::LEN];
-        let (
-            is_initialized_dst,
-            initializer_pubkey_dst,
-            mint_pubkey_dst,
-            temp_token_account_pubkey_dst,
-            expected_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
-
-        let Escrow {
-            is_initialized,
-            initializer_pubkey,
-            mint_pubkey,
-            temp_token_account_pubkey,
-            expected_amount,
-        } = self;
-
-        is_initialized_dst[0] = *is_initialized as u8;
-        initializer_pubkey_dst.copy_from_slice(initializer_pubkey.as_ref());
-        mint_pubkey_dst.copy_from_slice(mint_pubkey.as_ref());
-        temp_token_account_pubkey_dst.copy_from_slice(temp_token_account_pubkey.as_ref());
-        *expected_amount_dst = expected_amount.to_le_bytes();
+        serialize_padded(self, dst)
     }
 }
@@ -0,0 +1,332 @@
+//! Helpers to work with both the SPL token program and Token-2022.
+//!
+//! Token-2022 accounts and mints start with the same layout as SPL token ones,
+//! optionally followed by an account type byte and a list of type-length-value
+//! extensions. We only read the base state and the few extensions we care about,
+//! and route CPIs to whichever program owns the accounts involved.
+
+use {
+    borsh::BorshDeserialize,
+    solana_program::{
+        account_info::AccountInfo,
+        instruction::{AccountMeta, Instruction},
+        msg,
+        program_error::ProgramError,
+        program_pack::{IsInitialized, Pack},
+        pubkey::Pubkey,
+    },
+    spl_token::state::{Account as TokenAccount, Mint as TokenMint},
+    std::{convert::TryInto, str::FromStr},
+};
+
+use crate::{
+    error::EscrowError,
+    metadata::{Creator, Data, Key, Metadata, MAX_CREATOR_LIMIT},
+};
+
+pub const TOKEN_2022: &str = "TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb";
+// Decoded, as every instruction moving a token checks it.
+const TOKEN_2022_ID: Pubkey = Pubkey::new_from_array([
+    6, 221, 246, 225, 238, 117, 143, 222, 24, 66, 93, 188, 228, 108, 205, 218, 182, 26, 252, 77, 131, 185, 13, 39, 254, 189, 249, 40, 216, 161, 139, 252,
+]);
+
+// Extension types, as numbered by Token-2022.
+const TRANSFER_FEE_CONFIG: u16 = 1;
+const TRANSFER_FEE_AMOUNT: u16 = 2;
+const NON_TRANSFERABLE: u16 = 9;
+const PERMANENT_DELEGATE: u16 = 12;
+const TRANSFER_HOOK: u16 = 14;
+const TOKEN_METADATA: u16 = 19;
+
+// Mint extensions we can't trade: the token can't move, can be taken back
+// from the buyer at any time, or moving it runs a program we don't pass accounts for.
+const UNSUPPORTED_MINT_EXTENSIONS: [u16; 3] = [NON_TRANSFERABLE, PERMANENT_DELEGATE, TRANSFER_HOOK];
+
+// Token-2022 instruction tags
+const TRANSFER_FEE_EXTENSION: u8 = 26;
+const HARVEST_WITHHELD_TOKENS_TO_MINT: u8 = 4;
+
+// Keys of the token metadata extension's additional metadata holding royalty info.
+// Any other key that parses as a pubkey is a creator, with its share as value.
+pub const ROYALTY_BASIS_POINTS_KEY: &str = "royalty_basis_points";
+
+pub fn token_2022_program_id() -> Pubkey {
+    TOKEN_2022_ID
+}
+
+pub fn is_token_program(program_id: &Pubkey) -> bool {
+    *program_id == spl_token::id() || *program_id == token_2022_program_id()
+}
+
+/// Unpacks the base state of a token account owned by either token program.
+pub fn unpack_account(a: &AccountInfo) -> Result<TokenAccount, ProgramError> {
+    unpack_base::<TokenAccount>(&a.data.borrow())
+}
+
+/// Unpacks the base state of a mint owned by either token program.
+pub fn unpack_mint(a: &AccountInfo) -> Result<TokenMint, ProgramError> {
+    unpack_base::<TokenMint>(&a.data.borrow())
+}
+
+fn unpack_base<T: Pack + IsInitialized>(data: &[u8]) -> Result<T, ProgramError> {
+    // Anything longer must carry the account type byte after a full-length account.
+    if data.len() != T::LEN && data.len() <= TokenAccount::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let state = T::unpack_from_slice(&data[..T::LEN])?;
+    if !state.is_initialized() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    Ok(state)
+}
+
+/// Finds the value of a Token-2022 extension of the given type, if present.
+fn get_extension(data: &[u8], extension_type: u16) -> Option<&[u8]> {
+    // The account type byte comes right after the base account (mints are padded to that length).
+    let mut tlv = data.get(TokenAccount::LEN + 1..)?;
+    while tlv.len() >= 4 {
+        let ty = u16::from_le_bytes([tlv[0], tlv[1]]);
+        let len = u16::from_le_bytes([tlv[2], tlv[3]]) as usize;
+        let value = tlv.get(4..4 + len)?;
+        if ty == extension_type {
+            return Some(value);
+        }
+        tlv = &tlv[4 + len..];
+    }
+    None
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The transfer fee of a Token-2022 mint, as in effect for one epoch.
+pub struct TransferFee {
+    pub maximum_fee: u64,
+    pub transfer_fee_basis_points: u16,
+}
+
+impl TransferFee {
+    /// The fee withheld when transferring `amount`, rounded up like Token-2022 does.
+    pub fn calculate_fee(&self, amount: u64) -> u64 {
+        if self.transfer_fee_basis_points == 0 || amount == 0 {
+            return 0;
+        }
+        let numerator = amount as u128 * self.transfer_fee_basis_points as u128;
+        let mut fee = numerator / 10000;
+        if fee * 10000 < numerator {
+            fee += 1;
+        }
+        (fee as u64).min(self.maximum_fee)
+    }
+}
+
+/// Reads the transfer fee for `epoch` from the mint's transfer fee config, if it has one.
+pub fn get_transfer_fee(mint: &AccountInfo, epoch: u64) -> Option<TransferFee> {
+    let data = mint.data.borrow();
+    let config = get_extension(&data, TRANSFER_FEE_CONFIG)?;
+    if config.len() < 108 {
+        return None;
+    }
+    // Two authorities and the withheld amount, then the older and newer fee (epoch, max, bps).
+    let fee_at = |offset: usize| TransferFee {
+        maximum_fee: read_u64(config, offset + 8),
+        transfer_fee_basis_points: u16::from_le_bytes([config[offset + 16], config[offset + 17]]),
+    };
+    let newer_epoch = read_u64(config, 90);
+    if epoch >= newer_epoch {
+        Some(fee_at(90))
+    } else {
+        Some(fee_at(72))
+    }
+}
+
+/// Rejects Token-2022 mints with an extension that makes them unsafe or impossible to trade.
+pub fn check_mint_extensions(mint: &AccountInfo) -> Result<(), ProgramError> {
+    let data = mint.data.borrow();
+    for extension_type in UNSUPPORTED_MINT_EXTENSIONS.iter() {
+        if get_extension(&data, *extension_type).is_some() {
+            msg!("Mint {:?} has unsupported extension {:?}", mint.key, extension_type);
+            return Err(EscrowError::UnsupportedMintExtension.into());
+        }
+    }
+    Ok(())
+}
+
+/// The size of a token account for the mint with `mint_data`. Token-2022 accounts
+/// of mints with a transfer fee need room for the amount withheld from them.
+pub fn get_account_len(mint_data: &[u8]) -> usize {
+    if get_extension(mint_data, TRANSFER_FEE_CONFIG).is_some() {
+        // The account type byte, then the type, length and value of the transfer fee amount.
+        TokenAccount::LEN + 1 + 4 + 8
+    } else {
+        TokenAccount::LEN
+    }
+}
+
+/// The amount of fees withheld in a Token-2022 account, which must be harvested before closing it.
+pub fn get_withheld_amount(account: &AccountInfo) -> u64 {
+    let data = account.data.borrow();
+    match get_extension(&data, TRANSFER_FEE_AMOUNT) {
+        Some(value) if value.len() >= 8 => read_u64(value, 0),
+        _ => 0,
+    }
+}
+
+#[derive(BorshDeserialize)]
+struct TokenMetadata {
+    update_authority: [u8; 32],
+    mint: [u8; 32],
+    name: String,
+    symbol: String,
+    uri: String,
+    additional_metadata: Vec<(String, String)>,
+}
+
+/// Reads the mint's token metadata extension, if it has one, as Metaplex-style metadata.
+///
+/// Royalties are taken from the `royalty_basis_points` additional metadata field and
+/// creators from the fields whose key is a pubkey, with their share as value. Like
+/// Metaplex metadata, there are at most `MAX_CREATOR_LIMIT` creators, whose shares
+/// add up to 100, and royalties are at most 100%.
+pub fn get_token_metadata(mint: &AccountInfo) -> Result<Option<Metadata>, ProgramError> {
+    let data = mint.data.borrow();
+    let value = match get_extension(&data, TOKEN_METADATA) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let md = TokenMetadata::deserialize(&mut &value[..]).map_err(|_| EscrowError::InvalidMetadata)?;
+
+    let mut seller_fee_basis_points = 0;
+    let mut creators = vec![];
+    for (key, value) in md.additional_metadata {
+        if key == ROYALTY_BASIS_POINTS_KEY {
+            seller_fee_basis_points = value.parse().map_err(|_| EscrowError::InvalidMetadata)?;
+        } else if let Ok(address) = Pubkey::from_str(&key) {
+            let share = value.parse().map_err(|_| EscrowError::InvalidMetadata)?;
+            creators.push(Creator {
+                address,
+                verified: false,
+                share,
+            });
+        }
+    }
+    if seller_fee_basis_points > 10000 {
+        msg!("Royalties of {:?} basis points in token metadata", seller_fee_basis_points);
+        return Err(EscrowError::InvalidMetadata.into());
+    }
+    if creators.len() > MAX_CREATOR_LIMIT {
+        msg!("{:?} creators in token metadata, at most {:?}", creators.len(), MAX_CREATOR_LIMIT);
+        return Err(EscrowError::InvalidMetadata.into());
+    }
+    let total_share: u16 = creators.iter().map(|c| c.share as u16).sum();
+    if !creators.is_empty() && total_share != 100 {
+        msg!("Creator shares in token metadata add up to {:?}", total_share);
+        return Err(EscrowError::InvalidMetadata.into());
+    }
+
+    Ok(Some(Metadata {
+        key: Key::MetadataV1,
+        update_authority: Pubkey::new_from_array(md.update_authority),
+        mint: Pubkey::new_from_array(md.mint),
+        data: Data {
+            name: md.name,
+            symbol: md.symbol,
+            uri: md.uri,
+            seller_fee_basis_points,
+            creators: if creators.is_empty() { None } else { Some(creators) },
+        },
+        primary_sale_happened: false,
+        is_mutable: true,
+        edition_nonce: None,
+        token_standard: None,
+        collection: None,
+        uses: None,
+        collection_details: None,
+        programmable_config: None,
+    }))
+}
+
+/// Checks that the token program passed is the one owning `account`.
+pub fn check_token_program(token_program: &AccountInfo, account: &AccountInfo) -> Result<(), ProgramError> {
+    if !is_token_program(token_program.key) || token_program.key != account.owner {
+        msg!("Token program {:?} doesn't own {:?}", token_program.key, account.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+// The spl_token instruction builders only accept the SPL token program id,
+// but the instructions we use are encoded the same way by Token-2022.
+fn for_program(mut ix: Instruction, token_program_id: &Pubkey) -> Instruction {
+    ix.program_id = *token_program_id;
+    ix
+}
+
+pub fn set_authority(
+    token_program_id: &Pubkey,
+    owned_pubkey: &Pubkey,
+    new_authority_pubkey: Option<&Pubkey>,
+    authority_type: spl_token::instruction::AuthorityType,
+    owner_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let ix = spl_token::instruction::set_authority(
+        &spl_token::id(),
+        owned_pubkey,
+        new_authority_pubkey,
+        authority_type,
+        owner_pubkey,
+        &[],
+    )?;
+    Ok(for_program(ix, token_program_id))
+}
+
+pub fn transfer_checked(
+    token_program_id: &Pubkey,
+    source_pubkey: &Pubkey,
+    mint_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    authority_pubkey: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Result<Instruction, ProgramError> {
+    let ix = spl_token::instruction::transfer_checked(
+        &spl_token::id(),
+        source_pubkey,
+        mint_pubkey,
+        destination_pubkey,
+        authority_pubkey,
+        &[],
+        amount,
+        decimals,
+    )?;
+    Ok(for_program(ix, token_program_id))
+}
+
+pub fn close_account(
+    token_program_id: &Pubkey,
+    account_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    owner_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let ix = spl_token::instruction::close_account(
+        &spl_token::id(),
+        account_pubkey,
+        destination_pubkey,
+        owner_pubkey,
+        &[],
+    )?;
+    Ok(for_program(ix, token_program_id))
+}
+
+/// Moves the fees withheld in `source_pubkey` to the mint. Anyone can call this.
+pub fn harvest_withheld_tokens_to_mint(mint_pubkey: &Pubkey, source_pubkey: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: token_2022_program_id(),
+        accounts: vec![
+            AccountMeta::new(*mint_pubkey, false),
+            AccountMeta::new(*source_pubkey, false),
+        ],
+        data: vec![TRANSFER_FEE_EXTENSION, HARVEST_WITHHELD_TOKENS_TO_MINT],
+    }
+}
@@ -0,0 +1,175 @@
+use solana_escrow::cli::{self, Chain, CliResult, Filter};
+use solana_escrow::*;
+use solana_program::program_pack::Pack;
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature, Signer},
+    transaction::Transaction,
+};
+use std::str::FromStr;
+
+#[allow(dead_code)]
+mod utils;
+
+// A chain backed by the banks client. It can't list program accounts,
+// so it remembers every account a transaction touched and scans those.
+struct BanksChain {
+    runtime: tokio::runtime::Runtime,
+    banks_client: BanksClient,
+    known_accounts: Vec<Pubkey>,
+}
+
+impl Chain for BanksChain {
+    fn get_account(&mut self, pubkey: &Pubkey) -> CliResult<Option<Account>> {
+        let banks_client = &mut self.banks_client;
+        Ok(self.runtime.block_on(banks_client.get_account(*pubkey))?)
+    }
+
+    fn get_program_accounts(&mut self, program_id: &Pubkey, filters: &[Filter]) -> CliResult<Vec<(Pubkey, Account)>> {
+        let mut accounts = vec![];
+        for pubkey in self.known_accounts.clone() {
+            if let Some(account) = self.get_account(&pubkey)? {
+                if account.owner == *program_id && filters.iter().all(|f| f.matches(&account.data)) {
+                    accounts.push((pubkey, account));
+                }
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn get_minimum_balance_for_rent_exemption(&mut self, len: usize) -> CliResult<u64> {
+        let banks_client = &mut self.banks_client;
+        Ok(self.runtime.block_on(banks_client.get_rent())?.minimum_balance(len))
+    }
+
+    fn send(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> CliResult<Signature> {
+        for ix in instructions {
+            for meta in &ix.accounts {
+                if !self.known_accounts.contains(&meta.pubkey) {
+                    self.known_accounts.push(meta.pubkey);
+                }
+            }
+        }
+        let banks_client = &mut self.banks_client;
+        let blockhash = self.runtime.block_on(banks_client.get_recent_blockhash())?;
+        let transaction = Transaction::new_signed_with_payer(instructions, Some(&signers[0].pubkey()), &signers.to_vec(), blockhash);
+        let signature = transaction.signatures[0];
+        self.runtime.block_on(banks_client.process_transaction(transaction))?;
+        Ok(signature)
+    }
+}
+
+// Starts a validator with the escrow program, returning the chain, the seller
+// and one token account of the seller holding a freshly minted NFT.
+fn setup(program_id: Pubkey, buyer: &Keypair) -> (BanksChain, Keypair, Pubkey) {
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    program_test.add_account(
+        Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap(),
+        Account {
+            lamports: 1,
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        buyer.pubkey(),
+        Account {
+            lamports: 1_000_000_000,
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut program_ctx = runtime.block_on(program_test.start_with_context());
+    let seller = Keypair::from_bytes(&program_ctx.payer.to_bytes()).unwrap();
+    let mint_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    runtime.block_on(async {
+        utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &seller.pubkey())
+            .await
+            .unwrap();
+        utils::create_token_account(
+            &mut program_ctx,
+            &token_account_keypair,
+            &mint_keypair.pubkey(),
+            &seller.pubkey(),
+            &solana_sdk::sysvar::rent::Rent::default(),
+        )
+        .await
+        .unwrap();
+        utils::mint_tokens_to(
+            &mut program_ctx,
+            &mint_keypair.pubkey(),
+            &token_account_keypair.pubkey(),
+            &seller,
+            1,
+        )
+        .await
+        .unwrap();
+    });
+
+    let chain = BanksChain {
+        runtime,
+        banks_client: program_ctx.banks_client,
+        known_accounts: vec![],
+    };
+    (chain, seller, token_account_keypair.pubkey())
+}
+
+fn token_amount(chain: &mut BanksChain, token_account: &Pubkey) -> u64 {
+    let account = chain.get_account(token_account).unwrap().unwrap();
+    spl_token::state::Account::unpack_from_slice(&account.data).unwrap().amount
+}
+
+#[test]
+fn test_cli_list_update_and_buy() {
+    let program_id = Pubkey::new_unique();
+    let buyer = Keypair::new();
+    let (mut chain, seller, token_account) = setup(program_id, &buyer);
+
+    let escrow_pubkey = cli::list(&mut chain, &program_id, &seller, &token_account, 1000).unwrap();
+    assert_eq!(token_amount(&mut chain, &token_account), 0);
+    let listings = cli::list_by_seller(&mut chain, &program_id, &seller.pubkey()).unwrap();
+    assert_eq!(listings.len(), 1);
+    assert_eq!(listings[0].escrow, escrow_pubkey);
+    assert_eq!(listings[0].price, 1000);
+
+    cli::update_price(&mut chain, &program_id, &seller, &escrow_pubkey, 2000).unwrap();
+    let shown = cli::show(&mut chain, &escrow_pubkey).unwrap();
+    assert!(shown.contains("price:          2000 lamports"), "{}", shown);
+    assert!(shown.contains("sales tax:      50 lamports"), "{}", shown);
+    assert!(shown.contains("seller nets:    1950 lamports"), "{}", shown);
+    assert!(shown.contains("(sequence 1)"), "{}", shown);
+
+    let buyer_before = chain.get_account(&buyer.pubkey()).unwrap().unwrap().lamports;
+    cli::buy(&mut chain, &program_id, &buyer, &escrow_pubkey).unwrap();
+    assert!(chain.get_account(&escrow_pubkey).unwrap().is_none());
+    let buyer_after = chain.get_account(&buyer.pubkey()).unwrap().unwrap().lamports;
+    // The price, plus the rent of the new token account and the transaction fee.
+    assert!(buyer_before - buyer_after > 2000);
+    assert!(cli::list_by_seller(&mut chain, &program_id, &seller.pubkey())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_cli_cancel_returns_token_to_seller() {
+    let program_id = Pubkey::new_unique();
+    let buyer = Keypair::new();
+    let (mut chain, seller, token_account) = setup(program_id, &buyer);
+
+    let escrow_pubkey = cli::list(&mut chain, &program_id, &seller, &token_account, 1000).unwrap();
+    assert!(cli::update_price(&mut chain, &program_id, &buyer, &escrow_pubkey, 1).is_err());
+    cli::cancel(&mut chain, &program_id, &seller, &escrow_pubkey).unwrap();
+
+    assert_eq!(token_amount(&mut chain, &token_account), 1);
+    assert!(chain.get_account(&escrow_pubkey).unwrap().is_none());
+}
@@ -0,0 +1,297 @@
+//! Compute unit benchmarks of the BPF build of the program:
+//!
+//!     $ cargo test-bpf --test compute -- --ignored --nocapture
+//!
+//! Only the BPF build is metered, so the benchmarks are ignored by default and fail
+//! when it can't be found. Each path is measured by bisecting the smallest compute
+//! budget it goes through with, and fails if it needs more than recorded in
+//! `tests/compute_baseline.txt`, or isn't recorded there. Run with
+//! `UPDATE_COMPUTE_BASELINE=1` to record all of them again after an intended change.
+
+use borsh::BorshSerialize;
+use solana_escrow::state::Escrow;
+use solana_escrow::*;
+use solana_program::{program_option::COption, program_pack::Pack};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    signature::{keypair_from_seed, Keypair, Signer},
+    sysvar::rent::Rent,
+    transaction::Transaction,
+};
+use std::collections::BTreeMap;
+use std::fs;
+
+const PROGRAM_NAME: &str = "solana_escrow";
+const BASELINE_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/compute_baseline.txt");
+const MAX_UNITS: u64 = 200_000;
+// Bisection stops once the budget is known within this many units.
+const RESOLUTION: u64 = 50;
+const PRICE: u64 = 1_000_000;
+
+#[derive(Clone, Copy)]
+enum Path {
+    InitEscrow,
+    // A sale with that many creators in metadata.
+    Exchange(usize),
+    Cancel,
+}
+
+impl Path {
+    fn name(&self) -> String {
+        match self {
+            Path::InitEscrow => "init_escrow".to_string(),
+            Path::Exchange(creators) => format!("exchange_{}_creators", creators),
+            Path::Cancel => "cancel".to_string(),
+        }
+    }
+}
+
+// Keys are fixed so that PDAs, and the bumps tried to find them, are the same in every run.
+fn program_id() -> Pubkey {
+    Pubkey::new_from_array([7; 32])
+}
+
+fn keypair(seed: u8) -> Keypair {
+    keypair_from_seed(&[seed; 32]).unwrap()
+}
+
+fn add_account(program_test: &mut ProgramTest, pubkey: Pubkey, data: Vec<u8>, owner: Pubkey) {
+    program_test.add_account(
+        pubkey,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()).max(1) + 10 * PRICE,
+            data,
+            owner,
+            ..Account::default()
+        },
+    );
+}
+
+fn add_token_account(program_test: &mut ProgramTest, pubkey: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) {
+    let token_account = spl_token::state::Account {
+        mint,
+        owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    };
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    token_account.pack_into_slice(&mut data);
+    add_account(program_test, pubkey, data, spl_token::id());
+}
+
+fn sample_metadata(mint: Pubkey, creators: usize) -> metadata::Metadata {
+    let creators: Vec<metadata::Creator> = (0..creators)
+        .map(|i| metadata::Creator {
+            address: keypair(100 + i as u8).pubkey(),
+            verified: i == 0,
+            share: if i == 0 { 100 - 100 / creators as u8 * (creators as u8 - 1) } else { 100 / creators as u8 },
+        })
+        .collect();
+    metadata::Metadata {
+        key: metadata::Key::MetadataV1,
+        update_authority: keypair(99).pubkey(),
+        mint,
+        data: metadata::Data {
+            name: "Benchmark".to_string(),
+            symbol: "BNCH".to_string(),
+            uri: "https://example.com/benchmark.json".to_string(),
+            seller_fee_basis_points: 500,
+            creators: Some(creators).filter(|creators| !creators.is_empty()),
+        },
+        primary_sale_happened: true,
+        is_mutable: true,
+        edition_nonce: None,
+        token_standard: None,
+        collection: None,
+        uses: None,
+        collection_details: None,
+        programmable_config: None,
+    }
+}
+
+// The program's state before `path` runs, the instruction running it and its signers.
+fn setup(path: Path) -> (ProgramTest, Instruction, Vec<Keypair>) {
+    let program_id = program_id();
+    let mut program_test = ProgramTest::new(PROGRAM_NAME, program_id, None);
+    let initializer = keypair(1);
+    let taker = keypair(2);
+    let mint = keypair(3).pubkey();
+    let escrow_pubkey = keypair(4).pubkey();
+    let temp_token_account = keypair(5).pubkey();
+    let taker_token_account = keypair(6).pubkey();
+
+    let creators = match path {
+        Path::Exchange(creators) => creators,
+        _ => 1,
+    };
+    let md = sample_metadata(mint, creators);
+    let mut metadata_data = md.try_to_vec().unwrap();
+    metadata_data.resize(metadata::MAX_METADATA_LEN, 0);
+    add_account(&mut program_test, metadata::get_metadata_account(&mint), metadata_data, metadata::metaplex_program_id());
+    for creator in md.data.creators.iter().flatten() {
+        add_account(&mut program_test, creator.address, vec![], solana_program::system_program::id());
+    }
+    for pubkey in &[initializer.pubkey(), taker.pubkey()] {
+        add_account(&mut program_test, *pubkey, vec![], solana_program::system_program::id());
+    }
+    let mint_state = spl_token::state::Mint {
+        mint_authority: COption::Some(initializer.pubkey()),
+        supply: 1,
+        decimals: 0,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    };
+    let mut mint_data = vec![0; spl_token::state::Mint::LEN];
+    mint_state.pack_into_slice(&mut mint_data);
+    add_account(&mut program_test, mint, mint_data, spl_token::id());
+
+    if let Path::InitEscrow = path {
+        add_token_account(&mut program_test, temp_token_account, mint, initializer.pubkey(), 1);
+        add_account(&mut program_test, escrow_pubkey, vec![0; Escrow::LEN], program_id);
+        let instruction = client::init_escrow(
+            &program_id,
+            &initializer.pubkey(),
+            &temp_token_account,
+            &mint,
+            &escrow_pubkey,
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            Some(&md),
+            None,
+            None,
+            PRICE,
+        );
+        return (program_test, instruction, vec![initializer]);
+    }
+
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer.pubkey(),
+        mint_pubkey: mint,
+        temp_token_account_pubkey: temp_token_account,
+        expected_amount: PRICE,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0; Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    add_account(&mut program_test, escrow_pubkey, escrow_data, program_id);
+    let (pda, _nonce) = Pubkey::find_program_address(&[processor::ESCROW_PDA_SEED], &program_id);
+    add_token_account(&mut program_test, temp_token_account, mint, pda, 1);
+    // A cancellation is the seller buying back their own token.
+    let buyer = if let Path::Cancel = path { initializer } else { taker };
+    add_token_account(&mut program_test, taker_token_account, mint, buyer.pubkey(), 0);
+    let instruction = client::exchange(
+        &program_id,
+        &buyer.pubkey(),
+        &taker_token_account,
+        &escrow_pubkey,
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    (program_test, instruction, vec![buyer])
+}
+
+async fn succeeds_within(path: Path, max_units: u64) -> bool {
+    let (mut program_test, instruction, signers) = setup(path);
+    program_test.set_bpf_compute_max_units(max_units);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+    let mut all_signers = vec![&payer];
+    all_signers.extend(signers.iter());
+    transaction.sign(&all_signers, recent_blockhash);
+    banks_client.process_transaction(transaction).await.is_ok()
+}
+
+// The compute units `path` needs, within `RESOLUTION`.
+async fn measure(path: Path) -> u64 {
+    assert!(succeeds_within(path, MAX_UNITS).await, "{} fails even with {} compute units", path.name(), MAX_UNITS);
+    let (mut low, mut high) = (0, MAX_UNITS);
+    while high - low > RESOLUTION {
+        let middle = (low + high) / 2;
+        if succeeds_within(path, middle).await {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+    high
+}
+
+fn read_baseline() -> BTreeMap<String, u64> {
+    fs::read_to_string(BASELINE_FILE)
+        .expect("tests/compute_baseline.txt")
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next().unwrap().to_string();
+            let units = fields.next().and_then(|units| units.parse().ok()).expect("compute units in baseline");
+            (name, units)
+        })
+        .collect()
+}
+
+fn write_baseline(baseline: &BTreeMap<String, u64>) {
+    let mut contents = "# Compute units each path needs, written by tests/compute.rs\n".to_string();
+    for (name, units) in baseline {
+        contents.push_str(&format!("{} {}\n", name, units));
+    }
+    fs::write(BASELINE_FILE, contents).unwrap();
+}
+
+// Ignored by default: it needs the BPF build, which `cargo test` doesn't make.
+#[tokio::test]
+#[ignore]
+async fn test_compute_units() {
+    assert!(
+        find_file(&format!("{}.so", PROGRAM_NAME)).is_some(),
+        "{}.so not found, build it with `cargo build-bpf` to benchmark compute units",
+        PROGRAM_NAME
+    );
+    let update = std::env::var("UPDATE_COMPUTE_BASELINE").is_ok();
+    let mut baseline = if update { BTreeMap::new() } else { read_baseline() };
+    let mut paths = vec![Path::InitEscrow];
+    paths.extend((0..=5).map(Path::Exchange));
+    paths.push(Path::Cancel);
+
+    let mut failures = vec![];
+    println!("{:<24} {:>8} {:>8}", "path", "units", "baseline");
+    for path in paths {
+        let units = measure(path).await;
+        let name = path.name();
+        if update {
+            println!("{:<24} {:>8} {:>8}", name, units, "recorded");
+            baseline.insert(name, units);
+            continue;
+        }
+        match baseline.get(&name).copied() {
+            Some(baseline_units) => {
+                println!("{:<24} {:>8} {:>8}", name, units, baseline_units);
+                if units > baseline_units {
+                    failures.push(format!("{}: {} compute units, up from {}", name, units, baseline_units));
+                }
+            }
+            None => {
+                println!("{:<24} {:>8} {:>8}", name, units, "missing");
+                failures.push(format!("{}: {} compute units, not in the baseline", name, units));
+            }
+        }
+    }
+    if update {
+        write_baseline(&baseline);
+    }
+    assert!(
+        failures.is_empty(),
+        "compute units past the baseline, record intended changes with UPDATE_COMPUTE_BASELINE=1:\n{}",
+        failures.join("\n")
+    );
+}
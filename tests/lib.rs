@@ -1,3 +1,4 @@
+use borsh::BorshSerialize;
 use solana_escrow::state::Escrow;
 use solana_escrow::*;
 use solana_program::program_error::ProgramError;
@@ -56,6 +57,166 @@ async fn test_invalid_account_data() {
     assert_eq!(res.err().unwrap(), ProgramError::InvalidAccountData);
 }
 
+#[tokio::test]
+async fn test_account_key_mismatch() {
+    let mut data = vec![0_u8; state::Escrow::LEN.max(state::Marketplace::LEN)];
+    let marketplace = state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: Pubkey::new_unique(),
+        require_metadata: true,
+        ..state::Marketplace::default()
+    };
+    state::Marketplace::pack(marketplace, &mut data[..state::Marketplace::LEN]).unwrap();
+    assert_eq!(
+        Escrow::unpack_from_slice(&data).err().unwrap(),
+        ProgramError::Custom(19)
+    );
+    assert_eq!(
+        state::CompressedEscrow::unpack_from_slice(&data).err().unwrap(),
+        ProgramError::Custom(19)
+    );
+
+    // Unknown keys are not deserialized at all
+    data[0] = u8::MAX;
+    assert_eq!(
+        Escrow::unpack_from_slice(&data).err().unwrap(),
+        ProgramError::InvalidAccountData
+    );
+}
+
+fn legacy_escrow_data(initializer: &Pubkey, mint: &Pubkey, temp_token_account: &Pubkey, amount: u64) -> Vec<u8> {
+    let mut data = vec![1u8];
+    data.extend_from_slice(initializer.as_ref());
+    data.extend_from_slice(mint.as_ref());
+    data.extend_from_slice(temp_token_account.as_ref());
+    data.extend_from_slice(&amount.to_le_bytes());
+    data
+}
+
+#[tokio::test]
+async fn test_escrow_layout_versions() {
+    let initializer = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+
+    let legacy = legacy_escrow_data(&initializer, &mint, &temp_token_account, 42);
+    assert_eq!(legacy.len(), state::LEGACY_ESCROW_LEN);
+    let escrow = Escrow::unpack_versioned(&legacy).unwrap();
+    assert_eq!(escrow.initializer_pubkey, initializer);
+    assert_eq!(escrow.mint_pubkey, mint);
+    assert_eq!(escrow.temp_token_account_pubkey, temp_token_account);
+    assert_eq!(escrow.expected_amount, 42);
+
+    let mut current = vec![0_u8; state::Escrow::LEN];
+    Escrow::pack(escrow, &mut current).unwrap();
+    assert_eq!(current[0], state::AccountKey::EscrowV1 as u8);
+    assert_eq!(current[1], state::ESCROW_VERSION);
+    let escrow = Escrow::unpack_versioned(&current).unwrap();
+    assert_eq!(escrow.initializer_pubkey, initializer);
+    assert_eq!(escrow.expected_amount, 42);
+
+    // Unknown versions are rejected
+    current[1] = state::ESCROW_VERSION + 1;
+    assert_eq!(
+        Escrow::unpack_versioned(&current).err().unwrap(),
+        ProgramError::InvalidAccountData
+    );
+    assert_eq!(
+        Escrow::unpack_versioned(&legacy[..100]).err().unwrap(),
+        ProgramError::InvalidAccountData
+    );
+    // Legacy escrows are only read from accounts of their own length
+    let mut padded = legacy.clone();
+    padded.resize(state::Escrow::LEN, 0);
+    assert_eq!(
+        Escrow::unpack_versioned(&padded).err().unwrap(),
+        ProgramError::Custom(19)
+    );
+}
+
+#[tokio::test]
+async fn test_marketplace_layout_versions() {
+    let program_id = Pubkey::new_unique();
+    let (marketplace_pubkey, _) = state::get_marketplace_account(&program_id);
+    let authority_keypair = Keypair::new();
+
+    // The original layout: is_initialized, authority, require_metadata
+    let mut legacy = vec![1_u8];
+    legacy.extend_from_slice(authority_keypair.pubkey().as_ref());
+    legacy.push(1);
+    assert_eq!(legacy.len(), state::LEGACY_MARKETPLACE_LEN);
+    let marketplace = state::Marketplace::unpack_versioned(&legacy).unwrap();
+    assert_eq!(
+        marketplace,
+        state::Marketplace {
+            key: state::AccountKey::Marketplace,
+            authority_pubkey: authority_keypair.pubkey(),
+            require_metadata: true,
+            ..state::Marketplace::default()
+        }
+    );
+
+    // Later layouts stopped after the fields of their time, the others take their defaults
+    let mut shorter = legacy.clone();
+    shorter[0] = state::AccountKey::Marketplace as u8;
+    shorter.extend_from_slice(&500u16.to_le_bytes());
+    let marketplace = state::Marketplace::unpack_versioned(&shorter).unwrap();
+    assert_eq!(marketplace.referral_basis_points, 500);
+    assert_eq!(marketplace.fee_tiers, vec![]);
+    assert!(!marketplace.paused);
+    assert_eq!(marketplace.version, state::MARKETPLACE_VERSION);
+
+    // The version is the last field written
+    let version_offset = marketplace.try_to_vec().unwrap().len() - 1;
+    let mut current = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace::pack(marketplace, &mut current).unwrap();
+    assert_eq!(current[version_offset], state::MARKETPLACE_VERSION);
+    assert_eq!(state::Marketplace::unpack_versioned(&current).unwrap().referral_basis_points, 500);
+    let mut longer = current.clone();
+    longer.push(0);
+    assert_eq!(
+        state::Marketplace::unpack_versioned(&longer).err().unwrap(),
+        ProgramError::InvalidAccountData
+    );
+    // Unknown versions are rejected
+    current[version_offset] = state::MARKETPLACE_VERSION + 1;
+    assert_eq!(
+        state::Marketplace::unpack_versioned(&current).err().unwrap(),
+        ProgramError::InvalidAccountData
+    );
+
+    // Older accounts can't grow, so they can't be updated
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    program_test.add_account(
+        marketplace_pubkey,
+        Account {
+            lamports: 5000000,
+            data: legacy,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+    let accounts = vec![
+        AccountMeta::new_readonly(authority_keypair.pubkey(), true),
+        AccountMeta::new(marketplace_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[3u8, 0u8], accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::AccountDataTooSmall)
+    );
+}
+
 #[tokio::test]
 async fn test_init_with_not_enough_accounts_should_fail() {
     let program_id = Pubkey::new_unique();
@@ -81,7 +242,7 @@ async fn test_init_with_not_enough_accounts_should_fail() {
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -151,7 +312,7 @@ async fn test_init_when_escrow_account_not_rent_exempt() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -161,10 +322,11 @@ async fn test_init_when_escrow_account_not_rent_exempt() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -199,7 +361,7 @@ async fn test_init_when_initializer_not_signed_should_fail() {
     let mut program_ctx = program_test.start_with_context().await;
     let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(initializer_keypair.pubkey(), false),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -209,10 +371,11 @@ async fn test_init_when_initializer_not_signed_should_fail() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -255,7 +418,7 @@ async fn test_init_when_mint_not_created_should_fail() {
     let mut program_ctx = program_test.start_with_context().await;
     let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -265,10 +428,11 @@ async fn test_init_when_mint_not_created_should_fail() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -320,7 +484,7 @@ async fn test_init_when_token_not_created_should_fail() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -330,10 +494,11 @@ async fn test_init_when_token_not_created_should_fail() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -412,7 +577,7 @@ async fn test_init_with_invalid_mint_should_fail() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(wrong_mint_keypair.pubkey(), false),
@@ -422,10 +587,11 @@ async fn test_init_with_invalid_mint_should_fail() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &wrong_mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -505,7 +671,7 @@ async fn test_init_with_invalid_rent() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -515,10 +681,11 @@ async fn test_init_with_invalid_rent() {
         AccountMeta::new(wrong_rent_keypair.pubkey(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -589,7 +756,7 @@ async fn test_init_initialized_escrow() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -599,10 +766,11 @@ async fn test_init_initialized_escrow() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -673,7 +841,7 @@ async fn test_init_escrow_with_wrong_amount() {
     .await
     .unwrap();
 
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new(payer_keypair.pubkey(), true),
         AccountMeta::new(token_account_keypair.pubkey(), false),
         AccountMeta::new(mint_keypair.pubkey(), false),
@@ -683,10 +851,11 @@ async fn test_init_escrow_with_wrong_amount() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[0, 5, 0],
+            &[0u8, 5u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -767,7 +936,7 @@ async fn test_invalid_instruction() {
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
-            &[2, 5, 0],
+            &[u8::MAX, 5, 0],
             accounts,
         )],
         Some(&payer_keypair.pubkey()),
@@ -837,21 +1006,19 @@ async fn test_init_escrow() {
     .await
     .unwrap();
 
-    let accounts = vec![
-        AccountMeta::new(payer_keypair.pubkey(), true),
-        AccountMeta::new(token_account_keypair.pubkey(), false),
-        AccountMeta::new(mint_keypair.pubkey(), false),
-        AccountMeta::new(escrow_keypair.pubkey(), false),
-        AccountMeta::new(sales_tax_recipient_pubkey, false),
-        AccountMeta::new(solana_program::system_program::id(), false),
-        AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
-        AccountMeta::new(spl_token::id(), false),
-    ];
     let mut transaction = Transaction::new_with_payer(
-        &[Instruction::new_with_bincode(
-            program_id,
-            &[0u8, 1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
-            accounts,
+        &[client::init_escrow(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &token_account_keypair.pubkey(),
+            &mint_keypair.pubkey(),
+            &escrow_keypair.pubkey(),
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            None,
+            None,
+            None,
+            1,
         )],
         Some(&payer_keypair.pubkey()),
     );
@@ -869,13 +1036,16 @@ async fn test_init_escrow() {
         .expect("get_account")
         .expect("account not found");
     let escrow = state::Escrow::unpack_from_slice(&escrow_account.data).unwrap();
-    assert_eq!(escrow.is_initialized, true);
+    assert_eq!(escrow.key, state::AccountKey::EscrowV1);
     assert_eq!(escrow.initializer_pubkey, payer_keypair.pubkey());
     assert_eq!(
         escrow.temp_token_account_pubkey,
         token_account_keypair.pubkey()
     );
     assert_eq!(escrow.expected_amount, 1);
+    assert_eq!(escrow.created_at, escrow.updated_at);
+    assert_eq!(escrow.seller_token_account_pubkey, Pubkey::default());
+    assert_eq!(escrow.sequence, 0);
 
     let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
     let token_account = program_ctx
@@ -987,6 +1157,7 @@ async fn test_init_several_escrows() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
@@ -1012,6 +1183,7 @@ async fn test_init_several_escrows() {
         AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
         AccountMeta::new(spl_token::id(), false),
     ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
     transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
             program_id,
@@ -1041,14 +1213,14 @@ async fn test_init_several_escrows() {
         .expect("account not found");
     let escrow1 = state::Escrow::unpack_from_slice(&escrow1_account.data).unwrap();
     let escrow2 = state::Escrow::unpack_from_slice(&escrow2_account.data).unwrap();
-    assert_eq!(escrow1.is_initialized, true);
+    assert_eq!(escrow1.key, state::AccountKey::EscrowV1);
     assert_eq!(escrow1.initializer_pubkey, payer_keypair.pubkey());
     assert_eq!(
         escrow1.temp_token_account_pubkey,
         token_1_account_keypair.pubkey()
     );
     assert_eq!(escrow1.expected_amount, 1);
-    assert_eq!(escrow2.is_initialized, true);
+    assert_eq!(escrow2.key, state::AccountKey::EscrowV1);
     assert_eq!(escrow2.initializer_pubkey, payer_keypair.pubkey());
     assert_eq!(
         escrow2.temp_token_account_pubkey,
@@ -1095,11 +1267,12 @@ async fn test_process_escrow() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: price,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1141,8 +1314,8 @@ async fn test_process_escrow() {
     assert_eq!(
         state::Escrow::unpack_from_slice(&escrow_account.data)
             .unwrap()
-            .is_initialized,
-        true
+            .key,
+        state::AccountKey::EscrowV1
     );
 
     utils::create_mint(
@@ -1213,6 +1386,14 @@ async fn test_process_escrow() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1273,11 +1454,12 @@ async fn test_cancel_escrow() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1310,8 +1492,8 @@ async fn test_cancel_escrow() {
     assert_eq!(
         state::Escrow::unpack_from_slice(&escrow_account.data)
             .unwrap()
-            .is_initialized,
-        true
+            .key,
+        state::AccountKey::EscrowV1
     );
 
     utils::create_mint(
@@ -1385,6 +1567,14 @@ async fn test_cancel_escrow() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1439,11 +1629,12 @@ async fn test_process_escrow_token_account_mismatch() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: initializer_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1519,6 +1710,14 @@ async fn test_process_escrow_token_account_mismatch() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1557,11 +1756,12 @@ async fn test_process_escrow_initializer_account_mismatch() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1637,6 +1837,14 @@ async fn test_process_escrow_initializer_account_mismatch() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1675,11 +1883,12 @@ async fn test_process_escrow_sales_tax_account_mismatch() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1755,6 +1964,14 @@ async fn test_process_escrow_sales_tax_account_mismatch() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1793,11 +2010,12 @@ async fn test_process_escrow_expected_amount_mismatch() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -1873,6 +2091,14 @@ async fn test_process_escrow_expected_amount_mismatch() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -1912,11 +2138,12 @@ async fn test_process_escrow_missing_signature() {
     );
     let mut escrow_data = vec![0_u8; state::Escrow::LEN];
     let escrow_info = Escrow {
-        is_initialized: true,
+        key: state::AccountKey::EscrowV1,
         initializer_pubkey: initializer_keypair.pubkey(),
         mint_pubkey: mint_keypair.pubkey(),
         temp_token_account_pubkey: token_account_keypair.pubkey(),
         expected_amount: 1,
+        ..Escrow::default()
     };
     Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
     program_test.add_account(
@@ -2001,6 +2228,14 @@ async fn test_process_escrow_missing_signature() {
         AccountMeta::new(spl_token::id(), false),
         AccountMeta::new(solana_program::system_program::id(), false),
         AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
     ];
     let mut transaction = Transaction::new_with_payer(
         &[Instruction::new_with_bincode(
@@ -2022,3 +2257,3413 @@ async fn test_process_escrow_missing_signature() {
         TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
     );
 }
+
+async fn run_failing_sale(
+    marketplace: Option<state::Marketplace>,
+    metadata: Option<Account>,
+) -> TransactionError {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let price: u64 = 1000;
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    let escrow_info = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
+    program_test.add_account(
+        initializer_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        escrow_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: escrow_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        sales_tax_recipient_pubkey,
+        Account {
+            lamports: 1,
+            data: vec![],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (marketplace_pubkey, _) = state::get_marketplace_account(&program_id);
+    if let Some(marketplace) = marketplace {
+        let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+        state::Marketplace::pack(marketplace, &mut marketplace_data.borrow_mut()).unwrap();
+        program_test.add_account(
+            marketplace_pubkey,
+            Account {
+                lamports: 5000000,
+                data: marketplace_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    if let Some(metadata) = metadata {
+        program_test.add_account(utils::get_metadata_account(&mint_keypair.pubkey()), metadata);
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(
+        &mut program_ctx,
+        &mint_keypair,
+        100000000,
+        &payer_keypair.pubkey(),
+    )
+    .await
+    .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    utils::create_token_account(
+        &mut program_ctx,
+        &token_account_keypair,
+        &mint_keypair.pubkey(),
+        &pda,
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &taker_token_keypair,
+        &mint_keypair.pubkey(),
+        &payer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::mint_tokens_to(
+        &mut program_ctx,
+        &mint_keypair.pubkey(),
+        &token_account_keypair.pubkey(),
+        &payer_keypair,
+        price,
+    )
+    .await
+    .unwrap();
+
+    let accounts = vec![
+        AccountMeta::new(payer_keypair.pubkey(), true),
+        AccountMeta::new(taker_token_keypair.pubkey(), false),
+        AccountMeta::new(token_account_keypair.pubkey(), false),
+        AccountMeta::new(initializer_keypair.pubkey(), false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new(mint_keypair.pubkey(), false),
+        AccountMeta::new(utils::get_metadata_account(&mint_keypair.pubkey()), false),
+        AccountMeta::new(spl_token::id(), false),
+        AccountMeta::new(solana_program::system_program::id(), false),
+        AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(marketplace_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &[1u8, 232u8, 3u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
+            accounts,
+        )],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .err()
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_process_escrow_missing_metadata_when_required() {
+    let marketplace = state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap(),
+        require_metadata: true,
+        ..state::Marketplace::default()
+    };
+    assert_eq!(
+        run_failing_sale(Some(marketplace), None).await,
+        TransactionError::InstructionError(0, InstructionError::Custom(9))
+    );
+}
+
+#[tokio::test]
+async fn test_process_escrow_metadata_wrong_owner() {
+    let metadata = Account {
+        lamports: 5000000,
+        data: vec![4_u8; 679],
+        owner: Pubkey::new_unique(),
+        ..Account::default()
+    };
+    assert_eq!(
+        run_failing_sale(None, Some(metadata)).await,
+        TransactionError::InstructionError(0, InstructionError::Custom(18))
+    );
+}
+
+#[tokio::test]
+async fn test_process_escrow_corrupted_metadata() {
+    let metadata = Account {
+        lamports: 5000000,
+        data: vec![4_u8; 12],
+        owner: Pubkey::from_str(utils::METAPLEX).unwrap(),
+        ..Account::default()
+    };
+    assert_eq!(
+        run_failing_sale(None, Some(metadata)).await,
+        TransactionError::InstructionError(0, InstructionError::Custom(8))
+    );
+}
+
+#[tokio::test]
+async fn test_update_marketplace_wrong_authority() {
+    let program_id = Pubkey::new_unique();
+    let (marketplace_pubkey, _) = state::get_marketplace_account(&program_id);
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    let marketplace = state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap(),
+        require_metadata: false,
+        ..state::Marketplace::default()
+    };
+    state::Marketplace::pack(marketplace, &mut marketplace_data.borrow_mut()).unwrap();
+    program_test.add_account(
+        marketplace_pubkey,
+        Account {
+            lamports: 5000000,
+            data: marketplace_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    let accounts = vec![
+        AccountMeta::new(payer_keypair.pubkey(), true),
+        AccountMeta::new(marketplace_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &[3u8, 1u8],
+            accounts,
+        )],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(14))
+    );
+}
+
+#[tokio::test]
+async fn test_init_escrow_account_wrong_owner() {
+    let program_id = Pubkey::new_unique();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    program_test.add_account(
+        escrow_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![0_u8; state::Escrow::LEN],
+            owner: Pubkey::new_unique(),
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(
+        &mut program_ctx,
+        &mint_keypair,
+        100000000,
+        &payer_keypair.pubkey(),
+    )
+    .await
+    .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &token_account_keypair,
+        &mint_keypair.pubkey(),
+        &payer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::mint_tokens_to(
+        &mut program_ctx,
+        &mint_keypair.pubkey(),
+        &token_account_keypair.pubkey(),
+        &payer_keypair,
+        1,
+    )
+    .await
+    .unwrap();
+
+    let mut accounts = vec![
+        AccountMeta::new(payer_keypair.pubkey(), true),
+        AccountMeta::new(token_account_keypair.pubkey(), false),
+        AccountMeta::new(mint_keypair.pubkey(), false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new(solana_program::system_program::id(), false),
+        AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
+        AccountMeta::new(spl_token::id(), false),
+    ];
+    accounts.extend(utils::listing_accounts(&program_id, &mint_keypair.pubkey()));
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &[0u8, 1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
+            accounts,
+        )],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(15))
+    );
+}
+
+fn sample_metadata() -> metadata::Metadata {
+    metadata::Metadata {
+        key: metadata::Key::MetadataV1,
+        update_authority: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        data: metadata::Data {
+            name: "Sample".to_string(),
+            symbol: "SMPL".to_string(),
+            uri: "https://example.com/sample.json".to_string(),
+            seller_fee_basis_points: 500,
+            creators: Some(vec![metadata::Creator {
+                address: Pubkey::new_unique(),
+                verified: true,
+                share: 100,
+            }]),
+        },
+        primary_sale_happened: true,
+        is_mutable: true,
+        edition_nonce: None,
+        token_standard: None,
+        collection: None,
+        uses: None,
+        collection_details: None,
+        programmable_config: None,
+    }
+}
+
+#[tokio::test]
+async fn test_metadata_v1_layout() {
+    let md = sample_metadata();
+    let mut data = md.try_to_vec().unwrap();
+    // strip the six `None` tags of the newer fields, then zero-pad like the v1 program did
+    data.truncate(data.len() - 6);
+    data.resize(metadata::MAX_METADATA_LEN, 0);
+
+    let parsed = metadata::Metadata::from_u8(&data).unwrap();
+    assert_eq!(parsed.data, md.data);
+    assert_eq!(parsed.token_standard, None);
+    assert_eq!(parsed.collection, None);
+}
+
+#[tokio::test]
+async fn test_metadata_newer_layout() {
+    let mut md = sample_metadata();
+    md.edition_nonce = Some(254);
+    md.token_standard = Some(metadata::TokenStandard::ProgrammableNonFungible);
+    md.collection = Some(metadata::Collection {
+        verified: true,
+        key: Pubkey::new_unique(),
+    });
+    md.uses = Some(metadata::Uses {
+        use_method: metadata::UseMethod::Multiple,
+        remaining: 3,
+        total: 5,
+    });
+    md.collection_details = Some(metadata::CollectionDetails::V1 { size: 10 });
+    md.programmable_config = Some(metadata::ProgrammableConfig::V1 {
+        rule_set: Some(Pubkey::new_unique()),
+    });
+    let mut data = md.try_to_vec().unwrap();
+    data.resize(data.len() + 100, 0);
+
+    let parsed = metadata::Metadata::from_u8(&data).unwrap();
+    assert_eq!(parsed.edition_nonce, md.edition_nonce);
+    assert_eq!(parsed.token_standard, md.token_standard);
+    assert_eq!(parsed.collection, md.collection);
+    assert_eq!(parsed.uses, md.uses);
+    assert_eq!(parsed.collection_details, md.collection_details);
+    assert_eq!(parsed.programmable_config, md.programmable_config);
+}
+
+#[tokio::test]
+async fn test_metadata_garbage_after_known_fields() {
+    let mut md = sample_metadata();
+    md.token_standard = Some(metadata::TokenStandard::NonFungible);
+    let mut data = md.try_to_vec().unwrap();
+    // keep edition_nonce and token_standard, overwrite the rest with junk
+    data.truncate(data.len() - 4);
+    data.extend_from_slice(&[0xff; 16]);
+
+    let parsed = metadata::Metadata::from_u8(&data).unwrap();
+    assert_eq!(parsed.token_standard, Some(metadata::TokenStandard::NonFungible));
+    assert_eq!(parsed.collection, None);
+    assert_eq!(parsed.programmable_config, None);
+}
+
+#[tokio::test]
+async fn test_metadata_truncated_prefix() {
+    let data = sample_metadata().try_to_vec().unwrap();
+    let res = metadata::Metadata::from_u8(&data[..40]);
+    assert_eq!(
+        res.err().unwrap(),
+        ProgramError::Custom(error::EscrowError::InvalidMetadata as u32)
+    );
+}
+
+#[tokio::test]
+async fn test_programmable_nft_listing_and_sale() {
+    let program_id = Pubkey::new_unique();
+    let metaplex_id = Pubkey::from_str(utils::METAPLEX).unwrap();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let seller_token_keypair = Keypair::new();
+    let taker_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let master_edition_pubkey = Pubkey::new_unique();
+    let seller_token_record_pubkey = Pubkey::new_unique();
+    let taker_token_record_pubkey = Pubkey::new_unique();
+    let spl_ata_program_pubkey = Pubkey::new_unique();
+    let metadata_pubkey = utils::get_metadata_account(&mint_keypair.pubkey());
+    let price: u64 = 1_000_000;
+
+    let mut md = sample_metadata();
+    md.mint = mint_keypair.pubkey();
+    md.token_standard = Some(metadata::TokenStandard::ProgrammableNonFungible);
+    let creator_pubkey = md.data.creators.as_ref().unwrap()[0].address;
+    let mut metadata_data = md.try_to_vec().unwrap();
+    metadata_data.resize(metadata::MAX_METADATA_LEN, 0);
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    program_test.add_program(
+        "token_metadata_stub",
+        metaplex_id,
+        processor!(utils::process_token_metadata_stub),
+    );
+    program_test.add_account(
+        escrow_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![0_u8; state::Escrow::LEN],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        metadata_pubkey,
+        Account {
+            lamports: 5000000,
+            data: metadata_data,
+            owner: metaplex_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        taker_keypair.pubkey(),
+        Account {
+            lamports: 500000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        creator_pubkey,
+        Account {
+            lamports: 5000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(
+        &mut program_ctx,
+        &mint_keypair,
+        100000000,
+        &payer_keypair.pubkey(),
+    )
+    .await
+    .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &seller_token_keypair,
+        &mint_keypair.pubkey(),
+        &payer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &taker_token_keypair,
+        &mint_keypair.pubkey(),
+        &taker_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::mint_tokens_to(
+        &mut program_ctx,
+        &mint_keypair.pubkey(),
+        &seller_token_keypair.pubkey(),
+        &payer_keypair,
+        1,
+    )
+    .await
+    .unwrap();
+
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    let accounts = vec![
+        AccountMeta::new(payer_keypair.pubkey(), true),
+        AccountMeta::new(seller_token_keypair.pubkey(), false),
+        AccountMeta::new(mint_keypair.pubkey(), false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new(solana_program::system_program::id(), false),
+        AccountMeta::new(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
+        AccountMeta::new(spl_token::id(), false),
+        AccountMeta::new(metadata_pubkey, false),
+        AccountMeta::new_readonly(master_edition_pubkey, false),
+        AccountMeta::new(seller_token_record_pubkey, false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_policy_account(&program_id, &creator_pubkey).0, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &[0u8, 64u8, 66u8, 15u8, 0u8, 0u8, 0u8, 0u8, 0u8],
+            accounts,
+        )],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    // The token stays with the seller, delegated to the PDA
+    let seller_token_account = program_ctx
+        .banks_client
+        .get_account(seller_token_keypair.pubkey())
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    let token = spl_token::state::Account::unpack_from_slice(&seller_token_account.data).unwrap();
+    assert_eq!(token.owner, payer_keypair.pubkey());
+    assert_eq!(token.delegate, solana_program::program_option::COption::Some(pda));
+
+    let accounts = vec![
+        AccountMeta::new(taker_keypair.pubkey(), true),
+        AccountMeta::new(taker_token_keypair.pubkey(), false),
+        AccountMeta::new(seller_token_keypair.pubkey(), false),
+        AccountMeta::new(payer_keypair.pubkey(), false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new(mint_keypair.pubkey(), false),
+        AccountMeta::new(metadata_pubkey, false),
+        AccountMeta::new(spl_token::id(), false),
+        AccountMeta::new(solana_program::system_program::id(), false),
+        AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &mint_keypair.pubkey()).0, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_policy_account(&program_id, &creator_pubkey).0, false),
+        AccountMeta::new_readonly(master_edition_pubkey, false),
+        AccountMeta::new(seller_token_record_pubkey, false),
+        AccountMeta::new(taker_token_record_pubkey, false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(spl_ata_program_pubkey, false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new_readonly(metaplex_id, false),
+        AccountMeta::new(creator_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bincode(
+            program_id,
+            &[1u8, 1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8],
+            accounts,
+        )],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &taker_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let taker_token_account = program_ctx
+        .banks_client
+        .get_account(taker_token_keypair.pubkey())
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    let token = spl_token::state::Account::unpack_from_slice(&taker_token_account.data).unwrap();
+    assert_eq!(token.amount, 1);
+
+    let creator_account = program_ctx
+        .banks_client
+        .get_account(creator_pubkey)
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    assert_eq!(creator_account.lamports, 5000000 + price * 500 / 10000);
+}
+
+fn token_2022_mint_data(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut data = vec![0_u8; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        mint_authority: solana_program::program_option::COption::None,
+        supply: 1,
+        decimals: 0,
+        is_initialized: true,
+        freeze_authority: solana_program::program_option::COption::None,
+    }
+    .pack_into_slice(&mut data);
+    data.resize(spl_token::state::Account::LEN, 0);
+    // account type: mint
+    data.push(1);
+    for (extension_type, value) in extensions {
+        data.extend_from_slice(&extension_type.to_le_bytes());
+        data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+fn transfer_fee_config(older: (u64, u64, u16), newer: (u64, u64, u16)) -> Vec<u8> {
+    let mut value = vec![0_u8; 72];
+    for (epoch, maximum_fee, basis_points) in [older, newer].iter() {
+        value.extend_from_slice(&epoch.to_le_bytes());
+        value.extend_from_slice(&maximum_fee.to_le_bytes());
+        value.extend_from_slice(&basis_points.to_le_bytes());
+    }
+    value
+}
+
+#[tokio::test]
+async fn test_token_2022_transfer_fee() {
+    let key = Pubkey::new_unique();
+    let owner = token::token_2022_program_id();
+    let mut lamports = 0;
+    let mut data = token_2022_mint_data(&[(1, transfer_fee_config((0, 5, 100), (10, 1000, 5000)))]);
+    let mint = solana_program::account_info::AccountInfo::new(
+        &key, false, true, &mut lamports, &mut data, &owner, false, 0,
+    );
+
+    assert_eq!(token::unpack_mint(&mint).unwrap().supply, 1);
+
+    let older = token::get_transfer_fee(&mint, 9).unwrap();
+    assert_eq!(older.transfer_fee_basis_points, 100);
+    // rounds up, capped at the maximum fee
+    assert_eq!(older.calculate_fee(1), 1);
+    assert_eq!(older.calculate_fee(10000), 5);
+
+    let newer = token::get_transfer_fee(&mint, 10).unwrap();
+    assert_eq!(newer.transfer_fee_basis_points, 5000);
+    assert_eq!(newer.calculate_fee(100), 50);
+
+    // Token accounts of the mint hold the withheld amount
+    assert_eq!(token::get_account_len(&mint.data.borrow()), 178);
+    assert_eq!(token::get_account_len(&token_2022_mint_data(&[])), spl_token::state::Account::LEN);
+}
+
+#[tokio::test]
+async fn test_token_2022_metadata_extension() {
+    let key = Pubkey::new_unique();
+    let owner = token::token_2022_program_id();
+    let creator = Pubkey::new_unique();
+    let token_metadata = (
+        Pubkey::new_unique(),
+        key,
+        "Sample".to_string(),
+        "SMPL".to_string(),
+        "https://example.com/sample.json".to_string(),
+        vec![
+            ("royalty_basis_points".to_string(), "750".to_string()),
+            (creator.to_string(), "100".to_string()),
+            ("background".to_string(), "blue".to_string()),
+        ],
+    )
+        .try_to_vec()
+        .unwrap();
+    let mut lamports = 0;
+    let mut data = token_2022_mint_data(&[(19, token_metadata)]);
+    let mint = solana_program::account_info::AccountInfo::new(
+        &key, false, true, &mut lamports, &mut data, &owner, false, 0,
+    );
+
+    let md = token::get_token_metadata(&mint).unwrap().unwrap();
+    assert_eq!(md.mint, key);
+    assert_eq!(md.data.seller_fee_basis_points, 750);
+    let creators = md.data.creators.unwrap();
+    assert_eq!(creators.len(), 1);
+    assert_eq!(creators[0].address, creator);
+    assert_eq!(creators[0].share, 100);
+    assert!(token::get_transfer_fee(&mint, 0).is_none());
+
+    // Metadata Metaplex wouldn't accept is refused
+    let parse = |additional_metadata: Vec<(String, String)>| {
+        let token_metadata = (Pubkey::new_unique(), key, String::new(), String::new(), String::new(), additional_metadata)
+            .try_to_vec()
+            .unwrap();
+        let mut lamports = 0;
+        let mut data = token_2022_mint_data(&[(19, token_metadata)]);
+        let mint = solana_program::account_info::AccountInfo::new(&key, false, true, &mut lamports, &mut data, &owner, false, 0);
+        token::get_token_metadata(&mint).map(|md| md.unwrap().data)
+    };
+    let creator_shares = |shares: &[u8]| -> Vec<(String, String)> {
+        shares.iter().map(|share| (Pubkey::new_unique().to_string(), share.to_string())).collect()
+    };
+    assert_eq!(parse(creator_shares(&[50, 50])).unwrap().creators.unwrap().len(), 2);
+    assert_eq!(parse(creator_shares(&[20; 6])).unwrap_err(), ProgramError::Custom(8));
+    assert_eq!(parse(creator_shares(&[50, 49])).unwrap_err(), ProgramError::Custom(8));
+    assert_eq!(parse(creator_shares(&[200, 200])).unwrap_err(), ProgramError::Custom(8));
+    let royalties = |basis_points: &str| vec![("royalty_basis_points".to_string(), basis_points.to_string())];
+    assert_eq!(parse(royalties("10000")).unwrap().seller_fee_basis_points, 10000);
+    assert_eq!(parse(royalties("10001")).unwrap_err(), ProgramError::Custom(8));
+}
+
+#[tokio::test]
+async fn test_token_2022_unsupported_extensions() {
+    let key = Pubkey::new_unique();
+    let owner = token::token_2022_program_id();
+    let check = |extensions: &[(u16, Vec<u8>)]| {
+        let mut lamports = 0;
+        let mut data = token_2022_mint_data(extensions);
+        let mint = solana_program::account_info::AccountInfo::new(&key, false, true, &mut lamports, &mut data, &owner, false, 0);
+        token::check_mint_extensions(&mint)
+    };
+
+    assert_eq!(check(&[]), Ok(()));
+    assert_eq!(check(&[(1, transfer_fee_config((0, 5, 100), (10, 1000, 5000)))]), Ok(()));
+    // non-transferable, permanent delegate, transfer hook
+    for extension_type in [9_u16, 12, 14].iter() {
+        assert_eq!(
+            check(&[(1, transfer_fee_config((0, 5, 100), (10, 1000, 5000))), (*extension_type, vec![0; 32])]),
+            Err(ProgramError::Custom(35))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_compressed_nft_listing_and_sale() {
+    let program_id = Pubkey::new_unique();
+    let bubblegum_id = compression::bubblegum_program_id();
+    let escrow_keypair = Keypair::new();
+    let taker_keypair = Keypair::new();
+    let merkle_tree_pubkey = Pubkey::new_unique();
+    let tree_authority_pubkey = compression::get_tree_authority(&merkle_tree_pubkey);
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let proof_pubkey = Pubkey::new_unique();
+    let price: u64 = 1_000_000;
+
+    // The second creator is owed nothing, so the sale leaves its account out
+    let mut creators = sample_metadata().data.creators.unwrap();
+    creators.push(metadata::Creator {
+        address: Pubkey::new_unique(),
+        verified: false,
+        share: 0,
+    });
+    let creator_pubkey = creators[0].address;
+    let asset_id = compression::get_asset_id(&merkle_tree_pubkey, 7);
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    program_test.add_program(
+        "bubblegum_stub",
+        bubblegum_id,
+        processor!(utils::process_bubblegum_stub),
+    );
+    program_test.add_account(
+        escrow_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![0_u8; state::CompressedEscrow::LEN],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        merkle_tree_pubkey,
+        Account {
+            lamports: 5000000,
+            data: vec![0_u8; 64],
+            owner: bubblegum_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        taker_keypair.pubkey(),
+        Account {
+            lamports: 500000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        creator_pubkey,
+        Account {
+            lamports: 5000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    // The creator's policy raises the royalties from the leaf's 5% to 10%
+    let policy_pubkey = state::get_policy_account(&program_id, &creator_pubkey).0;
+    let mut policy_data = vec![0_u8; state::CreatorPolicy::LEN];
+    state::CreatorPolicy {
+        key: state::AccountKey::CreatorPolicy,
+        target: creator_pubkey,
+        creator_pubkey,
+        min_price: price,
+        royalty_floor_basis_points: 1000,
+        payment_mints: vec![],
+    }
+    .pack_into_slice(&mut policy_data);
+    program_test.add_account(
+        policy_pubkey,
+        Account {
+            lamports: 5000000,
+            data: policy_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    let mut data = vec![4u8];
+    data.extend((price, [1u8; 32], [2u8; 32], 500u16, 7u64, 3u32, creators.clone()).try_to_vec().unwrap());
+    let accounts = vec![
+        AccountMeta::new(payer_keypair.pubkey(), true),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new_readonly(payer_keypair.pubkey(), false),
+        AccountMeta::new_readonly(tree_authority_pubkey, false),
+        AccountMeta::new(merkle_tree_pubkey, false),
+        AccountMeta::new_readonly(compression::noop_program_id(), false),
+        AccountMeta::new_readonly(compression::account_compression_program_id(), false),
+        AccountMeta::new_readonly(bubblegum_id, false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &asset_id).0, false),
+        AccountMeta::new_readonly(policy_pubkey, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(proof_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &data, accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let tree = program_ctx
+        .banks_client
+        .get_account(merkle_tree_pubkey)
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    assert_eq!(tree.data[..32], pda.to_bytes());
+
+    let escrow = program_ctx
+        .banks_client
+        .get_account(escrow_keypair.pubkey())
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    let escrow = state::CompressedEscrow::unpack(&escrow.data).unwrap();
+    assert_eq!(escrow.initializer_pubkey, payer_keypair.pubkey());
+    assert_eq!(escrow.expected_amount, price);
+    assert_eq!(escrow.creators, creators);
+    assert_eq!(escrow.creator_hash, compression::hash_creators(&creators));
+
+    let mut data = vec![5u8];
+    data.extend_from_slice(&[1u8; 32]);
+    let accounts = vec![
+        AccountMeta::new(taker_keypair.pubkey(), true),
+        AccountMeta::new(payer_keypair.pubkey(), false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new(sales_tax_recipient_pubkey, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(tree_authority_pubkey, false),
+        AccountMeta::new(merkle_tree_pubkey, false),
+        AccountMeta::new_readonly(compression::noop_program_id(), false),
+        AccountMeta::new_readonly(compression::account_compression_program_id(), false),
+        AccountMeta::new_readonly(bubblegum_id, false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(state::get_access_account(&program_id, &asset_id).0, false),
+        AccountMeta::new_readonly(policy_pubkey, false),
+        AccountMeta::new(creator_pubkey, false),
+        AccountMeta::new_readonly(proof_pubkey, false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &data, accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &taker_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let tree = program_ctx
+        .banks_client
+        .get_account(merkle_tree_pubkey)
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    assert_eq!(tree.data[32..64], taker_keypair.pubkey().to_bytes());
+
+    let creator_account = program_ctx
+        .banks_client
+        .get_account(creator_pubkey)
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    assert_eq!(creator_account.lamports, 5000000 + price * 1000 / 10000);
+    assert!(program_ctx
+        .banks_client
+        .get_account(escrow_keypair.pubkey())
+        .await
+        .expect("get_account")
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_migrate_escrow() {
+    let program_id = Pubkey::new_unique();
+    let legacy_escrow_pubkey = Pubkey::new_unique();
+    let escrow_keypair = Keypair::new();
+    let initializer_keypair = Keypair::new();
+    let mint = Pubkey::new_unique();
+    let temp_token_account = Pubkey::new_unique();
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    program_test.add_account(
+        escrow_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![0_u8; state::Escrow::LEN],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        legacy_escrow_pubkey,
+        Account {
+            lamports: 5000000,
+            data: legacy_escrow_data(&initializer_keypair.pubkey(), &mint, &temp_token_account, 1000),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        initializer_keypair.pubkey(),
+        Account {
+            lamports: 5000000,
+            data: vec![],
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    let accounts = vec![
+        AccountMeta::new(initializer_keypair.pubkey(), true),
+        AccountMeta::new(legacy_escrow_pubkey, false),
+        AccountMeta::new(escrow_keypair.pubkey(), false),
+        AccountMeta::new_readonly(Pubkey::from_str(utils::RENT_ACCOUNT_STR).unwrap(), false),
+    ];
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[6u8], accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &initializer_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let escrow_account = program_ctx
+        .banks_client
+        .get_account(escrow_keypair.pubkey())
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    let escrow = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow_account.data[0], state::AccountKey::EscrowV1 as u8);
+    assert_eq!(escrow.initializer_pubkey, initializer_keypair.pubkey());
+    assert_eq!(escrow.mint_pubkey, mint);
+    assert_eq!(escrow.temp_token_account_pubkey, temp_token_account);
+    assert_eq!(escrow.expected_amount, 1000);
+    assert!(program_ctx
+        .banks_client
+        .get_account(legacy_escrow_pubkey)
+        .await
+        .expect("get_account")
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_update_price() {
+    let program_id = Pubkey::new_unique();
+    let escrow_pubkey = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint = Pubkey::new_unique();
+    // The mint has no metadata, so no creator policy applies
+    let price_accounts = vec![
+        AccountMeta::new_readonly(utils::get_metadata_account(&mint), false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(program_id, false),
+        AccountMeta::new_readonly(state::get_marketplace_account(&program_id).0, false),
+    ];
+
+    let mut program_test = ProgramTest::new(
+        "escrow", // Run the BPF version with `cargo test-bpf`
+        program_id,
+        processor!(processor::Processor::process), // Run the native version with `cargo test`
+    );
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    let escrow_info = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint,
+        temp_token_account_pubkey: Pubkey::new_unique(),
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+    Escrow::pack(escrow_info, &mut escrow_data.borrow_mut()).unwrap();
+    program_test.add_account(
+        escrow_pubkey,
+        Account {
+            lamports: 5000000,
+            data: escrow_data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    // Only the initializer can change the price
+    let mut accounts = vec![
+        AccountMeta::new_readonly(payer_keypair.pubkey(), true),
+        AccountMeta::new(escrow_pubkey, false),
+    ];
+    accounts.extend(price_accounts.clone());
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[7u8, 1, 0, 0, 0, 0, 0, 0, 0], accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(initializer_keypair.pubkey(), true),
+        AccountMeta::new(escrow_pubkey, false),
+    ];
+    accounts.extend(price_accounts);
+    let mut transaction = Transaction::new_with_payer(
+        &[Instruction::new_with_bytes(program_id, &[7u8, 0xd0, 0x07, 0, 0, 0, 0, 0, 0], accounts)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &initializer_keypair], program_ctx.last_blockhash);
+    program_ctx
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let escrow_account = program_ctx
+        .banks_client
+        .get_account(escrow_pubkey)
+        .await
+        .expect("get_account")
+        .expect("account not found");
+    let escrow = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow.expected_amount, 2000);
+    assert_eq!(escrow.sequence, 1);
+}
+
+#[tokio::test]
+async fn test_decode_events() {
+    let program_id = Pubkey::new_unique();
+    let other_program_id = Pubkey::new_unique();
+    let escrow = Pubkey::new_unique();
+    let seller = Pubkey::new_unique();
+    let creator = Pubkey::new_unique();
+
+    let sale = events::EscrowEvent::Sale {
+        escrow,
+        seller,
+        buyer: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        price: 1000,
+        sales_tax: 25,
+        royalties: vec![events::RoyaltyPayment {
+            creator,
+            amount: 50,
+        }],
+        seller_proceeds: 925,
+        fee_mode: state::FeeMode::SellerPaid,
+    };
+    let cancelled = events::EscrowEvent::ListingCancelled {
+        escrow,
+        seller,
+        mint: Pubkey::new_unique(),
+    };
+    let data = |event: &events::EscrowEvent| base64::encode(event.try_to_vec().unwrap());
+
+    let logs = vec![
+        format!("Program {} invoke [1]", program_id),
+        "Program log: Instruction: Exchange".to_string(),
+        format!("Program {} invoke [2]", other_program_id),
+        // Logged by another program, even though it decodes
+        format!("Program data: {}", data(&cancelled)),
+        format!("Program {} success", other_program_id),
+        format!("Program log: Program data: {}", data(&sale)),
+        "Program log: Program data: not base64".to_string(),
+        format!("Program {} consumed 12345 of 200000 compute units", program_id),
+        format!("Program {} success", program_id),
+        format!("Program {} invoke [1]", program_id),
+        format!("Program data: {}", data(&cancelled)),
+        format!("Program {} success", program_id),
+    ];
+    assert_eq!(
+        events::decode_events(&program_id, &logs),
+        vec![sale, cancelled]
+    );
+}
+
+#[tokio::test]
+async fn test_client_exchange_accounts() {
+    let program_id = Pubkey::new_unique();
+    let taker = Pubkey::new_unique();
+    let taker_token_account = Pubkey::new_unique();
+    let escrow_pubkey = Pubkey::new_unique();
+    let mut md = sample_metadata();
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: Pubkey::new_unique(),
+        mint_pubkey: md.mint,
+        temp_token_account_pubkey: Pubkey::new_unique(),
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+    let creators = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+    md.data.creators = Some(
+        creators
+            .iter()
+            .map(|address| metadata::Creator {
+                address: *address,
+                verified: false,
+                share: 50,
+            })
+            .collect(),
+    );
+
+    let ix = client::exchange(
+        &program_id,
+        &taker,
+        &taker_token_account,
+        &escrow_pubkey,
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    assert_eq!(ix.data, vec![15, 1, 0, 0, 0, 0, 0, 0, 0, 0xe8, 0x03, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(ix.accounts.len(), 19 + 2);
+    assert_eq!(ix.accounts[3].pubkey, escrow.initializer_pubkey);
+    assert_eq!(ix.accounts[7].pubkey, utils::get_metadata_account(&md.mint));
+    assert!(!ix.accounts[7].is_writable);
+    assert!(ix.accounts[19].is_writable);
+    assert!(ix.accounts[20].is_writable);
+    assert_eq!(ix.accounts[10].pubkey, Pubkey::find_program_address(&[b"escrow"], &program_id).0);
+    assert_eq!(ix.accounts[11].pubkey, state::get_marketplace_account(&program_id).0);
+    assert_eq!(ix.accounts[12].pubkey, program_id);
+    assert_eq!(ix.accounts[13].pubkey, program_id);
+    assert_eq!(ix.accounts[14].pubkey, program_id);
+    assert_eq!(ix.accounts[15].pubkey, state::get_access_account(&program_id, &md.mint).0);
+    assert_eq!(ix.accounts[16].pubkey, program_id);
+    assert_eq!(ix.accounts[17].pubkey, program_id);
+    assert_eq!(ix.accounts[18].pubkey, program_id);
+    assert_eq!(ix.accounts[19].pubkey, creators[0]);
+    assert_eq!(ix.accounts[20].pubkey, creators[1]);
+
+    // Programmable NFTs get their extra accounts before the creators
+    md.token_standard = Some(metadata::TokenStandard::ProgrammableNonFungible);
+    let ix = client::exchange(
+        &program_id,
+        &taker,
+        &taker_token_account,
+        &escrow_pubkey,
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    assert_eq!(ix.accounts.len(), 19 + 8 + 2);
+    assert_eq!(ix.accounts[7].pubkey, utils::get_metadata_account(&md.mint));
+    assert!(ix.accounts[7].is_writable);
+    assert_eq!(ix.accounts[22].pubkey, metadata::metaplex_program_id());
+    assert_eq!(ix.accounts[27].pubkey, creators[0]);
+
+    // Verified collections and creators get their access list entry and creator policies
+    md.data.creators.as_mut().unwrap()[1].verified = true;
+    let collection = Pubkey::new_unique();
+    md.collection = Some(metadata::Collection { verified: true, key: collection });
+    let ix = client::exchange(
+        &program_id,
+        &taker,
+        &taker_token_account,
+        &escrow_pubkey,
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    assert_eq!(ix.accounts[16].pubkey, state::get_access_account(&program_id, &collection).0);
+    assert_eq!(ix.accounts[17].pubkey, state::get_policy_account(&program_id, &collection).0);
+    assert_eq!(ix.accounts[18].pubkey, state::get_policy_account(&program_id, &creators[1]).0);
+}
+
+fn sample_instructions() -> Vec<instruction::EscrowInstruction> {
+    use instruction::EscrowInstruction::*;
+    vec![
+        InitEscrow { amount: 1000 },
+        Exchange { amount: u64::MAX },
+        InitMarketplace { require_metadata: true },
+        UpdateMarketplace { require_metadata: false },
+        InitCompressedEscrow {
+            amount: 1,
+            root: [1; 32],
+            metadata_args_hash: [2; 32],
+            seller_fee_basis_points: 500,
+            nonce: 7,
+            index: 3,
+            creators: sample_metadata().data.creators.unwrap(),
+        },
+        ExchangeCompressed { root: [3; 32] },
+        MigrateEscrow,
+        UpdatePrice { amount: 0 },
+        InitTreasury {
+            authority: Pubkey::new_from_array([4; 32]),
+            period_seconds: 86400,
+        },
+        WithdrawFees { amount: 1 },
+        SetPaused { paused: true },
+        SetAccess {
+            target: Pubkey::new_from_array([5; 32]),
+            mode: state::AccessMode::Denied,
+        },
+        ForceDelist,
+        SetCreatorPolicy {
+            target: Pubkey::new_from_array([6; 32]),
+            min_price: 1000,
+            royalty_floor_basis_points: 500,
+            payment_mints: vec![Pubkey::new_from_array([7; 32])],
+        },
+        ForceDelistCompressed { root: [8; 32] },
+        ExchangeV2 {
+            amount: 1,
+            expected_price: 1000,
+        },
+        UpdateMarketplaceV2 {
+            require_metadata: false,
+            referral_basis_points: 100,
+            fee_mode: state::FeeMode::BuyerPaid,
+            fee_tiers: vec![state::FeeTier {
+                token: Pubkey::new_from_array([3; 32]),
+                sales_tax_basis_points: 100,
+                listing_fee: 0,
+            }],
+            refundable_listing_fee: true,
+            deposit_forfeit_seconds: 60,
+            allow_list_only: true,
+        },
+    ]
+}
+
+#[tokio::test]
+async fn test_instruction_round_trip() {
+    for (tag, ix) in sample_instructions().into_iter().enumerate() {
+        let data = ix.pack();
+        assert_eq!(data[0] as usize, tag);
+        assert_eq!(instruction::EscrowInstruction::unpack(&data).unwrap(), ix);
+
+        // Trailing garbage and truncated input are rejected
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert_eq!(
+            instruction::EscrowInstruction::unpack(&trailing).err().unwrap(),
+            ProgramError::Custom(0)
+        );
+        if data.len() > 1 {
+            assert_eq!(
+                instruction::EscrowInstruction::unpack(&data[..data.len() - 1]).err().unwrap(),
+                ProgramError::Custom(0)
+            );
+        }
+    }
+
+    // The layout clients relied on before `pack` existed
+    assert_eq!(
+        instruction::EscrowInstruction::InitEscrow { amount: 1000 }.pack(),
+        vec![0u8, 232u8, 3u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8]
+    );
+    assert_eq!(
+        instruction::EscrowInstruction::UpdateMarketplace { require_metadata: true }.pack(),
+        vec![3u8, 1u8]
+    );
+    assert_eq!(
+        instruction::EscrowInstruction::UpdateMarketplaceV2 {
+            require_metadata: true,
+            referral_basis_points: 250,
+            fee_mode: state::FeeMode::BuyerPaid,
+            fee_tiers: vec![],
+            refundable_listing_fee: true,
+            deposit_forfeit_seconds: 3600,
+            allow_list_only: false,
+        }
+        .pack(),
+        vec![16u8, 1u8, 250u8, 0u8, 1u8, 0u8, 0u8, 0u8, 0u8, 1u8, 16u8, 14u8, 0u8, 0u8, 0u8]
+    );
+}
+
+#[tokio::test]
+async fn test_instruction_fuzz() {
+    // xorshift64, so failures are reproducible
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let samples = sample_instructions();
+    for _ in 0..10000 {
+        // Mutate a valid encoding, or start from random bytes
+        let mut data = if next() % 2 == 0 {
+            samples[next() as usize % samples.len()].pack()
+        } else {
+            // Every tag, and a few past the last one
+            vec![(next() % (samples.len() as u64 + 4)) as u8]
+        };
+        for _ in 0..next() % 4 {
+            match next() % 3 {
+                0 if !data.is_empty() => {
+                    let i = next() as usize % data.len();
+                    data[i] = next() as u8;
+                }
+                1 => data.push(next() as u8),
+                _ => {
+                    data.pop();
+                }
+            }
+        }
+
+        // Whatever unpacks has to pack back to the exact same bytes, unknown tags never unpack
+        if let Ok(ix) = instruction::EscrowInstruction::unpack(&data) {
+            assert_eq!(ix.pack(), data);
+            assert!((data[0] as usize) < samples.len());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_fee_quote() {
+    let mut md = sample_metadata();
+    let first = Pubkey::new_unique();
+    let second = Pubkey::new_unique();
+    md.data.creators = Some(vec![
+        metadata::Creator { address: first, verified: true, share: 60 },
+        metadata::Creator { address: second, verified: false, share: 40 },
+    ]);
+    let mut escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+
+    let quote = fees::quote_escrow(&escrow, Some(&md), &fees::SaleTerms::default()).unwrap();
+    assert_eq!(quote.price, 1000);
+    assert_eq!(quote.sales_tax, 25);
+    assert_eq!(quote.referral_fee, 0);
+    assert_eq!(
+        quote.royalties,
+        vec![
+            events::RoyaltyPayment { creator: first, amount: 30 },
+            events::RoyaltyPayment { creator: second, amount: 20 },
+        ]
+    );
+    assert_eq!(quote.seller_proceeds, 925);
+    assert_eq!(quote.total, 1000);
+
+    // The buyer pays the fees on top of the price, the seller gets all of it
+    let buyer_paid = fees::SaleTerms {
+        fee_mode: state::FeeMode::BuyerPaid,
+        ..fees::SaleTerms::default()
+    };
+    let quote = fees::quote_escrow(&escrow, Some(&md), &buyer_paid).unwrap();
+    assert_eq!(quote.fee_mode, state::FeeMode::BuyerPaid);
+    assert_eq!((quote.sales_tax, quote.seller_proceeds, quote.total), (25, 1000, 1075));
+
+    // Without metadata, only the sales tax is due
+    let quote = fees::quote_escrow(&escrow, None, &fees::SaleTerms::default()).unwrap();
+    assert!(quote.royalties.is_empty());
+    assert_eq!(quote.seller_proceeds, 975);
+
+    md.data.seller_fee_basis_points = 9800;
+    assert_eq!(
+        fees::quote_escrow(&escrow, Some(&md), &fees::SaleTerms::default()).unwrap_err(),
+        ProgramError::Custom(11)
+    );
+
+    escrow.expected_amount = 0;
+    assert_eq!(
+        fees::quote_escrow(&escrow, None, &fees::SaleTerms::default()).unwrap_err(),
+        ProgramError::Custom(10)
+    );
+
+    escrow.expected_amount = u64::MAX;
+    assert_eq!(
+        fees::quote_escrow(&escrow, None, &fees::SaleTerms::default()).unwrap_err(),
+        ProgramError::Custom(3)
+    );
+}
+
+#[tokio::test]
+async fn test_indexer_order_book() {
+    let program_id = Pubkey::new_unique();
+    let seller = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let collection = Pubkey::new_unique();
+    let (escrow, legacy_escrow, migrated_escrow) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+
+    let mut escrow_data = vec![0u8; Escrow::LEN];
+    Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: seller,
+        mint_pubkey: mint,
+        expected_amount: 2000,
+        sequence: 3,
+        ..Escrow::default()
+    }
+    .pack_into_slice(&mut escrow_data);
+    let legacy_data = legacy_escrow_data(&Pubkey::new_unique(), &mint, &Pubkey::new_unique(), 1000);
+
+    // The filters select the accounts as getProgramAccounts would
+    let matches = |filters: &[indexer::Filter], data: &[u8]| filters.iter().all(|f| f.matches(data));
+    assert!(indexer::seller_filters(&seller).iter().any(|f| matches(f, &escrow_data)));
+    assert!(!indexer::seller_filters(&seller).iter().any(|f| matches(f, &legacy_data)));
+    assert!(indexer::mint_filters(&mint).iter().all(|f| matches(f, &escrow_data) || matches(f, &legacy_data)));
+
+    let mut book = indexer::OrderBook::new();
+    assert!(book.load_account(&escrow, &escrow_data));
+    assert!(book.load_account(&legacy_escrow, &legacy_data));
+    assert!(!book.load_account(&Pubkey::new_unique(), &[0u8; Escrow::LEN]));
+    assert!(!book.load_account(&Pubkey::new_unique(), &[0u8; state::Marketplace::LEN]));
+    assert_eq!(book.len(), 2);
+    let prices = |listings: Vec<&indexer::Listing>| listings.iter().map(|l| l.price).collect::<Vec<_>>();
+    assert_eq!(prices(book.by_mint(&mint)), vec![1000, 2000]);
+    assert_eq!(prices(book.by_seller(&seller)), vec![2000]);
+    assert!(book.by_collection(&collection).is_empty());
+
+    // Joining the metadata indexes the mint's listings by its verified collection
+    let mut md = sample_metadata();
+    md.mint = mint;
+    md.collection = Some(metadata::Collection { verified: true, key: collection });
+    book.add_metadata(md);
+    assert_eq!(prices(book.by_collection(&collection)), vec![1000, 2000]);
+
+    let logs = |events: &[events::EscrowEvent]| -> Vec<String> {
+        let mut logs = vec![format!("Program {} invoke [1]", program_id)];
+        for event in events {
+            logs.push(format!("Program data: {}", base64::encode(event.try_to_vec().unwrap())));
+        }
+        logs.push(format!("Program {} success", program_id));
+        logs
+    };
+    book.apply_logs(
+        &program_id,
+        &logs(&[
+            // Older than the loaded account, skipped
+            events::EscrowEvent::PriceUpdated { escrow, old_price: 1, new_price: 2, sequence: 2 },
+            events::EscrowEvent::PriceUpdated { escrow, old_price: 2000, new_price: 500, sequence: 4 },
+            events::EscrowEvent::ListingMigrated { legacy_escrow, escrow: migrated_escrow },
+        ]),
+    );
+    assert_eq!(prices(book.by_mint(&mint)), vec![500, 1000]);
+    assert!(book.get(&legacy_escrow).is_none());
+    assert_eq!(book.get(&migrated_escrow).unwrap().price, 1000);
+
+    // Reloading an account older than the events keeps the newer state
+    book.load_account(&escrow, &escrow_data);
+    assert_eq!(book.get(&escrow).unwrap().price, 500);
+
+    book.apply_logs(
+        &program_id,
+        &logs(&[
+            events::EscrowEvent::Sale {
+                escrow,
+                seller,
+                buyer: Pubkey::new_unique(),
+                mint,
+                price: 500,
+                sales_tax: 12,
+                royalties: vec![],
+                seller_proceeds: 488,
+                fee_mode: state::FeeMode::SellerPaid,
+            },
+            events::EscrowEvent::ListingCancelled { escrow: migrated_escrow, seller, mint },
+        ]),
+    );
+    assert!(book.is_empty());
+    assert!(book.by_mint(&mint).is_empty());
+    assert!(book.by_collection(&collection).is_empty());
+
+    let new_escrow = Pubkey::new_unique();
+    book.apply_event(&events::EscrowEvent::ListingCreated { escrow: new_escrow, seller, mint, price: 700 });
+    assert_eq!(prices(book.by_collection(&collection)), vec![700]);
+}
+
+#[tokio::test]
+async fn test_referral_fee() {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let referrer = Pubkey::new_unique();
+    let authority_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let price: u64 = 1000;
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        referral_basis_points: 100,
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (sales_tax_recipient_pubkey, vec![], solana_program::system_program::id()),
+        (referrer, vec![], solana_program::system_program::id()),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    utils::create_token_account(&mut program_ctx, &token_account_keypair, &mint_keypair.pubkey(), &pda, &Rent::default())
+        .await
+        .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &taker_token_keypair,
+        &mint_keypair.pubkey(),
+        &payer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account_keypair.pubkey(), &payer_keypair, 1)
+        .await
+        .unwrap();
+
+    let exchange = |referrer: &Pubkey| {
+        client::exchange(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &taker_token_keypair.pubkey(),
+            &escrow_keypair.pubkey(),
+            &escrow,
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            None,
+            Some(referrer),
+            None,
+            1,
+        )
+    };
+
+    // The taker can't refer themselves
+    let mut transaction = Transaction::new_with_payer(&[exchange(&payer_keypair.pubkey())], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(21))
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[exchange(&referrer)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    // The buyer pays the same sales tax, 2.5%, of which 1% goes to the referrer
+    let lamports = |account: Option<Account>| account.unwrap().lamports;
+    assert_eq!(lamports(program_ctx.banks_client.get_account(referrer).await.unwrap()), 5000000 + 10);
+    assert_eq!(
+        lamports(program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap()),
+        5000000 + 15
+    );
+
+    let referred = |referral_basis_points| fees::SaleTerms {
+        referral_basis_points,
+        ..fees::SaleTerms::default()
+    };
+    let quote = fees::quote_escrow(&escrow, None, &referred(100)).unwrap();
+    assert_eq!((quote.sales_tax, quote.referral_fee, quote.seller_proceeds), (25, 10, 975));
+    assert_eq!(fees::quote_escrow(&escrow, None, &referred(251)).unwrap_err(), ProgramError::Custom(20));
+
+    // The marketplace can't give away more than the sales tax
+    let ix = client::update_marketplace(
+        &program_id,
+        &authority_keypair.pubkey(),
+        false,
+        251,
+        state::FeeMode::SellerPaid,
+        vec![],
+        false,
+        0,
+        false,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(20))
+    );
+
+    // The original UpdateMarketplace only sets whether metadata is required
+    let marketplace_pubkey = state::get_marketplace_account(&program_id).0;
+    let get_marketplace = |account: Option<Account>| state::Marketplace::unpack_from_slice(&account.unwrap().data).unwrap();
+    let before = get_marketplace(program_ctx.banks_client.get_account(marketplace_pubkey).await.unwrap());
+    let ix = Instruction::new_with_bytes(
+        program_id,
+        &instruction::EscrowInstruction::UpdateMarketplace { require_metadata: true }.pack(),
+        vec![
+            AccountMeta::new_readonly(authority_keypair.pubkey(), true),
+            AccountMeta::new(marketplace_pubkey, false),
+        ],
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let after = get_marketplace(program_ctx.banks_client.get_account(marketplace_pubkey).await.unwrap());
+    assert_eq!(
+        after,
+        state::Marketplace {
+            require_metadata: true,
+            ..before
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_fee_tiers() {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let membership_mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let membership_token_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let price: u64 = 1000;
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let membership_tier = state::FeeTier {
+        token: membership_mint_keypair.pubkey(),
+        sales_tax_basis_points: 100,
+        listing_fee: 0,
+    };
+    let marketplace = state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        referral_basis_points: 200,
+        fee_tiers: vec![membership_tier.clone()],
+        ..state::Marketplace::default()
+    };
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    marketplace.pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (sales_tax_recipient_pubkey, vec![], solana_program::system_program::id()),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    for mint in [&mint_keypair, &membership_mint_keypair] {
+        utils::create_mint(&mut program_ctx, mint, 100000000, &payer_keypair.pubkey())
+            .await
+            .unwrap();
+    }
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (mint, token_account, owner) in vec![
+        (&mint_keypair, &token_account_keypair, pda),
+        (&mint_keypair, &taker_token_keypair, payer_keypair.pubkey()),
+        (&membership_mint_keypair, &membership_token_keypair, payer_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+    }
+    utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account_keypair.pubkey(), &payer_keypair, 1)
+        .await
+        .unwrap();
+    utils::mint_tokens_to(
+        &mut program_ctx,
+        &membership_mint_keypair.pubkey(),
+        &membership_token_keypair.pubkey(),
+        &payer_keypair,
+        1,
+    )
+    .await
+    .unwrap();
+
+    let exchange = |proof: &client::DiscountProof| {
+        client::exchange(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &taker_token_keypair.pubkey(),
+            &escrow_keypair.pubkey(),
+            &escrow,
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            None,
+            None,
+            Some(proof),
+            1,
+        )
+    };
+
+    // An empty token account proves nothing
+    let empty_proof = client::DiscountProof {
+        token_account: taker_token_keypair.pubkey(),
+        metadata: None,
+    };
+    let mut transaction = Transaction::new_with_payer(&[exchange(&empty_proof)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(23))
+    );
+
+    // Members pay a sales tax of 1% instead of 2.5%
+    let membership_proof = client::DiscountProof {
+        token_account: membership_token_keypair.pubkey(),
+        metadata: None,
+    };
+    let mut transaction = Transaction::new_with_payer(&[exchange(&membership_proof)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let sales_tax_account = program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().unwrap();
+    assert_eq!(sales_tax_account.lamports, 5000000 + 10);
+
+    // The cheapest matching tier applies, and the referrer's cut stays within it
+    let collection = Pubkey::new_unique();
+    let collection_tier = state::FeeTier {
+        token: collection,
+        sales_tax_basis_points: 50,
+        listing_fee: 1000,
+    };
+    let marketplace = state::Marketplace {
+        fee_tiers: vec![membership_tier.clone(), collection_tier.clone()],
+        ..marketplace
+    };
+    let mint = membership_mint_keypair.pubkey();
+    assert_eq!(fees::find_fee_tier(&marketplace, &mint, None), Some(&membership_tier));
+    assert_eq!(fees::find_fee_tier(&marketplace, &mint, Some(&collection)), Some(&collection_tier));
+    assert_eq!(fees::find_fee_tier(&marketplace, &Pubkey::new_unique(), None), None);
+    let terms = fees::SaleTerms::new(&marketplace, true, Some(&collection_tier));
+    assert_eq!((terms.sales_tax_basis_points, terms.referral_basis_points), (50, 50));
+    assert_eq!(fees::listing_fee(Some(&collection_tier)), 1000);
+    assert_eq!(fees::listing_fee(None), utils::LISTING_FEE);
+
+    // Tiers only lower the fees
+    let ix = client::update_marketplace(
+        &program_id,
+        &authority_keypair.pubkey(),
+        false,
+        0,
+        state::FeeMode::SellerPaid,
+        vec![state::FeeTier {
+            sales_tax_basis_points: 300,
+            ..membership_tier
+        }],
+        false,
+        0,
+        false,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(22))
+    );
+}
+
+#[tokio::test]
+async fn test_refundable_listing_fee() {
+    let program_id = Pubkey::new_unique();
+    let seller_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let old_escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let old_token_account_keypair = Keypair::new();
+    let seller_token_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        refundable_listing_fee: true,
+        deposit_forfeit_seconds: 3600,
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    // A listing with a deposit, up since the epoch
+    let old_escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: seller_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: old_token_account_keypair.pubkey(),
+        expected_amount: 1000,
+        listing_deposit: utils::LISTING_FEE,
+        ..Escrow::default()
+    };
+    let mut old_escrow_data = vec![0_u8; state::Escrow::LEN];
+    old_escrow.pack_into_slice(&mut old_escrow_data);
+    for (pubkey, lamports, data, owner) in vec![
+        (seller_keypair.pubkey(), 1000000000, vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), 5000000, vec![0_u8; state::Escrow::LEN], program_id),
+        (old_escrow_keypair.pubkey(), 5000000 + utils::LISTING_FEE, old_escrow_data, program_id),
+        (state::get_marketplace_account(&program_id).0, 5000000, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (token_account, owner) in vec![
+        (&token_account_keypair, seller_keypair.pubkey()),
+        (&old_token_account_keypair, pda),
+        (&seller_token_keypair, seller_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint_keypair.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+    }
+    for token_account in [&token_account_keypair, &old_token_account_keypair] {
+        utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account.pubkey(), &payer_keypair, 1)
+            .await
+            .unwrap();
+    }
+
+    let ix = client::init_escrow(
+        &program_id,
+        &seller_keypair.pubkey(),
+        &token_account_keypair.pubkey(),
+        &mint_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1000,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &seller_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    // The listing fee is held by the escrow account
+    let escrow_account = program_ctx.banks_client.get_account(escrow_keypair.pubkey()).await.unwrap().unwrap();
+    assert_eq!(escrow_account.lamports, 5000000 + utils::LISTING_FEE);
+    let escrow = Escrow::unpack_from_slice(&escrow_account.data).unwrap();
+    assert_eq!(escrow.listing_deposit, utils::LISTING_FEE);
+    assert!(program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().is_none());
+
+    let cancel = |escrow_pubkey: &Pubkey, escrow: &Escrow| {
+        client::exchange(
+            &program_id,
+            &seller_keypair.pubkey(),
+            &seller_token_keypair.pubkey(),
+            escrow_pubkey,
+            escrow,
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            None,
+            None,
+            None,
+            1,
+        )
+    };
+
+    // Cancelled right away, the deposit is refunded with the rent
+    let seller_before = program_ctx.banks_client.get_account(seller_keypair.pubkey()).await.unwrap().unwrap();
+    let mut transaction = Transaction::new_with_payer(&[cancel(&escrow_keypair.pubkey(), &escrow)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &seller_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let seller_after = program_ctx.banks_client.get_account(seller_keypair.pubkey()).await.unwrap().unwrap();
+    assert!(seller_after.lamports >= seller_before.lamports + 5000000 + utils::LISTING_FEE);
+    assert!(program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().is_none());
+
+    // Cancelled after the forfeit period, the deposit goes to the sales tax recipient
+    let mut transaction = Transaction::new_with_payer(
+        &[cancel(&old_escrow_keypair.pubkey(), &old_escrow)],
+        Some(&payer_keypair.pubkey()),
+    );
+    transaction.sign(&[&payer_keypair, &seller_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let sales_tax_account = program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().unwrap();
+    assert_eq!(sales_tax_account.lamports, utils::LISTING_FEE);
+}
+#[tokio::test]
+async fn test_treasury_period_rollover() {
+    let mut treasury = state::Treasury {
+        period_seconds: 100,
+        current_period: state::FeePeriod {
+            start: 1000,
+            sales_tax: 5,
+            ..state::FeePeriod::default()
+        },
+        ..state::Treasury::default()
+    };
+    treasury.roll_period(1099);
+    assert_eq!(treasury.current_period.sales_tax, 5);
+
+    // Periods stay aligned on the first one, skipping those without activity
+    treasury.roll_period(1350);
+    assert_eq!(treasury.previous_period.start, 1000);
+    assert_eq!(treasury.previous_period.sales_tax, 5);
+    assert_eq!(treasury.current_period.start, 1300);
+    assert_eq!(treasury.current_period.sales_tax, 0);
+
+    // A single period never rolls over
+    treasury.period_seconds = 0;
+    treasury.roll_period(i64::MAX);
+    assert_eq!(treasury.current_period.start, 1300);
+}
+
+#[tokio::test]
+async fn test_treasury() {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let new_escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+    let treasury_authority_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let treasury_pubkey = state::get_treasury_account(&program_id).0;
+    let price: u64 = 1000;
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (authority_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (new_escrow_keypair.pubkey(), vec![0_u8; state::Escrow::LEN], program_id),
+        (sales_tax_recipient_pubkey, vec![], solana_program::system_program::id()),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    utils::create_token_account(&mut program_ctx, &token_account_keypair, &mint_keypair.pubkey(), &pda, &Rent::default())
+        .await
+        .unwrap();
+    utils::create_token_account(
+        &mut program_ctx,
+        &taker_token_keypair,
+        &mint_keypair.pubkey(),
+        &payer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account_keypair.pubkey(), &payer_keypair, 1)
+        .await
+        .unwrap();
+
+    let ix = client::init_treasury(&program_id, &authority_keypair.pubkey(), &treasury_authority_keypair.pubkey(), 86400);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    let marketplace_account = program_ctx
+        .banks_client
+        .get_account(state::get_marketplace_account(&program_id).0)
+        .await
+        .unwrap()
+        .unwrap();
+    let marketplace = state::Marketplace::unpack_from_slice(&marketplace_account.data).unwrap();
+    assert!(marketplace.has_treasury);
+    assert_eq!(client::fee_recipient(&program_id, &marketplace), treasury_pubkey);
+
+    let exchange = |fee_recipient: &Pubkey| {
+        client::exchange(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &taker_token_keypair.pubkey(),
+            &escrow_keypair.pubkey(),
+            &escrow,
+            fee_recipient,
+            &spl_token::id(),
+            None,
+            None,
+            None,
+            1,
+        )
+    };
+
+    // Fees can't go to the sales tax recipient anymore
+    let mut transaction = Transaction::new_with_payer(&[exchange(&sales_tax_recipient_pubkey)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(4))
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[exchange(&treasury_pubkey)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    let treasury_account = program_ctx.banks_client.get_account(treasury_pubkey).await.unwrap().unwrap();
+    let rent_exemption = Rent::default().minimum_balance(state::Treasury::LEN);
+    assert_eq!(treasury_account.lamports, rent_exemption + 25);
+    let treasury = state::Treasury::unpack_from_slice(&treasury_account.data).unwrap();
+    assert_eq!(treasury.authority_pubkey, treasury_authority_keypair.pubkey());
+    assert_eq!(treasury.current_period.sales_tax, 25);
+    assert_eq!(treasury.total_collected, 25);
+
+    // Only the treasury authority withdraws, and only what's above the rent exemption
+    let destination = Pubkey::new_unique();
+    let blockhash = program_ctx.last_blockhash;
+    let withdraw = |authority: &Keypair, amount| {
+        let ix = client::withdraw_fees(&program_id, &authority.pubkey(), &destination, amount);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, authority], blockhash);
+        transaction
+    };
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(withdraw(&authority_keypair, 25)).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(25))
+    );
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(withdraw(&treasury_authority_keypair, 26))
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InsufficientFunds)
+    );
+    program_ctx
+        .banks_client
+        .process_transaction(withdraw(&treasury_authority_keypair, 25))
+        .await
+        .unwrap();
+
+    let destination_account = program_ctx.banks_client.get_account(destination).await.unwrap().unwrap();
+    assert_eq!(destination_account.lamports, 25);
+    let treasury_account = program_ctx.banks_client.get_account(treasury_pubkey).await.unwrap().unwrap();
+    assert_eq!(treasury_account.lamports, rent_exemption);
+    let treasury = state::Treasury::unpack_from_slice(&treasury_account.data).unwrap();
+    assert_eq!((treasury.current_period.withdrawn, treasury.total_withdrawn), (25, 25));
+
+    // Listing fees too go to the treasury only
+    let list = |fee_recipient: &Pubkey| {
+        client::init_escrow(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &taker_token_keypair.pubkey(),
+            &mint_keypair.pubkey(),
+            &new_escrow_keypair.pubkey(),
+            fee_recipient,
+            &spl_token::id(),
+            None,
+            None,
+            None,
+            price,
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(&[list(&sales_tax_recipient_pubkey)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(4))
+    );
+    let mut transaction = Transaction::new_with_payer(&[list(&treasury_pubkey)], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let treasury_account = program_ctx.banks_client.get_account(treasury_pubkey).await.unwrap().unwrap();
+    assert_eq!(treasury_account.lamports, rent_exemption + utils::LISTING_FEE);
+}
+
+#[tokio::test]
+async fn test_pause() {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let new_escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (new_escrow_keypair.pubkey(), vec![0_u8; state::Escrow::LEN], program_id),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (token_account, owner) in vec![
+        (&token_account_keypair, pda),
+        (&taker_token_keypair, payer_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint_keypair.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+        utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account.pubkey(), &payer_keypair, 1)
+            .await
+            .unwrap();
+    }
+
+    // Only the marketplace authority pauses
+    let blockhash = program_ctx.last_blockhash;
+    let set_paused = |authority: &Keypair, paused| {
+        let ix = client::set_paused(&program_id, &authority.pubkey(), paused);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, authority], blockhash);
+        transaction
+    };
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(set_paused(&initializer_keypair, true)).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(14))
+    );
+    program_ctx
+        .banks_client
+        .process_transaction(set_paused(&authority_keypair, true))
+        .await
+        .unwrap();
+
+    // No sales while paused
+    let ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(26))
+    );
+
+    // No new listings either
+    let ix = client::init_escrow(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &mint_keypair.pubkey(),
+        &new_escrow_keypair.pubkey(),
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1000,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(26))
+    );
+
+    // Leaving out the marketplace account doesn't get around the pause
+    let mut ix = client::init_escrow(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &mint_keypair.pubkey(),
+        &new_escrow_keypair.pubkey(),
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1000,
+    );
+    ix.accounts.truncate(10);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+
+    // Nor new prices
+    let ix = client::update_price(&program_id, &initializer_keypair.pubkey(), &escrow_keypair.pubkey(), &mint_keypair.pubkey(), None, 2000);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &initializer_keypair], program_ctx.last_blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(26))
+    );
+
+    // But the seller gets the NFT back
+    let initializer_token_keypair = Keypair::new();
+    utils::create_token_account(
+        &mut program_ctx,
+        &initializer_token_keypair,
+        &mint_keypair.pubkey(),
+        &initializer_keypair.pubkey(),
+        &Rent::default(),
+    )
+    .await
+    .unwrap();
+    let ix = client::exchange(
+        &program_id,
+        &initializer_keypair.pubkey(),
+        &initializer_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &initializer_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    assert!(program_ctx.banks_client.get_account(escrow_keypair.pubkey()).await.unwrap().is_none());
+
+    let marketplace_account = program_ctx
+        .banks_client
+        .get_account(state::get_marketplace_account(&program_id).0)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(state::Marketplace::unpack_from_slice(&marketplace_account.data).unwrap().paused);
+}
+
+#[tokio::test]
+async fn test_access_lists() {
+    let program_id = Pubkey::new_unique();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let new_escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let seller_token_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let mint_access_pubkey = state::get_access_account(&program_id, &mint_keypair.pubkey()).0;
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (authority_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (new_escrow_keypair.pubkey(), vec![0_u8; state::Escrow::LEN], program_id),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (token_account, owner) in vec![
+        (&token_account_keypair, pda),
+        (&taker_token_keypair, payer_keypair.pubkey()),
+        (&seller_token_keypair, initializer_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint_keypair.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+    }
+    for token_account in [&token_account_keypair, &taker_token_keypair] {
+        utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account.pubkey(), &payer_keypair, 1)
+            .await
+            .unwrap();
+    }
+
+    let blockhash = program_ctx.last_blockhash;
+    let set_access = |authority: &Keypair, mode| {
+        let ix = client::set_access(&program_id, &authority.pubkey(), &mint_keypair.pubkey(), mode);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, authority], blockhash);
+        transaction
+    };
+    let init_escrow = |amount| {
+        let ix = client::init_escrow(
+            &program_id,
+            &payer_keypair.pubkey(),
+            &taker_token_keypair.pubkey(),
+            &mint_keypair.pubkey(),
+            &new_escrow_keypair.pubkey(),
+            &client::sales_tax_recipient(),
+            &spl_token::id(),
+            None,
+            None,
+            None,
+            amount,
+        );
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair], blockhash);
+        transaction
+    };
+
+    // Only the marketplace authority manages the lists
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(set_access(&initializer_keypair, state::AccessMode::Denied))
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(14))
+    );
+    program_ctx
+        .banks_client
+        .process_transaction(set_access(&authority_keypair, state::AccessMode::Denied))
+        .await
+        .unwrap();
+    let access_account = program_ctx.banks_client.get_account(mint_access_pubkey).await.unwrap().unwrap();
+    assert_eq!(access_account.owner, program_id);
+    let access_entry = state::AccessEntry::unpack_from_slice(&access_account.data).unwrap();
+    assert_eq!(access_entry.target, mint_keypair.pubkey());
+    assert_eq!(access_entry.mode, state::AccessMode::Denied);
+
+    // A denied mint is neither sold nor listed
+    let ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        None,
+        None,
+        None,
+        1,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(27))
+    );
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(init_escrow(1000)).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(27))
+    );
+
+    // The marketplace takes the listing down, the seller gets the token and the rent back
+    let seller_before = program_ctx.banks_client.get_account(initializer_keypair.pubkey()).await.unwrap().unwrap();
+    let ix = client::force_delist(
+        &program_id,
+        &authority_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &seller_token_keypair.pubkey(),
+        &spl_token::id(),
+        None,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let seller_token_account = program_ctx.banks_client.get_account(seller_token_keypair.pubkey()).await.unwrap().unwrap();
+    assert_eq!(spl_token::state::Account::unpack_from_slice(&seller_token_account.data).unwrap().amount, 1);
+    assert!(program_ctx.banks_client.get_account(escrow_keypair.pubkey()).await.unwrap().is_none());
+    assert!(program_ctx.banks_client.get_account(token_account_keypair.pubkey()).await.unwrap().is_none());
+    let seller_after = program_ctx.banks_client.get_account(initializer_keypair.pubkey()).await.unwrap().unwrap();
+    assert!(seller_after.lamports > seller_before.lamports + 5000000);
+    assert!(program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().is_none());
+
+    // Marketplaces taking only allowed tokens refuse unlisted ones
+    let ix = client::update_marketplace(
+        &program_id,
+        &authority_keypair.pubkey(),
+        false,
+        0,
+        state::FeeMode::SellerPaid,
+        vec![],
+        false,
+        0,
+        true,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    program_ctx
+        .banks_client
+        .process_transaction(set_access(&authority_keypair, state::AccessMode::Unlisted))
+        .await
+        .unwrap();
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(init_escrow(2000)).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(28))
+    );
+
+    program_ctx
+        .banks_client
+        .process_transaction(set_access(&authority_keypair, state::AccessMode::Allowed))
+        .await
+        .unwrap();
+    program_ctx.banks_client.process_transaction(init_escrow(3000)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_force_delist_programmable() {
+    let program_id = Pubkey::new_unique();
+    let metaplex_id = Pubkey::from_str(utils::METAPLEX).unwrap();
+    let initializer_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+    let mint_pubkey = Pubkey::new_unique();
+    let escrow_pubkey = Pubkey::new_unique();
+    let seller_token_pubkey = Pubkey::new_unique();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    let token_record_pubkey = client::get_token_record_account(&mint_pubkey, &seller_token_pubkey);
+
+    let mut md = sample_metadata();
+    md.mint = mint_pubkey;
+    md.token_standard = Some(metadata::TokenStandard::ProgrammableNonFungible);
+    let mut metadata_data = md.try_to_vec().unwrap();
+    metadata_data.resize(metadata::MAX_METADATA_LEN, 0);
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    program_test.add_program(
+        "token_metadata_stub",
+        metaplex_id,
+        processor!(utils::process_token_metadata_stub),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey,
+        temp_token_account_pubkey: seller_token_pubkey,
+        seller_token_account_pubkey: seller_token_pubkey,
+        expected_amount: 1000,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    let mut mint_data = vec![0_u8; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        supply: 1,
+        is_initialized: true,
+        ..spl_token::state::Mint::default()
+    }
+    .pack_into_slice(&mut mint_data);
+    // The seller's token stays in their account, delegated to the PDA and locked in its token record.
+    let mut token_data = vec![0_u8; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint: mint_pubkey,
+        owner: initializer_keypair.pubkey(),
+        amount: 1,
+        delegate: solana_program::program_option::COption::Some(pda),
+        delegated_amount: 1,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    }
+    .pack_into_slice(&mut token_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (authority_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_pubkey, escrow_data, program_id),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+        (mint_pubkey, mint_data, spl_token::id()),
+        (seller_token_pubkey, token_data, spl_token::id()),
+        (utils::get_metadata_account(&mint_pubkey), metadata_data, metaplex_id),
+        (token_record_pubkey, vec![1], metaplex_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    let ix = client::force_delist(
+        &program_id,
+        &authority_keypair.pubkey(),
+        &escrow_pubkey,
+        &escrow,
+        &seller_token_pubkey,
+        &spl_token::id(),
+        Some(&md),
+    );
+    assert_eq!(ix.accounts[9].pubkey, utils::get_metadata_account(&md.mint));
+    assert!(ix.accounts[9].is_writable);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], program_ctx.last_blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    // The PDA revoked its delegation, unlocking the token
+    let token_record = program_ctx.banks_client.get_account(token_record_pubkey).await.unwrap().unwrap();
+    assert_eq!(token_record.data, vec![0]);
+    assert!(program_ctx.banks_client.get_account(escrow_pubkey).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_compressed_access_and_force_delist() {
+    let program_id = Pubkey::new_unique();
+    let bubblegum_id = compression::bubblegum_program_id();
+    let initializer_keypair = Keypair::new();
+    let taker_keypair = Keypair::new();
+    let authority_keypair = Keypair::new();
+    let escrow_pubkey = Pubkey::new_unique();
+    let new_escrow_pubkey = Pubkey::new_unique();
+    let merkle_tree_pubkey = Pubkey::new_unique();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    let leaf = client::CompressedLeaf {
+        merkle_tree: merkle_tree_pubkey,
+        leaf_delegate: initializer_keypair.pubkey(),
+        root: [1; 32],
+        metadata_args_hash: [2; 32],
+        seller_fee_basis_points: 500,
+        nonce: 7,
+        index: 3,
+        creators: sample_metadata().data.creators.unwrap(),
+        proof: vec![Pubkey::new_unique()],
+    };
+    let asset_id = compression::get_asset_id(&merkle_tree_pubkey, leaf.nonce);
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    program_test.add_program(
+        "bubblegum_stub",
+        bubblegum_id,
+        processor!(utils::process_bubblegum_stub),
+    );
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: authority_keypair.pubkey(),
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (taker_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (authority_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_pubkey, vec![0_u8; state::CompressedEscrow::LEN], program_id),
+        (new_escrow_pubkey, vec![0_u8; state::CompressedEscrow::LEN], program_id),
+        (merkle_tree_pubkey, vec![0_u8; 64], bubblegum_id),
+        (sales_tax_recipient_pubkey, vec![], solana_program::system_program::id()),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 500000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+    let blockhash = program_ctx.last_blockhash;
+    let set_access = |mode| {
+        let ix = client::set_access(&program_id, &authority_keypair.pubkey(), &asset_id, mode);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, &authority_keypair], blockhash);
+        transaction
+    };
+    let init_escrow = |escrow_pubkey: &Pubkey| {
+        let ix = client::init_compressed_escrow(&program_id, &initializer_keypair.pubkey(), escrow_pubkey, &sales_tax_recipient_pubkey, &leaf, None, 1000);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, &initializer_keypair], blockhash);
+        transaction
+    };
+
+    // Listed while the asset is on neither list
+    program_ctx.banks_client.process_transaction(init_escrow(&escrow_pubkey)).await.unwrap();
+    let escrow_account = program_ctx.banks_client.get_account(escrow_pubkey).await.unwrap().unwrap();
+    let escrow = state::CompressedEscrow::unpack(&escrow_account.data).unwrap();
+
+    // A denied asset is neither listed nor sold
+    program_ctx.banks_client.process_transaction(set_access(state::AccessMode::Denied)).await.unwrap();
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(init_escrow(&new_escrow_pubkey)).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(27))
+    );
+    let ix = client::exchange_compressed(&program_id, &taker_keypair.pubkey(), &escrow_pubkey, &escrow, &sales_tax_recipient_pubkey, leaf.root, &leaf.proof);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &taker_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(27))
+    );
+
+    // ForceDelist doesn't take compressed listings, ForceDelistCompressed gives the leaf back
+    let mut ix = client::force_delist_compressed(&program_id, &authority_keypair.pubkey(), &escrow_pubkey, &escrow, leaf.root, &leaf.proof);
+    ix.data = instruction::EscrowInstruction::ForceDelist.pack();
+    ix.accounts.truncate(9);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(19))
+    );
+    let tree = program_ctx.banks_client.get_account(merkle_tree_pubkey).await.unwrap().unwrap();
+    assert_eq!(tree.data[..32], pda.to_bytes());
+
+    let ix = client::force_delist_compressed(&program_id, &authority_keypair.pubkey(), &escrow_pubkey, &escrow, leaf.root, &leaf.proof);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &authority_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let tree = program_ctx.banks_client.get_account(merkle_tree_pubkey).await.unwrap().unwrap();
+    assert_eq!(tree.data[..32], initializer_keypair.pubkey().to_bytes());
+    assert_eq!(tree.data[32..64], initializer_keypair.pubkey().to_bytes());
+    assert!(program_ctx.banks_client.get_account(escrow_pubkey).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_compressed_listing_deposit() {
+    let program_id = Pubkey::new_unique();
+    let bubblegum_id = compression::bubblegum_program_id();
+    let initializer_keypair = Keypair::new();
+    let taker_keypair = Keypair::new();
+    let escrow_pubkey = Pubkey::new_unique();
+    let new_escrow_pubkey = Pubkey::new_unique();
+    let merkle_tree_pubkey = Pubkey::new_unique();
+    let sales_tax_recipient_pubkey = Pubkey::from_str(utils::SALES_TAX_ACCOUNT_STR).unwrap();
+    let price: u64 = 1_000_000;
+    let leaf = client::CompressedLeaf {
+        merkle_tree: merkle_tree_pubkey,
+        leaf_delegate: initializer_keypair.pubkey(),
+        root: [1; 32],
+        metadata_args_hash: [2; 32],
+        seller_fee_basis_points: 500,
+        nonce: 7,
+        index: 3,
+        creators: sample_metadata().data.creators.unwrap(),
+        proof: vec![Pubkey::new_unique()],
+    };
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    program_test.add_program(
+        "bubblegum_stub",
+        bubblegum_id,
+        processor!(utils::process_bubblegum_stub),
+    );
+    let mut marketplace_data = vec![0_u8; state::Marketplace::LEN];
+    state::Marketplace {
+        key: state::AccountKey::Marketplace,
+        authority_pubkey: Pubkey::new_unique(),
+        refundable_listing_fee: true,
+        ..state::Marketplace::default()
+    }
+    .pack_into_slice(&mut marketplace_data);
+    let mut accounts = vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (taker_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_pubkey, vec![0_u8; state::CompressedEscrow::LEN], program_id),
+        (new_escrow_pubkey, vec![0_u8; state::CompressedEscrow::LEN], program_id),
+        (merkle_tree_pubkey, vec![0_u8; 64], bubblegum_id),
+        (sales_tax_recipient_pubkey, vec![], solana_program::system_program::id()),
+        (state::get_marketplace_account(&program_id).0, marketplace_data, program_id),
+    ];
+    accounts.extend(leaf.creators.iter().map(|c| (c.address, vec![], solana_program::system_program::id())));
+    for (pubkey, data, owner) in accounts {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 500000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+    let blockhash = program_ctx.last_blockhash;
+    let init_escrow = |escrow_pubkey: &Pubkey| {
+        let ix = client::init_compressed_escrow(&program_id, &initializer_keypair.pubkey(), escrow_pubkey, &sales_tax_recipient_pubkey, &leaf, None, price);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, &initializer_keypair], blockhash);
+        transaction
+    };
+
+    // The listing fee is held in the escrow account
+    program_ctx.banks_client.process_transaction(init_escrow(&escrow_pubkey)).await.unwrap();
+    let escrow_account = program_ctx.banks_client.get_account(escrow_pubkey).await.unwrap().unwrap();
+    assert_eq!(escrow_account.lamports, 500000000 + utils::LISTING_FEE);
+    let escrow = state::CompressedEscrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow.listing_deposit, utils::LISTING_FEE);
+    let recipient = program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().unwrap();
+    assert_eq!(recipient.lamports, 500000000);
+
+    // Cancelling refunds it along with the rent
+    let seller_before = program_ctx.banks_client.get_account(initializer_keypair.pubkey()).await.unwrap().unwrap();
+    let ix = client::exchange_compressed(&program_id, &initializer_keypair.pubkey(), &escrow_pubkey, &escrow, &sales_tax_recipient_pubkey, leaf.root, &leaf.proof);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &initializer_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let seller_after = program_ctx.banks_client.get_account(initializer_keypair.pubkey()).await.unwrap().unwrap();
+    assert_eq!(seller_after.lamports, seller_before.lamports + 500000000 + utils::LISTING_FEE);
+    let recipient = program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().unwrap();
+    assert_eq!(recipient.lamports, 500000000);
+
+    // A sale forfeits it to the account receiving the sales fees
+    program_ctx.banks_client.process_transaction(init_escrow(&new_escrow_pubkey)).await.unwrap();
+    let escrow_account = program_ctx.banks_client.get_account(new_escrow_pubkey).await.unwrap().unwrap();
+    let escrow = state::CompressedEscrow::unpack(&escrow_account.data).unwrap();
+    let ix = client::exchange_compressed(&program_id, &taker_keypair.pubkey(), &new_escrow_pubkey, &escrow, &sales_tax_recipient_pubkey, leaf.root, &leaf.proof);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &taker_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let recipient = program_ctx.banks_client.get_account(sales_tax_recipient_pubkey).await.unwrap().unwrap();
+    assert_eq!(recipient.lamports, 500000000 + price * fees::SALES_TAX / 10000 + utils::LISTING_FEE);
+    assert!(program_ctx.banks_client.get_account(new_escrow_pubkey).await.unwrap().is_none());
+
+    // Listings made before the deposit was recorded read as holding none
+    let mut legacy = vec![0_u8; state::CompressedEscrow::LEN];
+    state::CompressedEscrow {
+        key: state::AccountKey::CompressedEscrow,
+        creators: leaf.creators.clone(),
+        ..state::CompressedEscrow::default()
+    }
+    .pack_into_slice(&mut legacy);
+    legacy.truncate(state::LEGACY_COMPRESSED_ESCROW_LEN);
+    let escrow = state::CompressedEscrow::unpack_versioned(&legacy).unwrap();
+    assert_eq!((escrow.created_at, escrow.listing_deposit), (0, 0));
+    assert_eq!(escrow.creators, leaf.creators);
+}
+
+#[tokio::test]
+async fn test_creator_policy() {
+    let program_id = Pubkey::new_unique();
+    let metaplex_id = Pubkey::from_str(utils::METAPLEX).unwrap();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let creator_keypair = Keypair::new();
+    let price: u64 = 1_000_000;
+
+    let mut md = sample_metadata();
+    md.mint = mint_keypair.pubkey();
+    md.data.creators = Some(vec![metadata::Creator {
+        address: creator_keypair.pubkey(),
+        verified: true,
+        share: 100,
+    }]);
+    let mut metadata_data = md.try_to_vec().unwrap();
+    metadata_data.resize(metadata::MAX_METADATA_LEN, 0);
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    for (pubkey, data, owner) in vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (creator_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (utils::get_metadata_account(&mint_keypair.pubkey()), metadata_data, metaplex_id),
+    ] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (token_account, owner) in vec![
+        (&token_account_keypair, pda),
+        (&taker_token_keypair, payer_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint_keypair.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+    }
+    utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account_keypair.pubkey(), &payer_keypair, 1)
+        .await
+        .unwrap();
+
+    let blockhash = program_ctx.last_blockhash;
+    let set_policy = |creator: &Keypair, target: &Pubkey, min_price, royalty_floor_basis_points| {
+        let ix = client::set_creator_policy(&program_id, &creator.pubkey(), target, min_price, royalty_floor_basis_points, vec![]);
+        let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+        transaction.sign(&[&payer_keypair, creator], blockhash);
+        transaction
+    };
+    let ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    // Paid by the seller, so that it differs from the purchase that goes through
+    let mut buy = Transaction::new_with_payer(&[ix], Some(&initializer_keypair.pubkey()));
+    buy.sign(&[&initializer_keypair, &payer_keypair], blockhash);
+
+    // Only verified creators of a collection set its policy
+    let collection = Pubkey::new_unique();
+    assert_eq!(
+        program_ctx
+            .banks_client
+            .process_transaction(set_policy(&creator_keypair, &collection, 0, 0))
+            .await
+            .err()
+            .unwrap()
+            .unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(31))
+    );
+
+    // Listings below the creator's minimum price aren't sold
+    program_ctx
+        .banks_client
+        .process_transaction(set_policy(&creator_keypair, &creator_keypair.pubkey(), price + 1, 0))
+        .await
+        .unwrap();
+    let policy_pubkey = state::get_policy_account(&program_id, &creator_keypair.pubkey()).0;
+    let policy_account = program_ctx.banks_client.get_account(policy_pubkey).await.unwrap().unwrap();
+    let policy = state::CreatorPolicy::unpack_from_slice(&policy_account.data).unwrap();
+    assert_eq!((policy.creator_pubkey, policy.min_price), (creator_keypair.pubkey(), price + 1));
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(buy).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(32))
+    );
+    // Nor can their price be lowered below it
+    let ix = client::update_price(&program_id, &initializer_keypair.pubkey(), &escrow_keypair.pubkey(), &mint_keypair.pubkey(), Some(&md), price);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair, &initializer_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(32))
+    );
+
+    // The royalty floor applies over the metadata's royalties
+    program_ctx
+        .banks_client
+        .process_transaction(set_policy(&creator_keypair, &creator_keypair.pubkey(), price, 1000))
+        .await
+        .unwrap();
+
+    // Once the price was updated, even to the same amount, Exchange can't tell which one the taker saw
+    let ix = client::update_price(&program_id, &initializer_keypair.pubkey(), &escrow_keypair.pubkey(), &mint_keypair.pubkey(), Some(&md), price);
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&initializer_keypair.pubkey()));
+    transaction.sign(&[&initializer_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let mut ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    ix.data = instruction::EscrowInstruction::Exchange { amount: 1 }.pack();
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(34))
+    );
+
+    // A taker who saw another price doesn't pay the current one
+    let stale = Escrow {
+        expected_amount: price / 2,
+        ..escrow
+    };
+    let ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &stale,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(34))
+    );
+    let creator_before = program_ctx.banks_client.get_account(creator_keypair.pubkey()).await.unwrap().unwrap();
+    let ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    let mut transaction = Transaction::new_with_payer(&[ix], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let creator_after = program_ctx.banks_client.get_account(creator_keypair.pubkey()).await.unwrap().unwrap();
+    assert_eq!(creator_after.lamports - creator_before.lamports, price / 10);
+
+    // Sales are paid in SOL, policies listing other payment mints refuse them
+    let mut policy = state::CreatorPolicy {
+        payment_mints: vec![Pubkey::new_unique()],
+        ..state::CreatorPolicy::default()
+    };
+    assert_eq!(fees::apply_creator_policies(&[policy], price).unwrap_err(), ProgramError::Custom(33));
+    policy = state::CreatorPolicy {
+        payment_mints: vec![spl_token::native_mint::id()],
+        royalty_floor_basis_points: 700,
+        ..state::CreatorPolicy::default()
+    };
+    assert_eq!(fees::apply_creator_policies(&[policy], price), Ok(700));
+    let floor = fees::SaleTerms {
+        royalty_floor_basis_points: 700,
+        ..fees::SaleTerms::default()
+    };
+    assert_eq!(fees::quote_escrow(&escrow, Some(&md), &floor).unwrap().royalties[0].amount, 70000);
+    // Without creators to pay, the floor doesn't apply
+    assert_eq!(fees::quote_escrow(&escrow, None, &floor).unwrap().seller_proceeds, price - 25000);
+}
+
+#[tokio::test]
+async fn test_creator_accounts_by_key() {
+    let program_id = Pubkey::new_unique();
+    let metaplex_id = Pubkey::from_str(utils::METAPLEX).unwrap();
+    let initializer_keypair = Keypair::new();
+    let mint_keypair = Keypair::new();
+    let escrow_keypair = Keypair::new();
+    let token_account_keypair = Keypair::new();
+    let taker_token_keypair = Keypair::new();
+    let creators = vec![Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+    let price: u64 = 1_000_000;
+
+    let mut md = sample_metadata();
+    md.mint = mint_keypair.pubkey();
+    md.data.creators = Some(
+        creators
+            .iter()
+            .zip(vec![60, 40, 0])
+            .map(|(address, share)| metadata::Creator {
+                address: *address,
+                verified: false,
+                share,
+            })
+            .collect(),
+    );
+    let mut metadata_data = md.try_to_vec().unwrap();
+    metadata_data.resize(metadata::MAX_METADATA_LEN, 0);
+
+    let mut program_test = ProgramTest::new(
+        "escrow",
+        program_id,
+        processor!(processor::Processor::process),
+    );
+    let escrow = Escrow {
+        key: state::AccountKey::EscrowV1,
+        initializer_pubkey: initializer_keypair.pubkey(),
+        mint_pubkey: mint_keypair.pubkey(),
+        temp_token_account_pubkey: token_account_keypair.pubkey(),
+        expected_amount: price,
+        ..Escrow::default()
+    };
+    let mut escrow_data = vec![0_u8; state::Escrow::LEN];
+    escrow.pack_into_slice(&mut escrow_data);
+    let mut accounts = vec![
+        (initializer_keypair.pubkey(), vec![], solana_program::system_program::id()),
+        (escrow_keypair.pubkey(), escrow_data, program_id),
+        (utils::get_metadata_account(&mint_keypair.pubkey()), metadata_data, metaplex_id),
+    ];
+    accounts.extend(creators.iter().map(|creator| (*creator, vec![], solana_program::system_program::id())));
+    for (pubkey, data, owner) in accounts {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: 5000000,
+                data,
+                owner,
+                ..Account::default()
+            },
+        );
+    }
+    let mut program_ctx = program_test.start_with_context().await;
+    let payer_keypair = Keypair::from_base58_string(&program_ctx.payer.to_base58_string());
+
+    utils::create_mint(&mut program_ctx, &mint_keypair, 100000000, &payer_keypair.pubkey())
+        .await
+        .unwrap();
+    let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+    for (token_account, owner) in vec![
+        (&token_account_keypair, pda),
+        (&taker_token_keypair, payer_keypair.pubkey()),
+    ] {
+        utils::create_token_account(&mut program_ctx, token_account, &mint_keypair.pubkey(), &owner, &Rent::default())
+            .await
+            .unwrap();
+    }
+    utils::mint_tokens_to(&mut program_ctx, &mint_keypair.pubkey(), &token_account_keypair.pubkey(), &payer_keypair, 1)
+        .await
+        .unwrap();
+
+    // The client leaves out the creator with no share
+    let exchange_ix = client::exchange(
+        &program_id,
+        &payer_keypair.pubkey(),
+        &taker_token_keypair.pubkey(),
+        &escrow_keypair.pubkey(),
+        &escrow,
+        &client::sales_tax_recipient(),
+        &spl_token::id(),
+        Some(&md),
+        None,
+        None,
+        1,
+    );
+    let fixed_len = exchange_ix.accounts.len() - 2;
+    assert_eq!(exchange_ix.accounts[fixed_len..].iter().map(|meta| meta.pubkey).collect::<Vec<_>>(), creators[..2].to_vec());
+    let with_creators = |passed: &[Pubkey]| {
+        let mut ix = exchange_ix.clone();
+        ix.accounts.truncate(fixed_len);
+        ix.accounts.extend(passed.iter().map(|creator| AccountMeta::new(*creator, false)));
+        ix
+    };
+
+    // A creator owed royalties must be passed
+    let blockhash = program_ctx.last_blockhash;
+    let mut transaction = Transaction::new_with_payer(&[with_creators(&[creators[1], creators[2]])], Some(&initializer_keypair.pubkey()));
+    transaction.sign(&[&initializer_keypair, &payer_keypair], blockhash);
+    assert_eq!(
+        program_ctx.banks_client.process_transaction(transaction).await.err().unwrap().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(12))
+    );
+
+    // Any order goes, and creators passed twice are only paid once
+    let mut before = vec![];
+    for creator in &creators {
+        before.push(program_ctx.banks_client.get_account(*creator).await.unwrap().unwrap().lamports);
+    }
+    let mut transaction = Transaction::new_with_payer(&[with_creators(&[creators[1], creators[0], creators[1]])], Some(&payer_keypair.pubkey()));
+    transaction.sign(&[&payer_keypair], blockhash);
+    program_ctx.banks_client.process_transaction(transaction).await.unwrap();
+    let quote = fees::quote_escrow(&escrow, Some(&md), &fees::SaleTerms::default()).unwrap();
+    for (i, creator) in creators.iter().enumerate() {
+        let after = program_ctx.banks_client.get_account(*creator).await.unwrap().unwrap().lamports;
+        assert_eq!(after - before[i], quote.royalties[i].amount);
+    }
+    assert_eq!(quote.royalties.iter().map(|royalty| royalty.amount).collect::<Vec<_>>(), vec![30000, 20000, 0]);
+}
+
+#[tokio::test]
+async fn test_decoded_program_ids() {
+    assert_eq!(processor::SALES_TAX_RECIPIENT, Pubkey::from_str(processor::SALES_TAX_RECIPIENT_INTERNAL).unwrap());
+    assert_eq!(metadata::metaplex_program_id(), Pubkey::from_str(metadata::METAPLEX).unwrap());
+    assert_eq!(token::token_2022_program_id(), Pubkey::from_str(token::TOKEN_2022).unwrap());
+    assert_eq!(compression::bubblegum_program_id(), Pubkey::from_str(compression::BUBBLEGUM).unwrap());
+    assert_eq!(compression::account_compression_program_id(), Pubkey::from_str(compression::ACCOUNT_COMPRESSION).unwrap());
+    assert_eq!(compression::noop_program_id(), Pubkey::from_str(compression::NOOP).unwrap());
+}
@@ -1,6 +1,11 @@
-use solana_program::program_pack::Pack;
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program::invoke,
+    program_error::ProgramError, program_option::COption, program_pack::Pack,
+};
+use solana_escrow::state::{get_access_account, get_marketplace_account};
 use solana_program_test::*;
 use solana_sdk::{
+    instruction::AccountMeta,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
     system_instruction,
@@ -138,3 +143,110 @@ pub fn get_metadata_account(mint: &Pubkey) -> Pubkey {
     let (metadata_key, _nonce) = Pubkey::find_program_address(metadata_seeds, &program_key);
     metadata_key
 }
+
+/// The accounts InitEscrow takes after the token program, for a token with no metadata
+/// listed without a fee tier.
+pub fn listing_accounts(program_id: &Pubkey, mint: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(get_metadata_account(mint), false),
+        AccountMeta::new_readonly(*program_id, false),
+        AccountMeta::new_readonly(get_marketplace_account(program_id).0, false),
+        AccountMeta::new_readonly(*program_id, false),
+        AccountMeta::new_readonly(*program_id, false),
+        AccountMeta::new_readonly(get_access_account(program_id, mint).0, false),
+        AccountMeta::new_readonly(*program_id, false),
+        AccountMeta::new_readonly(*program_id, false),
+        AccountMeta::new_readonly(*program_id, false),
+    ]
+}
+
+/// A stand-in for the token metadata program, implementing just enough of
+/// Delegate, Revoke and Transfer with plain token program instructions.
+pub fn process_token_metadata_stub(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    match instruction_data[0] {
+        // Delegate: 1 = delegate, 6 = token, 7 = authority
+        44 => {
+            let ix = spl_token::instruction::approve(
+                &spl_token::id(),
+                accounts[6].key,
+                accounts[1].key,
+                accounts[7].key,
+                &[],
+                1,
+            )?;
+            invoke(&ix, accounts)
+        }
+        // Revoke: 4 = token record, 6 = token, 7 = authority
+        45 => {
+            let token = spl_token::state::Account::unpack(&accounts[6].data.borrow())?;
+            if token.owner != *accounts[7].key {
+                // The delegate revoking itself. The token program only takes the owner's
+                // signature, so the stand-in just unlocks the token record.
+                if !accounts[7].is_signer || token.delegate != COption::Some(*accounts[7].key) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                for byte in accounts[4].data.borrow_mut().iter_mut() {
+                    *byte = 0;
+                }
+                return Ok(());
+            }
+            let ix = spl_token::instruction::revoke(
+                &spl_token::id(),
+                accounts[6].key,
+                accounts[7].key,
+                &[],
+            )?;
+            invoke(&ix, accounts)
+        }
+        // Transfer: 0 = token, 2 = destination, 9 = authority
+        49 => {
+            let mut amount = [0u8; 8];
+            amount.copy_from_slice(&instruction_data[2..10]);
+            let ix = spl_token::instruction::transfer(
+                &spl_token::id(),
+                accounts[0].key,
+                accounts[2].key,
+                accounts[9].key,
+                &[],
+                u64::from_le_bytes(amount),
+            )?;
+            invoke(&ix, accounts)
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+/// A stand-in for the Bubblegum program. The merkle tree account just records
+/// the leaf's delegate and owner, at offsets 0 and 32.
+pub fn process_bubblegum_stub(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let mut tree = accounts[4].data.borrow_mut();
+    match instruction_data[0] {
+        // Delegate: 1 = leaf owner, 3 = new delegate
+        90 => {
+            if !accounts[1].is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            tree[..32].copy_from_slice(accounts[3].key.as_ref());
+            tree[32..64].copy_from_slice(accounts[1].key.as_ref());
+            Ok(())
+        }
+        // Transfer: 2 = leaf delegate, 3 = new owner
+        163 => {
+            if !accounts[2].is_signer || tree[..32] != accounts[2].key.to_bytes() {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            tree[..32].copy_from_slice(accounts[3].key.as_ref());
+            tree[32..64].copy_from_slice(accounts[3].key.as_ref());
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}